@@ -9,8 +9,9 @@
 //! You can use this macro on top of functions to automatically append the
 //! label frame to the function.
 //!
-//! Example usage:
-//! ```rust
+//! Example usage, in a crate that depends on `fx_markers`, which the expanded
+//! code refers to:
+//! ```ignore
 //! #[gecko_profiler_fn_label(DOM)]
 //! fn foo(bar: u32) -> u32 {
 //!     bar
@@ -24,18 +25,33 @@
 //!
 //! See the documentation of `gecko_profiler_label!` macro to learn more about
 //! its parameters.
+//!
+//! On an `async fn`, the label frame is entered each time the future is polled,
+//! so it covers the body on both sides of every `.await`.
+//!
+//! ```ignore
+//! #[gecko_profiler_fn_label(Network)]
+//! async fn fetch(url: &str) -> Vec<u8> {
+//!     let response = send_request(url).await;
+//!     response.body().await
+//! }
+//! ```
 
-use syn::Path;
 use proc_macro2::Span;
 use syn::spanned::Spanned;
 extern crate proc_macro;
 
 use proc_macro2::TokenStream;
+use proc_macro_error::{abort, abort_call_site, abort_if_dirty, emit_error, proc_macro_error};
 use quote::quote_spanned;
 use quote::{quote, ToTokens};
+use syn::ext::IdentExt;
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
 use syn::Fields;
 use syn::{parse_macro_input, DeriveInput, Ident};
 use syn::{Data, Error};
+use syn::{ItemFn, Token};
 
 // We want to try and derive this:
 // pub trait ProfilerMarker: Serialize + DeserializeOwned {
@@ -48,6 +64,16 @@ use syn::{Data, Error};
 //     /// A method that streams the marker payload data as JSON object properties.
 //     /// Please see the [JSONWriter] struct to see its methods.
 //     fn stream_json_marker_data(&self, json_writer: &mut JSONWriter);
+//     /// A static method that reconstructs the marker payload from the JSON
+//     /// object streamed by `stream_json_marker_data()`, resolving unique strings
+//     /// through `unique_strings`.
+//     fn from_json_marker_data(
+//         json: &serde_json::Value,
+//         unique_strings: &UniqueStrings,
+//     ) -> Result<Self, MarkerError>;
+//     /// A method that returns the value of the field with the given key,
+//     /// formatted according to its format, or `None` if there's no such field.
+//     fn field_value_as_string(&self, key: &str) -> Option<String>;
 // }
 
 static LOCATIONS: &[&str] = &[
@@ -76,8 +102,34 @@ static FORMATS: &[&str] = &[
     "Percentage",
     "Integer",
     "Decimal",
+    "Flow",
+    "TerminatingFlow",
+    "Pid",
+    "Tid",
+    "List",
+];
+
+static GRAPH_TYPES: &[&str] = &["Line", "Bar", "FilledLine"];
+
+static GRAPH_COLORS: &[&str] = &[
+    "Blue", "Green", "Grey", "Ink", "Magenta", "Orange", "Purple", "Red", "Teal", "Yellow",
+];
+
+/// Formats of string data, which can't be drawn as a graph.
+static STRING_FORMATS: &[&str] = &[
+    "Url",
+    "FilePath",
+    "SanitizedString",
+    "String",
+    "UniqueString",
 ];
 
+/// Formats of flow ids, which must be integers.
+static FLOW_FORMATS: &[&str] = &["Flow", "TerminatingFlow"];
+
+/// The formats of identifiers, which are kept as they are by `Sub` and `Mul`.
+static ID_FORMATS: &[&str] = &["Flow", "TerminatingFlow", "Pid", "Tid"];
+
 fn is_valid_marker_location(ident: &syn::Ident) -> bool {
     let ident_as_string = ident.to_string();
     LOCATIONS.iter().any(|e| *e == ident_as_string.as_str())
@@ -90,18 +142,66 @@ fn is_valid_format_string(ident: &syn::Ident) -> bool {
 
 #[proc_macro_derive(
     ProfilerMarker,
-    attributes(marker_display, MarkerChart, searchable, format)
+    attributes(
+        marker_display,
+        MarkerChart,
+        searchable,
+        format,
+        key,
+        label,
+        graph,
+        flatten,
+        serde_property,
+        profiler_marker,
+        profiler_impl_for,
+        marker_options,
+        marker_scalable,
+        marker_diffable,
+        marker_doc,
+        marker_v2,
+        marker_version,
+        marker_metrics
+    )
 )]
+#[proc_macro_error]
 pub fn derive_profiler_marker(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // Step 1: Parse the input into a syntax tree.
     let input = parse_macro_input!(input as DeriveInput);
 
-    let mut marker_locations: Vec<syn::Ident> = vec![];
+    // The locations to display the marker in, each with an optional note from
+    // `note = ".."`.
+    let mut marker_locations: Vec<(syn::Ident, Option<String>)> = vec![];
+    // The path of the runtime crate in the generated code, from
+    // `#[profiler_marker(crate = "..")]`.
+    let mut krate: Option<syn::Path> = None;
+    // The type to implement `ProfilerMarker` for instead of the input struct,
+    // from `#[profiler_impl_for = ".."]`.
+    let mut impl_for: Option<(syn::TypePath, &syn::Attribute)> = None;
+    // The options to add markers with by default, from
+    // `#[marker_options(stack, interval_start)]`.
+    let mut marker_options: Option<(MarkerOptionsAttribute, &syn::Attribute)> = None;
+    // Whether to implement `Mul<f64>`, from `#[marker_scalable]`.
+    let mut marker_scalable: Option<&syn::Attribute> = None;
+    // Whether to implement `Sub`, from `#[marker_diffable]`.
+    let mut marker_diffable: Option<&syn::Attribute> = None;
+    // The description of the schema, from `#[marker_doc = ".."]`.
+    let mut marker_doc: Option<(String, &syn::Attribute)> = None;
+    // Whether to implement `ProfilerMarker_v2`, and with what, from
+    // `#[marker_v2(version = 2, category = "..")]`.
+    let mut marker_v2: Option<(MarkerV2Attribute, &syn::Attribute)> = None;
+    // The schema version returned by `schema_version_const()`, from
+    // `#[marker_version(N)]`.
+    let mut marker_version: Option<(syn::LitInt, &syn::Attribute)> = None;
+    // Whether to implement `From<Marker>` for `metrics::Label`, from
+    // `#[marker_metrics]`, with the path of the `metrics` crate from
+    // `#[marker_metrics(crate = "..")]`.
+    let mut marker_metrics: Option<&syn::Attribute> = None;
+    let mut metrics_crate: Option<syn::Path> = None;
 
     // Step 2: Check the attributes of the input, look for marker specific ones.
     // This could be done better in terms of error reporting and how we check for
     // ill-formed attributes (e.g. #[marker_display()])
-    for attr in input.attrs {
+    for attr in &input.attrs {
         // Ignore inner attributes, as they're not from us
         match attr.style {
             syn::AttrStyle::Inner(_) => {
@@ -112,52 +212,377 @@ pub fn derive_profiler_marker(input: proc_macro::TokenStream) -> proc_macro::Tok
 
         // look at the path: we're expecting `marker_display`
         if attr.path().is_ident("marker_display") {
-            match attr.parse_nested_meta(|meta| {
-                match meta.path.get_ident() {
-                    Some(i) => {
-                        if is_valid_marker_location(i) {
-                            marker_locations.push(i.clone());
-                            Ok(())
-                        } else {
-                            Err(meta.error("Unsupported marker display location"))
-                        }
+            if let Err(e) = attr.parse_nested_meta(marker_location_parser(&mut marker_locations)) {
+                emit_syn_error(e);
+            }
+        } else if attr.path().is_ident("profiler_marker") {
+            if let Err(e) = attr.parse_nested_meta(|meta| {
+                if !meta.path.is_ident("crate") {
+                    return Err(
+                        meta.error("Expected `crate = \"..\"` as argument to 'profiler_marker'")
+                    );
+                }
+                if krate.is_some() {
+                    return Err(meta.error("Too many crate arguments"));
+                }
+                let path: syn::LitStr = meta.value()?.parse()?;
+                krate = Some(path.parse().map_err(|_| {
+                    Error::new(
+                        path.span(),
+                        "Expected the path of the runtime crate, e.g. \"::fx_markers\"",
+                    )
+                })?);
+                Ok(())
+            }) {
+                emit_syn_error(e);
+            }
+        } else if attr.path().is_ident("profiler_impl_for") {
+            if let Some((_, previous)) = &impl_for {
+                emit_duplicate_attribute_error(attr, previous, "profiler_impl_for");
+                continue;
+            }
+            match parse_string_attribute(attr).map(|path| syn::parse_str(&path)) {
+                Some(Ok(ty)) => impl_for = Some((ty, attr)),
+                Some(Err(_)) => emit_error!(
+                    attr,
+                    "Expected the path of the type to implement ProfilerMarker for, \
+                     e.g. `#[profiler_impl_for = \"some_crate::ForeignType\"]`"
+                ),
+                // The error has already been emitted.
+                None => {}
+            }
+        } else if attr.path().is_ident("marker_options") {
+            if let Some((_, previous)) = &marker_options {
+                emit_duplicate_attribute_error(attr, previous, "marker_options");
+                continue;
+            }
+            if let Some(options) = parse_marker_options_attribute(attr) {
+                marker_options = Some((options, attr));
+            }
+        } else if attr.path().is_ident("marker_scalable") {
+            if let Some(previous) = marker_scalable {
+                emit_duplicate_attribute_error(attr, previous, "marker_scalable");
+                continue;
+            }
+            if let Err(e) = attr.meta.require_path_only() {
+                emit_syn_error(e);
+            }
+            marker_scalable = Some(attr);
+        } else if attr.path().is_ident("marker_diffable") {
+            if let Some(previous) = marker_diffable {
+                emit_duplicate_attribute_error(attr, previous, "marker_diffable");
+                continue;
+            }
+            if let Err(e) = attr.meta.require_path_only() {
+                emit_syn_error(e);
+            }
+            marker_diffable = Some(attr);
+        } else if attr.path().is_ident("marker_doc") {
+            if let Some((_, previous)) = &marker_doc {
+                emit_duplicate_attribute_error(attr, previous, "marker_doc");
+            } else if let Some(value) = parse_string_attribute(attr) {
+                marker_doc = Some((value, attr));
+            }
+        } else if attr.path().is_ident("marker_v2") {
+            if let Some((_, previous)) = &marker_v2 {
+                emit_duplicate_attribute_error(attr, previous, "marker_v2");
+                continue;
+            }
+            if let Some(v2) = parse_marker_v2_attribute(attr) {
+                marker_v2 = Some((v2, attr));
+            }
+        } else if attr.path().is_ident("marker_version") {
+            if let Some((_, previous)) = &marker_version {
+                emit_duplicate_attribute_error(attr, previous, "marker_version");
+                continue;
+            }
+            match attr.parse_args::<syn::LitInt>() {
+                Ok(version) if version.base10_parse::<u32>().is_ok() => {
+                    marker_version = Some((version, attr));
+                }
+                _ => emit_error!(
+                    attr,
+                    "Expected the schema version as a u32, e.g. `#[marker_version(2)]`"
+                ),
+            }
+        } else if attr.path().is_ident("marker_metrics") {
+            if let Some(previous) = marker_metrics {
+                emit_duplicate_attribute_error(attr, previous, "marker_metrics");
+                continue;
+            }
+            if !matches!(attr.meta, syn::Meta::Path(_)) {
+                if let Err(e) = attr.parse_nested_meta(|meta| {
+                    if !meta.path.is_ident("crate") {
+                        return Err(
+                            meta.error("Expected `crate = \"..\"` as argument to 'marker_metrics'")
+                        );
                     }
-                    None => {
-                        // No need to defer this.
-                        Err(meta.error(
-                            "Expected a marker display location as argument to 'marker_display'",
-                        ))
+                    if metrics_crate.is_some() {
+                        return Err(meta.error("Too many crate arguments"));
                     }
+                    let path: syn::LitStr = meta.value()?.parse()?;
+                    metrics_crate = Some(path.parse().map_err(|_| {
+                        Error::new(
+                            path.span(),
+                            "Expected the path of the metrics crate, e.g. \"::metrics\"",
+                        )
+                    })?);
+                    Ok(())
+                }) {
+                    emit_syn_error(e);
                 }
-            }) {
-                Err(e) => return e.into_compile_error().into(),
-                Ok(_) => {} // continue safely.
-            };
+            }
+            marker_metrics = Some(attr);
         }
     }
+    if let (Some(scalable), Some((_, impl_for))) = (marker_scalable, &impl_for) {
+        emit_error!(
+            scalable,
+            "'marker_scalable' can't be combined with 'profiler_impl_for'";
+            note = impl_for.span() => "`Mul<f64>` can't be implemented for a type from another crate";
+        );
+    }
+    if let (Some(diffable), Some((_, impl_for))) = (marker_diffable, &impl_for) {
+        emit_error!(
+            diffable,
+            "'marker_diffable' can't be combined with 'profiler_impl_for'";
+            note = impl_for.span() => "`Sub` can't be implemented for a type from another crate";
+        );
+    }
+    if let (Some(metrics), Some((_, impl_for))) = (marker_metrics, &impl_for) {
+        emit_error!(
+            metrics,
+            "'marker_metrics' can't be combined with 'profiler_impl_for'";
+            note = impl_for.span() => "`From` can't be implemented for `metrics::Label` with a type from another crate";
+        );
+    }
+    // `#[marker_version(N)]` is the version of `#[marker_v2]` too, so they
+    // can't disagree.
+    if let (Some((version, attr)), Some((v2, v2_attr))) = (&marker_version, &mut marker_v2) {
+        match &v2.version {
+            Some(v2_version) if v2_version.base10_digits() != version.base10_digits() => {
+                emit_error!(
+                    attr,
+                    "'marker_version' and 'marker_v2' give different schema versions";
+                    note = v2_version.span() => "`marker_v2` gives version {} here", v2_version;
+                );
+            }
+            Some(_) => {}
+            None => v2.version = Some(version.clone()),
+        }
+    }
+    let krate = krate.unwrap_or_else(|| syn::parse_quote!(::fx_markers));
 
     println!("Found marker locations: {:?}", marker_locations);
 
     // Get the name of the input
     let name = &input.ident;
-    // Get generic type accoutremonts
-    let (_impl_generics, _ty_generics, _where_clause) = input.generics.split_for_impl();
+    // Get generic type accoutremonts. The schema of each instantiation of a
+    // generic marker is memoized for the program's lifetime, so their
+    // parameters must be `'static`.
+    let generics = &input.generics;
+    let mut marker_generics = generics.clone();
+    if !generics.params.is_empty() {
+        marker_generics
+            .make_where_clause()
+            .predicates
+            .push(syn::parse_quote!(Self: 'static));
+    }
+    let (impl_generics, ty_generics, where_clause) = marker_generics.split_for_impl();
+
+    // The type that the traits are implemented for: the input struct itself, or
+    // the type given by `#[profiler_impl_for = ".."]`, in which case the input
+    // struct only describes the fields of that type.
+    let (self_ty, type_name) = match &impl_for {
+        Some((path, _)) => {
+            let type_name = path.path.segments.last().map(|s| s.ident.clone());
+            (quote!(#path), type_name.unwrap_or_else(|| name.clone()))
+        }
+        None => (quote!(#name #ty_generics), name.clone()),
+    };
+
+    let fields = parse_marker_fields(&input);
+    if let Some(diffable) = marker_diffable {
+        check_diffable_fields(diffable, &fields);
+    }
+    let metrics_label_field =
+        marker_metrics.and_then(|metrics| metrics_label_field(metrics, &fields));
+
+    // Report every error found while parsing the attributes at once, rather than
+    // stopping at the first one.
+    abort_if_dirty();
+
+    // Step 3: Implement `MarkerFields`, which handles the fields of the struct,
+    // so that the struct can be flattened into other markers.
+    let marker_fields_impl = marker_fields_impl(&krate, &self_ty, generics, &fields);
+
+    // Step 4: We need to generate five methods:
+    // marker_type_name (For which we'll use the name of the struct, or of the
+    // type from `profiler_impl_for`)
+    // marker_type_display (For which we'll use `MarkerFields::add_schema_rows`)
+    // stream_json_marker_data (For which we'll use `MarkerFields::stream_fields`)
+    // from_json_marker_data (For which we'll use `MarkerFields::read_fields`)
+    // field_value_as_string (For which we'll use the fields of the struct)
+    let marker_type_name_fn = marker_type_name_impl(&type_name);
+    let description = marker_doc.as_ref().map(|(doc, _)| doc.as_str());
+    let marker_type_display_fn =
+        marker_type_display_impl(&krate, generics, &marker_locations, description);
+    let field_value_as_string_fn = field_value_as_string_impl(&krate, &fields);
+    let default_marker_options_fn = marker_options
+        .as_ref()
+        .map(|(options, _)| default_marker_options_impl(&krate, options));
+
+    // Step 5: Generate conversions of the marker to other representations.
+    let btree_map_from_impl = btree_map_from_impl(&krate, &self_ty, generics, &fields);
+    let vec_from_impl = vec_from_impl(&krate, &self_ty, generics, &fields);
+    let try_from_map_impl = try_from_map_impl(&krate, &self_ty, generics, &fields);
 
-    // Step 3: We need to generate three methods:
-    // marker_type_name (For which we'll use the name of the struct)
-    // marker_type_display (For which we'll use the fields of the struct)
-    // stream_json_marker_data (Fro which we'll use the fields of the struct)
-    let marker_type_name_fn = marker_type_name_impl(&name);
-    let marker_type_display_fn = marker_type_display_impl(name, &marker_locations, &input.data);
-    let stream_json_marker_data_fn = stream_json_marker_data_impl();
+    // Step 6: Generate a constant for the key of each field, for code that
+    // builds or reads the JSON by hand, along with ones for the number of
+    // fields and the display locations.
+    let field_keys_impl =
+        field_keys_impl(&krate, name, &self_ty, generics, &marker_locations, &fields);
+    // And one for the schema JSON, when it can be written here.
+    let schema_json_const_impl =
+        schema_json(&krate, &type_name, &marker_locations, description, &fields)
+            .filter(|_| generics.params.is_empty())
+            .map(|schema_json| {
+                quote! {
+                    #[automatically_derived]
+                    impl #name {
+                        /// The JSON of the marker's schema, as returned by
+                        /// `ProfilerMarker::schema_json()`, written at compile time.
+                        pub const SCHEMA_JSON: &'static str = #schema_json;
+                    }
+                }
+            });
+    // And one for the bytes of the marker type name.
+    let type_name_bytes_impl = type_name_bytes_impl(name, generics, &type_name);
+    // The struct from `profiler_impl_for` is never built, so a method on it
+    // would be of no use.
+    let fields_present_impl = match impl_for {
+        Some(_) => quote!(),
+        None => fields_present_impl(name, generics, &fields),
+    };
+    let with_field_override_impl = match impl_for {
+        Some(_) => quote!(),
+        None => with_field_override_impl(&krate, name, &marker_generics),
+    };
+
+    // Step 7: Implement `Mul<f64>` to scale the numeric fields, if asked to.
+    let mul_impl = marker_scalable.map(|_| mul_impl(&krate, &self_ty, generics, &fields));
+    // And `Sub`, to compute the difference of two markers.
+    let sub_impl = marker_diffable.map(|_| sub_impl(&self_ty, generics, &fields));
+    // And `From<Marker>` for `metrics::Label`, for the `metrics` crate.
+    let metrics_crate = metrics_crate.unwrap_or_else(|| syn::parse_quote!(::metrics));
+    let metrics_label_impl = metrics_label_field
+        .map(|field| metrics_label_impl(&metrics_crate, &self_ty, generics, field));
+    // And `LowerExp` and `UpperExp`, to show the marker with its `Decimal` and
+    // `Percentage` fields in scientific notation. The type from `profiler_impl_for` is
+    // foreign, so it can't implement them.
+    let exp_fmt_impls = if impl_for.is_none() && fields.iter().any(is_exp_field) {
+        let lower = exp_fmt_impl(
+            &self_ty,
+            generics,
+            name,
+            &fields,
+            quote!(::std::fmt::LowerExp),
+        );
+        let upper = exp_fmt_impl(
+            &self_ty,
+            generics,
+            name,
+            &fields,
+            quote!(::std::fmt::UpperExp),
+        );
+        quote!(#lower #upper)
+    } else {
+        quote!()
+    };
+
+    // Step 8: Implement `ProfilerMarker_v2`, if asked to. It extends
+    // `ProfilerMarker`, so it has the same bounds.
+    let marker_v2_impl = marker_v2
+        .as_ref()
+        .map(|(v2, _)| marker_v2_impl(&krate, &self_ty, &marker_generics, v2));
+    // Along with a `const fn` for the schema version, next to the constants
+    // for the field keys. It's also generated for `#[marker_version(N)]` on
+    // its own.
+    let schema_version_const_impl = match (&marker_version, &marker_v2) {
+        (Some((version, _)), _) => Some(schema_version_const_impl(name, generics, Some(version))),
+        (None, Some((v2, _))) => Some(schema_version_const_impl(
+            name,
+            generics,
+            v2.version.as_ref(),
+        )),
+        (None, None) => None,
+    };
+
+    // Summarize what was generated in the documentation of the implementation.
+    let impl_summary = impl_summary(&type_name, &fields);
+
+    // A struct that only describes the fields of another type is never built,
+    // nor are its fields read, so mark them as used.
+    let config_use = if impl_for.is_some() {
+        let field_names = fields.iter().map(|f| &f.ident);
+        quote! {
+            const _: () = {
+                let _ = |config: #name #ty_generics| {
+                    #(let _ = &config.#field_names;)*
+                };
+            };
+        }
+    } else {
+        quote!()
+    };
 
     let total_impl = quote! {
 
-        impl ProfilerMarker for #name {
+        #marker_fields_impl
+
+        #[doc = #impl_summary]
+        #[automatically_derived]
+        impl #impl_generics #krate::ProfilerMarker for #self_ty #where_clause {
             #marker_type_name_fn
             #marker_type_display_fn
-            #stream_json_marker_data_fn
+
+            fn stream_json_marker_data(&self, json_writer: &mut #krate::JSONWriter) {
+                <Self as #krate::MarkerFields>::stream_fields(self, json_writer);
+            }
+
+            fn from_json_marker_data(
+                json: &#krate::serde_json::Value,
+                unique_strings: &#krate::UniqueStrings,
+            ) -> ::std::result::Result<Self, #krate::MarkerError> {
+                <Self as #krate::MarkerFields>::read_fields(
+                    &#krate::JSONReader::new(json, unique_strings)?
+                )
+            }
+
+            #field_value_as_string_fn
+
+            fn key_at(index: usize) -> ::std::option::Option<&'static str> {
+                <Self as #krate::MarkerFields>::KEYS.get(index).copied()
+            }
+
+            #default_marker_options_fn
         }
+
+        #btree_map_from_impl
+        #vec_from_impl
+        #try_from_map_impl
+        #field_keys_impl
+        #schema_json_const_impl
+        #type_name_bytes_impl
+        #fields_present_impl
+        #with_field_override_impl
+        #mul_impl
+        #sub_impl
+        #metrics_label_impl
+        #exp_fmt_impls
+        #marker_v2_impl
+        #schema_version_const_impl
+        #config_use
     };
 
     println!("Total generation: {}", total_impl);
@@ -165,132 +590,2398 @@ pub fn derive_profiler_marker(input: proc_macro::TokenStream) -> proc_macro::Tok
     proc_macro::TokenStream::from(total_impl)
 }
 
+/// A summary of the fields of the marker, and how they're streamed, for the
+/// documentation of the generated `ProfilerMarker` implementation.
+fn impl_summary(type_name: &Ident, fields: &[MarkerField]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            let name = f.ident.unraw();
+            if f.flatten {
+                format!("{} (flattened {})", name, f.ty.to_token_stream())
+            } else if f.serde_property {
+                format!("{} (serialized)", name)
+            } else if f.uses_field_value_trait() {
+                format!("{} (format of {})", name, f.value_ty().to_token_stream())
+            } else {
+                format!("{} ({})", name, f.format_name())
+            }
+        })
+        .collect();
+    if fields.is_empty() {
+        format!(
+            "Generated by the ProfilerMarker derive for {}, which has no fields.",
+            type_name
+        )
+    } else {
+        format!(
+            "Generated by the ProfilerMarker derive for {} with fields: {}.",
+            type_name,
+            fields.join(", ")
+        )
+    }
+}
+
+/// Return a parser for the arguments of `#[marker_display(..)]`, which pushes
+/// each location onto `marker_locations`, along with the note given by the
+/// `note = ".."` that follows it, if any.
+fn marker_location_parser(
+    marker_locations: &mut Vec<(syn::Ident, Option<String>)>,
+) -> impl FnMut(syn::meta::ParseNestedMeta) -> syn::Result<()> + '_ {
+    let first = marker_locations.len();
+    move |meta| {
+        if meta.path.is_ident("note") {
+            // A note documents the location listed just before it.
+            let note: syn::LitStr = meta.value()?.parse()?;
+            return match marker_locations[first..].last_mut() {
+                Some((_, existing @ None)) => {
+                    *existing = Some(note.value());
+                    Ok(())
+                }
+                Some((location, Some(_))) => Err(Error::new(
+                    note.span(),
+                    format!("Marker display location {} already has a note", location),
+                )),
+                None => {
+                    Err(meta.error("'note' must follow the marker display location it documents"))
+                }
+            };
+        }
+        match meta.path.get_ident() {
+            Some(i) => {
+                if is_valid_marker_location(i) {
+                    marker_locations.push((i.clone(), None));
+                    Ok(())
+                } else {
+                    Err(meta.error("Unsupported marker display location"))
+                }
+            }
+            None => {
+                // No need to defer this.
+                Err(meta
+                    .error("Expected a marker display location as argument to 'marker_display'"))
+            }
+        }
+    }
+}
+
+/// The locations to display the marker in, from `#[marker_display(..)]`.
+/// Markers are displayed in the marker chart unless told otherwise.
+fn display_locations(marker_locations: &[(syn::Ident, Option<String>)]) -> Vec<Ident> {
+    if marker_locations.is_empty() {
+        vec![Ident::new("MarkerChart", Span::call_site())]
+    } else {
+        marker_locations.iter().map(|(l, _)| l.clone()).collect()
+    }
+}
+
+/// Give the locations of `#[marker_display(..)]` to a hand-written
+/// `impl ProfilerMarker for ..` block, with the same syntax as the derive.
+///
+/// The locations, and their notes, are set on the schema returned by the
+/// block's `marker_type_display()`. If the block doesn't define
+/// `marker_type_display()`, one is added that returns a schema with no rows.
+///
+/// As the derive uses `marker_display` as a helper attribute, refer to this
+/// one by its path, i.e. `#[profiler_macros::marker_display(..)]`, rather than
+/// importing it next to the derive.
+#[proc_macro_attribute]
+#[proc_macro_error]
+pub fn marker_display(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let mut marker_locations: Vec<(syn::Ident, Option<String>)> = vec![];
+    if let Err(e) = syn::meta::parser(marker_location_parser(&mut marker_locations)).parse(attr) {
+        emit_syn_error(e);
+    }
+
+    let mut item_impl: syn::ItemImpl = match syn::parse(item) {
+        Ok(item_impl) => item_impl,
+        Err(e) => abort!(
+            e.span(),
+            "marker_display can only be used on `impl ProfilerMarker for ..` blocks"
+        ),
+    };
+    let is_profiler_marker_impl = item_impl.trait_.as_ref().is_some_and(|(_, path, _)| {
+        path.segments
+            .last()
+            .is_some_and(|s| s.ident == "ProfilerMarker")
+    });
+    if !is_profiler_marker_impl {
+        abort!(
+            item_impl.self_ty,
+            "marker_display can only be used on `impl ProfilerMarker for ..` blocks"
+        );
+    }
+    abort_if_dirty();
+
+    let locations = display_locations(&marker_locations);
+    let notes = marker_locations
+        .iter()
+        .filter_map(|(location, note)| note.as_ref().map(|note| (location, note)))
+        .map(|(location, note)| {
+            quote!(schema.set_location_note(::fx_markers::Location::#location, #note);)
+        });
+    let set_locations = quote! {
+        schema.set_locations(&[#(::fx_markers::Location::#locations),*]);
+        #(#notes)*
+    };
+
+    let display_fn = item_impl.items.iter_mut().find_map(|item| match item {
+        syn::ImplItem::Fn(f) if f.sig.ident == "marker_type_display" => Some(f),
+        _ => None,
+    });
+    match display_fn {
+        Some(f) => {
+            let block = &f.block;
+            f.block = syn::parse_quote!({
+                let mut schema: ::fx_markers::MarkerSchema = #block;
+                #set_locations
+                schema
+            });
+        }
+        None => item_impl.items.push(syn::parse_quote! {
+            fn marker_type_display() -> ::fx_markers::MarkerSchema {
+                let mut schema = ::fx_markers::MarkerSchema::new(&[]);
+                schema.set_chart_label("Name: {marker.name}");
+                #set_locations
+                schema
+            }
+        }),
+    }
+
+    proc_macro::TokenStream::from(item_impl.into_token_stream())
+}
+
+/// Enter a `gecko_profiler_label!` frame for the whole body of a function.
+///
+/// For an `async fn`, the label is entered each time the returned future is
+/// polled, through `LabeledFuture`, rather than only while the future is
+/// created. So the label covers every part of the body on either side of an
+/// `.await`, but not the time spent suspended.
+///
+/// Closures aren't supported, as attributes on expressions are unstable; use
+/// `gecko_profiler_label!` inside the closure body instead.
+#[proc_macro_attribute]
+#[proc_macro_error]
+pub fn gecko_profiler_fn_label(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let label = parse_label_arguments(attr);
+
+    let function: ItemFn = match syn::parse(item.clone()) {
+        Ok(function) => function,
+        Err(e) => {
+            if syn::parse::<syn::ExprClosure>(item).is_ok() {
+                abort_call_site!(
+                    "gecko_profiler_fn_label can't be used on closures";
+                    help = "use `gecko_profiler_label!` at the start of the closure body instead"
+                );
+            }
+            abort!(
+                e.span(),
+                "gecko_profiler_fn_label can only be used on functions"
+            )
+        }
+    };
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = function;
+
+    let body = if sig.asyncness.is_some() {
+        // Pin down the type of the async block, so that `?` and `return` in
+        // the body convert to the function's return type as they would have.
+        let inner = match &sig.output {
+            syn::ReturnType::Type(_, ty) if !matches!(**ty, syn::Type::ImplTrait(_)) => quote! {
+                async move {
+                    let __gecko_profiler_result: #ty = #block;
+                    __gecko_profiler_result
+                }
+            },
+            syn::ReturnType::Type(..) => quote!(async move #block),
+            syn::ReturnType::Default => quote! {
+                async move {
+                    let __gecko_profiler_result: () = #block;
+                    __gecko_profiler_result
+                }
+            },
+        };
+        quote! {
+            ::fx_markers::LabeledFuture::new(#label, #inner).await
+        }
+    } else {
+        quote! {
+            let _gecko_profiler_label = ::fx_markers::AutoProfilerLabel::new(#label);
+            #block
+        }
+    };
+
+    proc_macro::TokenStream::from(quote! {
+        #(#attrs)*
+        #vis #sig {
+            #body
+        }
+    })
+}
+
+/// Parse the `Category` or `Category, Subcategory` arguments of
+/// `#[gecko_profiler_fn_label(..)]` into a `ProfilerLabel` expression.
+fn parse_label_arguments(attr: proc_macro::TokenStream) -> TokenStream {
+    let arguments = match Punctuated::<Ident, Token![,]>::parse_terminated.parse(attr) {
+        Ok(arguments) => arguments,
+        Err(e) => abort!(
+            e.span(),
+            "Expected a category, and optionally a subcategory, as arguments to \
+                 'gecko_profiler_fn_label'"
+        ),
+    };
+    let mut arguments = arguments.into_iter();
+    let category = match arguments.next() {
+        Some(category) => category.to_string(),
+        None => abort_call_site!("Expected a category as argument to 'gecko_profiler_fn_label'"),
+    };
+    let subcategory = match arguments.next() {
+        Some(subcategory) => {
+            let subcategory = subcategory.to_string();
+            quote!(Some(#subcategory))
+        }
+        None => quote!(None),
+    };
+    if let Some(extra) = arguments.next() {
+        abort!(extra, "Too many arguments to 'gecko_profiler_fn_label'");
+    }
+    quote!(::fx_markers::ProfilerLabel::new(#category, #subcategory))
+}
+
 fn marker_type_name_impl(name: &Ident) -> TokenStream {
     let name_str = name.to_token_stream().to_string();
     let ts = quote! {
         fn marker_type_name() -> &'static str {
             #name_str
         }
-    }
-    .into();
+    };
 
     println!("Generated type name impl: {}", ts);
 
     ts
 }
 
-fn marker_type_display_impl(_name: &Ident, _marker_locations: &Vec<syn::Ident>, data: &Data) -> TokenStream {
-    let key_label_formats = match *data {
-        Data::Struct(ref data) => match data.fields {
-            Fields::Named(ref fields) => {
-                let displays = fields.named.iter().map(|f| {
-                    let fname = &f.ident;
-                    let fname_str = fname.to_token_stream().to_string();
-                    let attrs = &f.attrs;
-                    let mut format: Option<Ident> = None;
-                    let mut searchable: bool = false;
-                    for attr in attrs {
-                        match attr.style {
-                            syn::AttrStyle::Outer => {
-                                if attr.path().is_ident("searchable") {
-                                    searchable = true;
-                                } else if attr.path().is_ident("format") {
-                                    if format.is_some() {
-                                        return Error::new(attr.span(),"Too many format arguments").into_compile_error().into();
-                                    }
-                                    match attr.parse_nested_meta(|meta| {
-                                        match meta.path.get_ident() {
-                                            Some(i) => {
-                                                if is_valid_format_string(i) {
-                                                    format = Some(i.clone());
-                                                    Ok(())
-                                                } else {
-                                                    Err(meta.error("Unsupported format specifier"))
-                                                }
-                                            }
-                                            None => {
-                                                Err(meta.error(
-                                                    "Expected a marker format specifier as argument to 'format'",
-                                                ))
-                                            }
-                                        }
-                                    }) {
-                                        Err(e) => return e.into_compile_error().into(),
-                                        Ok(_) => {} // continue safely.
-                                    };
-                                }
-                            }
-                            syn::AttrStyle::Inner(_) => {
-                            },
-                        }
-                    }
+/// A struct member of a marker payload, along with the marker specific
+/// attribute information extracted from it.
+struct MarkerField {
+    ident: Ident,
+    ty: syn::Type,
+    /// The JSON property name, from `#[key = ".."]`, or the field name.
+    key: String,
+    /// The label of the field's schema row, from `#[label = ".."]`, the first
+    /// line of the field's doc comment, or the field name.
+    label: String,
+    format: Option<Ident>,
+    searchable: bool,
+    graph: Option<GraphAttribute>,
+    /// Whether the field is a nested marker payload whose fields are inlined,
+    /// from `#[flatten]`.
+    flatten: bool,
+    /// Whether the field is streamed with its `Serialize` implementation, from
+    /// `#[serde_property]`.
+    serde_property: bool,
+    /// The field's doc comment, which is forwarded to the constant for its key.
+    docs: Vec<syn::Attribute>,
+}
+
+/// The arguments of a `#[marker_options(stack, interval_start)]` struct
+/// attribute.
+struct MarkerOptionsAttribute {
+    stack: bool,
+    /// Either `interval_start` or `interval_end`.
+    timing: Option<Ident>,
+}
+
+/// The arguments of a `#[marker_v2(version = 2, category = "..")]` struct
+/// attribute.
+struct MarkerV2Attribute {
+    version: Option<syn::LitInt>,
+    category: Option<syn::LitStr>,
+}
 
-                    let fstring = match format {
-                        Some(ident) => format!("Format::{}", ident.to_string()),
-                        None => "Format::String".to_string(),
-                    };
-                    let format_type = syn::parse_str::<Path>(fstring.as_str()).unwrap();
+/// The arguments of a `#[graph(Type, color = Color)]` field attribute.
+struct GraphAttribute {
+    graph_type: Ident,
+    color: Option<Ident>,
+}
+
+impl MarkerField {
+    /// The name of the field's format, which defaults to `String`.
+    fn format_name(&self) -> String {
+        self.format
+            .as_ref()
+            .map_or("String".to_string(), |f| f.to_string())
+    }
+
+    /// The type of the field's value, i.e. `T` for an `Option<T>` field.
+    fn value_ty(&self) -> &syn::Type {
+        option_inner_type(&self.ty).unwrap_or(&self.ty)
+    }
+
+    /// Whether the field is described and streamed through its
+    /// `MarkerFieldValue` implementation, which is the case when it has no
+    /// `#[format(..)]`, and isn't a type that we know how to stream ourselves.
+    fn uses_field_value_trait(&self) -> bool {
+        let ty = self.value_ty();
+        self.format.is_none()
+            && !self.flatten
+            && !self.serde_property
+            && !is_string_like_type(ty)
+            && !is_primitive_type(ty)
+            && map_types(ty).is_none()
+    }
+}
 
-                    // Ident::new(fname.as_str(), Span::call_site());
-                    // println!("Format_type: {:?}", format_type.into_token_stream());
+/// Walk the members of the input struct, and extract the marker specific
+/// attributes (`#[format(..)]`, `#[searchable]`, `#[key = ".."]`, `#[label = ".."]`,
+/// `#[graph(..)]`, `#[flatten]`, `#[serde_property]`) from each of them, along with the first line
+/// of their doc comments. Keys default to the serialized name of the field, so
+/// `#[serde(rename = "..")]` and the struct's `#[serde(rename_all = "..")]` are
+/// followed too.
+///
+/// Errors in the attributes are emitted through `proc_macro_error` as they are
+/// found, so that they can all be reported together. Deriving for anything other
+/// than a struct with named fields aborts straight away.
+fn parse_marker_fields(input: &DeriveInput) -> Vec<MarkerField> {
+    let found = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => {
+                let rename_all = serde_name_attribute(&input.attrs, "rename_all");
+                return parse_named_fields(fields, rename_all.as_deref());
+            }
+            Fields::Unnamed(_) => "a tuple struct",
+            Fields::Unit => "a unit struct",
+        },
+        Data::Enum(_) => "an enum",
+        Data::Union(_) => "a union",
+    };
+    abort!(
+        input.ident,
+        "ProfilerMarker can only be derived for structs with named fields; found {}",
+        found
+    )
+}
 
-                    if searchable { 
-                        quote! {
-                            schema.add_key_label_format_searchable(#fname_str, #fname_str, #format_type, Searchable::Searchable);
+fn parse_named_fields(fields: &syn::FieldsNamed, rename_all: Option<&str>) -> Vec<MarkerField> {
+    let mut marker_fields = vec![];
+    // Fields disabled by `#[cfg(..)]` have been removed by the compiler before
+    // the derive runs, along with the `cfg` attributes of the enabled ones, so
+    // the generated code only ever mentions the fields that exist.
+    for f in fields.named.iter() {
+        let Some(ident) = f.ident.clone() else {
+            emit_error!(f, "Expected a named field");
+            continue;
+        };
+        let mut format: Option<(Ident, &syn::Attribute)> = None;
+        let mut searchable: Option<&syn::Attribute> = None;
+        let mut key: Option<(String, &syn::Attribute)> = None;
+        let mut label: Option<(String, &syn::Attribute)> = None;
+        let mut doc_label: Option<String> = None;
+        let mut docs: Vec<syn::Attribute> = vec![];
+        let mut graph: Option<(GraphAttribute, &syn::Attribute)> = None;
+        let mut flatten: Option<&syn::Attribute> = None;
+        let mut serde_property: Option<&syn::Attribute> = None;
+        for attr in &f.attrs {
+            match attr.style {
+                syn::AttrStyle::Outer => {
+                    if attr.path().is_ident("searchable") {
+                        searchable = Some(attr);
+                    } else if attr.path().is_ident("flatten") {
+                        if let Err(e) = attr.meta.require_path_only() {
+                            emit_syn_error(e);
                         }
-                    } else {
-                        quote! {
-                            schema.add_key_label_format(#fname_str, #fname_str, #format_type);
+                        flatten = Some(attr);
+                    } else if attr.path().is_ident("serde_property") {
+                        if let Err(e) = attr.meta.require_path_only() {
+                            emit_syn_error(e);
+                        }
+                        serde_property = Some(attr);
+                    } else if attr.path().is_ident("key") {
+                        if let Some((_, previous)) = key {
+                            emit_duplicate_attribute_error(attr, previous, "key");
+                        } else if let Some(value) = parse_string_attribute(attr) {
+                            key = Some((value, attr));
+                        }
+                    } else if attr.path().is_ident("label") {
+                        if let Some((_, previous)) = label {
+                            emit_duplicate_attribute_error(attr, previous, "label");
+                        } else if let Some(value) = parse_string_attribute(attr) {
+                            label = Some((value, attr));
+                        }
+                    } else if attr.path().is_ident("doc") {
+                        if doc_label.is_none() {
+                            doc_label = first_doc_line(attr);
+                        }
+                        if attr.meta.require_name_value().is_ok() {
+                            docs.push(attr.clone());
+                        }
+                    } else if attr.path().is_ident("graph") {
+                        if let Some((_, previous)) = graph {
+                            emit_duplicate_attribute_error(attr, previous, "graph");
+                        } else if let Some(value) = parse_graph_attribute(attr) {
+                            graph = Some((value, attr));
+                        }
+                    } else if attr.path().is_ident("format") {
+                        if let Some((_, previous)) = format {
+                            emit_duplicate_attribute_error(attr, previous, "format");
+                        } else if let Some(value) = parse_format_attribute(attr) {
+                            format = Some((value, attr));
                         }
                     }
-                });
+                }
+                syn::AttrStyle::Inner(_) => {}
+            }
+        }
 
-                quote! {
-                    #(; #displays)*
+        if let Some(attr) = flatten {
+            let conflicting = [
+                format.as_ref().map(|(_, a)| *a),
+                searchable,
+                key.as_ref().map(|(_, a)| *a),
+                label.as_ref().map(|(_, a)| *a),
+                graph.as_ref().map(|(_, a)| *a),
+                serde_property,
+            ];
+            for other in conflicting.iter().flatten() {
+                emit_error!(
+                    attr,
+                    "'flatten' can't be combined with other marker field attributes";
+                    note = other.span() => "conflicting attribute `{}` here", path_name(other);
+                );
+            }
+        }
+
+        let value_ty = option_inner_type(&f.ty).unwrap_or(&f.ty);
+        if let Some((format, _)) = &format {
+            // Type aliases of integers are checked when the generated code is
+            // compiled, see `add_schema_rows_impl`.
+            if FLOW_FORMATS.contains(&format.to_string().as_str())
+                && !is_integer_type(value_ty)
+                && !may_be_type_alias(value_ty)
+            {
+                emit_error!(
+                    value_ty,
+                    "The {} format can only be used on integer fields, e.g. `u64`", format;
+                    note = format.span() => "the format is given here";
+                );
+            }
+        }
+
+        // Map fields are streamed as nested objects, with a property per entry.
+        let mut map_format: Option<Ident> = None;
+        if let Some((key_ty, entry_ty)) = map_types(value_ty) {
+            if !is_string_like_type(key_ty) {
+                emit_error!(
+                    key_ty,
+                    "Map fields must have string keys, e.g. `BTreeMap<String, {}>`",
+                    entry_ty.to_token_stream()
+                );
+            }
+            if let Some((_, graph_attr)) = &graph {
+                emit_error!(graph_attr, "'graph' can't be used on map fields");
+            }
+            match &format {
+                Some((format, _)) if format == "UniqueString" => {
+                    emit_error!(format, "Map fields can't use the UniqueString format");
                 }
+                Some(_) => {}
+                None => map_format = Some(Ident::new(infer_format(entry_ty), Span::call_site())),
             }
-            Fields::Unnamed(ref _fields) => {
-                todo!()
+        } else if let Some((_, graph_attr)) = &graph {
+            match &format {
+                Some((format, _)) if STRING_FORMATS.contains(&format.to_string().as_str()) => {
+                    emit_error!(
+                        graph_attr,
+                        "'graph' can only be used on fields with a numeric format";
+                        note = format.span() => "the field has the string format `{}`", format;
+                    )
+                }
+                Some((format, _)) if format == "List" => emit_error!(
+                    graph_attr,
+                    "'graph' can only be used on fields with a numeric format";
+                    note = format.span() => "the field has the `List` format";
+                ),
+                Some(_) => {}
+                // Fields using `MarkerFieldValue` have their format checked when
+                // the generated code is compiled.
+                None if !is_string_like_type(value_ty) && !is_primitive_type(value_ty) => {}
+                None => emit_error!(
+                    graph_attr,
+                    "'graph' can only be used on fields with a numeric format";
+                    help = "the field has the default `String` format, add a numeric \
+                            `#[format(..)]` such as `#[format(Integer)]`";
+                ),
             }
-            Fields::Unit => {
-                todo!()
+        }
+
+        let marker_field = MarkerField {
+            key: key.map(|(key, _)| key).unwrap_or_else(|| {
+                serde_name_attribute(&f.attrs, "rename")
+                    .unwrap_or_else(|| rename_field(&ident.unraw().to_string(), rename_all))
+            }),
+            label: label
+                .map(|(label, _)| label)
+                .or(doc_label)
+                .unwrap_or_else(|| ident.unraw().to_string()),
+            ident,
+            ty: f.ty.clone(),
+            format: format.map(|(format, _)| format).or(map_format),
+            searchable: searchable.is_some(),
+            graph: graph.map(|(graph, _)| graph),
+            flatten: flatten.is_some(),
+            serde_property: serde_property.is_some(),
+            docs,
+        };
+        marker_fields.push(marker_field);
+    }
+
+    marker_fields
+}
+
+/// Find the value of `name = ".."` in the `#[serde(..)]` attributes, or of
+/// `name(serialize = "..")`, as the keys are the serialized names. Everything
+/// else is left for serde to check, so malformed attributes are ignored here.
+fn serde_name_attribute(attrs: &[syn::Attribute], name: &str) -> Option<String> {
+    let mut value = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        let _ = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident(name) {
+                if meta.input.peek(Token![=]) {
+                    let lit: syn::LitStr = meta.value()?.parse()?;
+                    value = Some(lit.value());
+                } else {
+                    meta.parse_nested_meta(|meta| {
+                        if meta.path.is_ident("serialize") {
+                            let lit: syn::LitStr = meta.value()?.parse()?;
+                            value = Some(lit.value());
+                        } else {
+                            skip_meta_value(&meta)?;
+                        }
+                        Ok(())
+                    })?;
+                }
+                Ok(())
+            } else {
+                skip_meta_value(&meta)
             }
-        },
-        Data::Enum(_) | Data::Union(_) => unimplemented!(),
+        });
+    }
+    value
+}
+
+/// Skip over the value of a nested meta item we don't care about, whether it's
+/// `name = value`, `name(..)`, or just `name`.
+fn skip_meta_value(meta: &syn::meta::ParseNestedMeta) -> syn::Result<()> {
+    if meta.input.peek(Token![=]) {
+        meta.value()?.parse::<syn::Expr>()?;
+    } else if meta.input.peek(syn::token::Paren) {
+        let content;
+        syn::parenthesized!(content in meta.input);
+        content.parse::<TokenStream>()?;
+    }
+    Ok(())
+}
+
+/// Apply a serde `rename_all` rule to the name of a field, the same way serde
+/// does. Field names are assumed to be snake_case, and unknown rules are left
+/// for serde to report.
+fn rename_field(name: &str, rename_all: Option<&str>) -> String {
+    let pascal_case = || {
+        name.split('_')
+            .map(|word| {
+                let mut chars = word.chars();
+                chars.next().map_or_else(String::new, |first| {
+                    first.to_uppercase().chain(chars).collect()
+                })
+            })
+            .collect::<String>()
     };
+    match rename_all {
+        Some("UPPERCASE") | Some("SCREAMING_SNAKE_CASE") => name.to_ascii_uppercase(),
+        Some("PascalCase") => pascal_case(),
+        Some("camelCase") => {
+            let pascal = pascal_case();
+            let mut chars = pascal.chars();
+            chars.next().map_or_else(String::new, |first| {
+                first.to_lowercase().chain(chars).collect()
+            })
+        }
+        Some("kebab-case") => name.replace('_', "-"),
+        Some("SCREAMING-KEBAB-CASE") => name.to_ascii_uppercase().replace('_', "-"),
+        _ => name.to_string(),
+    }
+}
 
-    let ts = quote! {
-        fn marker_type_display() -> MarkerSchema {
-            let mut schema = MarkerSchema::new(&[Location::MarkerChart]);
-            schema.set_chart_label("Name: {marker.name}");
+/// Report every message held by a `syn::Error` through `proc_macro_error`.
+fn emit_syn_error(error: Error) {
+    for e in error {
+        emit_error!(e.span(), "{}", e);
+    }
+}
 
-            #key_label_formats
+/// Report an attribute that was given more than once on the same field,
+/// pointing at the first occurrence too.
+fn emit_duplicate_attribute_error(attr: &syn::Attribute, previous: &syn::Attribute, name: &str) {
+    emit_error!(
+        attr,
+        "Too many {} arguments", name;
+        note = previous.span() => "`{}` was first given here", name;
+    );
+}
 
-            schema
+/// The name of an attribute's path, for use in diagnostics.
+fn path_name(attr: &syn::Attribute) -> String {
+    attr.path().to_token_stream().to_string()
+}
+
+/// Parse the argument of a `#[format(Format)]` attribute.
+fn parse_format_attribute(attr: &syn::Attribute) -> Option<Ident> {
+    let mut format: Option<Ident> = None;
+    let result = attr.parse_nested_meta(|meta| match meta.path.get_ident() {
+        Some(i) => {
+            if is_valid_format_string(i) {
+                format = Some(i.clone());
+                Ok(())
+            } else {
+                Err(meta.error("Unsupported format specifier"))
+            }
+        }
+        None => Err(meta.error("Expected a marker format specifier as argument to 'format'")),
+    });
+    match result {
+        Ok(()) => format,
+        Err(e) => {
+            emit_syn_error(e);
+            None
         }
     }
-    .into();
-    // use gecko_profiler::marker::schema::*;
+}
 
-    //         schema.set_tooltip_label("{marker.data.a}");
-    //         schema.add_key_label_format("a", "A Value", Format::Integer);
-    //         schema.add_key_label_format("b", "B Value", Format::String);
-    //         schema
-    println!("Generated type display impl: {}", ts);
+/// The first non-empty line of a `#[doc = ".."]` attribute, trimmed.
+fn first_doc_line(attr: &syn::Attribute) -> Option<String> {
+    match &attr.meta.require_name_value().ok()?.value {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Str(s),
+            ..
+        }) => s
+            .value()
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(str::to_string),
+        _ => None,
+    }
+}
 
-    ts
+/// Parse the string value of a name-value attribute, e.g. `#[key = "value"]`.
+fn parse_string_attribute(attr: &syn::Attribute) -> Option<String> {
+    let name_value = match attr.meta.require_name_value() {
+        Ok(name_value) => name_value,
+        Err(e) => {
+            emit_syn_error(e);
+            return None;
+        }
+    };
+    match &name_value.value {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Str(s),
+            ..
+        }) => Some(s.value()),
+        value => {
+            emit_error!(value, "Expected a string literal");
+            None
+        }
+    }
 }
 
-fn stream_json_marker_data_impl() -> TokenStream {
-    let ts = quote! {
-        fn stream_json_marker_data(&self, json_writer: &mut JSONWriter) -> () {
+/// Parse the arguments of `#[marker_options(..)]`: `stack`, and at most one of
+/// `interval_start` or `interval_end`. A whole interval can't be a default, as
+/// its start is only known to the caller.
+fn parse_marker_options_attribute(attr: &syn::Attribute) -> Option<MarkerOptionsAttribute> {
+    let mut options = MarkerOptionsAttribute {
+        stack: false,
+        timing: None,
+    };
+    let result = attr.parse_nested_meta(|meta| {
+        let Some(ident) = meta.path.get_ident() else {
+            return Err(meta.error("Expected a marker option as argument to 'marker_options'"));
+        };
+        if ident == "stack" {
+            if options.stack {
+                return Err(meta.error("Too many stack arguments"));
+            }
+            options.stack = true;
+        } else if ident == "interval_start" || ident == "interval_end" {
+            if let Some(previous) = &options.timing {
+                return Err(meta.error(format!(
+                    "Marker timing was already given as `{}`; expected at most one of \
+                     `interval_start` or `interval_end`",
+                    previous
+                )));
+            }
+            options.timing = Some(ident.clone());
+        } else if ident == "interval" {
+            return Err(meta.error(
+                "`interval` can't be a default marker option, as the start of the interval \
+                 isn't known when the marker is added; add the marker with \
+                 `MarkerOptions::interval_from(start)` instead",
+            ));
+        } else {
+            return Err(meta.error(
+                "Unsupported marker option; expected `stack`, `interval_start` or \
+                 `interval_end`",
+            ));
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => Some(options),
+        Err(e) => {
+            emit_syn_error(e);
+            None
         }
     }
-    .into();
-    println!("Generated streaming json marker: {}", ts);
+}
 
-    ts
-    // fn stream_json_marker_data(&self, json_writer: &mut gecko_profiler::JSONWriter) {
-    //         json_writer.int_property("a", self.a.into());
-    //         json_writer.string_property("b", self.b.as_ref());
+fn parse_marker_v2_attribute(attr: &syn::Attribute) -> Option<MarkerV2Attribute> {
+    let mut v2 = MarkerV2Attribute {
+        version: None,
+        category: None,
+    };
+    // `#[marker_v2]` on its own uses the defaults of the trait.
+    if let syn::Meta::Path(_) = attr.meta {
+        return Some(v2);
+    }
+    let result = attr.parse_nested_meta(|meta| {
+        if meta.path.is_ident("version") {
+            if v2.version.is_some() {
+                return Err(meta.error("Too many version arguments"));
+            }
+            let version_error = |span| Error::new(span, "Expected the schema version as a u32");
+            let version: syn::LitInt = meta
+                .value()?
+                .parse()
+                .map_err(|e: Error| version_error(e.span()))?;
+            version
+                .base10_parse::<u32>()
+                .map_err(|_| version_error(version.span()))?;
+            v2.version = Some(version);
+        } else if meta.path.is_ident("category") {
+            if v2.category.is_some() {
+                return Err(meta.error("Too many category arguments"));
+            }
+            v2.category = Some(meta.value()?.parse()?);
+        } else {
+            return Err(meta.error(
+                "Unsupported argument to 'marker_v2'; expected `version = ..` or `category = \"..\"`",
+            ));
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => Some(v2),
+        Err(e) => {
+            emit_syn_error(e);
+            None
+        }
+    }
+}
+
+/// Parse the arguments of a `#[graph(Type)]` or `#[graph(Type, color = Color)]` attribute.
+fn parse_graph_attribute(attr: &syn::Attribute) -> Option<GraphAttribute> {
+    let mut graph_type: Option<Ident> = None;
+    let mut color: Option<Ident> = None;
+    let result = attr.parse_nested_meta(|meta| {
+        if meta.path.is_ident("color") {
+            let value: Ident = meta.value()?.parse()?;
+            if !GRAPH_COLORS.contains(&value.to_string().as_str()) {
+                return Err(Error::new(value.span(), "Unsupported graph color"));
+            }
+            color = Some(value);
+            Ok(())
+        } else {
+            match meta.path.get_ident() {
+                Some(i) if graph_type.is_none() => {
+                    if GRAPH_TYPES.contains(&i.to_string().as_str()) {
+                        graph_type = Some(i.clone());
+                        Ok(())
+                    } else {
+                        Err(meta.error("Unsupported graph type"))
+                    }
+                }
+                _ => Err(meta.error(
+                    "Expected a graph type, and optionally `color = ..`, as arguments to 'graph'",
+                )),
+            }
+        }
+    });
+    if let Err(e) = result {
+        emit_syn_error(e);
+        return None;
+    }
+    match graph_type {
+        Some(graph_type) => Some(GraphAttribute { graph_type, color }),
+        None => {
+            emit_error!(attr, "Expected a graph type as argument to 'graph'");
+            None
+        }
+    }
+}
+
+/// Get the last path segment of a type, e.g. `Arc` from `std::sync::Arc<str>`.
+fn last_path_segment(ty: &syn::Type) -> Option<&syn::PathSegment> {
+    match ty {
+        syn::Type::Path(type_path) if type_path.qself.is_none() => type_path.path.segments.last(),
+        _ => None,
+    }
+}
+
+/// Get the single generic type argument of a path segment, e.g. `str` from `Arc<str>`.
+fn single_type_argument(segment: &syn::PathSegment) -> Option<&syn::Type> {
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) if args.args.len() == 1 => {
+            match args.args.first() {
+                Some(syn::GenericArgument::Type(ty)) => Some(ty),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// If `ty` is a `BTreeMap<K, V>` or `HashMap<K, V>`, return `K` and `V`.
+fn map_types(ty: &syn::Type) -> Option<(&syn::Type, &syn::Type)> {
+    let segment = last_path_segment(ty)?;
+    if segment.ident != "BTreeMap" && segment.ident != "HashMap" {
+        return None;
+    }
+    let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    let mut types = args.args.iter().filter_map(|arg| match arg {
+        syn::GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    });
+    Some((types.next()?, types.next()?))
+}
+
+/// Whether `ty` is a `HashMap`, whose entries must be sorted to be streamed
+/// in a deterministic order.
+fn is_hash_map_type(ty: &syn::Type) -> bool {
+    last_path_segment(ty).is_some_and(|segment| segment.ident == "HashMap")
+}
+
+/// The format for the schema row of a map field without a `#[format(..)]`,
+/// from the type of the map's values.
+fn infer_format(ty: &syn::Type) -> &'static str {
+    match last_path_segment(ty).map(|segment| segment.ident.to_string()) {
+        Some(ident) if ident == "f32" || ident == "f64" => "Decimal",
+        Some(ident) if is_primitive_type(ty) && ident != "bool" && ident != "char" => "Integer",
+        _ => "String",
+    }
+}
+
+/// If `ty` is an `Option<T>`, return `T`.
+fn option_inner_type(ty: &syn::Type) -> Option<&syn::Type> {
+    let segment = last_path_segment(ty)?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    single_type_argument(segment)
+}
+
+/// Check whether `ty` is one of the types that we can stream directly as a
+/// string, i.e. `String`, `&str`, and `Arc`/`Rc` wrappers around `str` or `String`.
+fn is_string_like_type(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Reference(reference) => is_str_or_string(&reference.elem),
+        _ => match last_path_segment(ty) {
+            Some(segment) if segment.ident == "Arc" || segment.ident == "Rc" => {
+                single_type_argument(segment).is_some_and(is_str_or_string)
+            }
+            _ => is_str_or_string(ty),
+        },
+    }
+}
+
+/// Check whether `ty` is a primitive numeric, `bool` or `char` type, which are
+/// streamed using their `Display` output when given a string format.
+fn is_primitive_type(ty: &syn::Type) -> bool {
+    static PRIMITIVES: &[&str] = &[
+        "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize",
+        "f32", "f64", "bool", "char",
+    ];
+    match last_path_segment(ty) {
+        Some(segment) => {
+            segment.arguments.is_none() && PRIMITIVES.contains(&segment.ident.to_string().as_str())
+        }
+        None => false,
+    }
+}
+
+/// Check whether `ty` is `std::time::Instant`, which is streamed as a
+/// process-relative time with `#[format(Time)]`.
+fn is_instant_type(ty: &syn::Type) -> bool {
+    match last_path_segment(ty) {
+        Some(segment) => segment.ident == "Instant" && segment.arguments.is_none(),
+        None => false,
+    }
+}
+
+/// Check whether `ty` is a plain path that we don't recognize, which could be
+/// a type alias (or a `use .. as ..` rename) of a type that we do. Checks that
+/// depend on the underlying type are then left to the compiler.
+fn may_be_type_alias(ty: &syn::Type) -> bool {
+    matches!(ty, syn::Type::Path(path) if path.qself.is_none())
+        && last_path_segment(ty).is_some_and(|segment| segment.arguments.is_none())
+        && !is_primitive_type(ty)
+        && !is_string_like_type(ty)
+}
+
+/// Check whether `ty` is a primitive integer type.
+fn is_integer_type(ty: &syn::Type) -> bool {
+    is_primitive_type(ty)
+        && !matches!(
+            last_path_segment(ty)
+                .map(|segment| segment.ident.to_string())
+                .as_deref(),
+            Some("f32" | "f64" | "bool" | "char")
+        )
+}
+
+fn is_str_or_string(ty: &syn::Type) -> bool {
+    match last_path_segment(ty) {
+        Some(segment) => {
+            (segment.ident == "str" || segment.ident == "String") && segment.arguments.is_none()
+        }
+        None => false,
+    }
+}
+
+/// Whether the type is a `Vec<String>` or `Vec<&str>`.
+fn is_vec_of_strings_type(ty: &syn::Type) -> bool {
+    match last_path_segment(ty) {
+        Some(segment) if segment.ident == "Vec" => {
+            single_type_argument(segment).is_some_and(|inner| match inner {
+                syn::Type::Reference(reference) => is_str_or_string(&reference.elem),
+                inner => is_str_or_string(inner),
+            })
+        }
+        _ => false,
+    }
+}
+
+fn marker_type_display_impl(
+    krate: &syn::Path,
+    generics: &syn::Generics,
+    marker_locations: &[(syn::Ident, Option<String>)],
+    description: Option<&str>,
+) -> TokenStream {
+    let locations = display_locations(marker_locations);
+    let description = description.map(|d| quote!(schema.set_description(#d);));
+    let notes = marker_locations
+        .iter()
+        .filter_map(|(location, note)| note.as_ref().map(|note| (location, note)))
+        .map(|(location, note)| quote!(schema.set_location_note(#krate::Location::#location, #note);));
+    let build = quote! {
+        || {
+            let mut schema = #krate::MarkerSchema::new(&[#(#krate::Location::#locations),*]);
+            schema.set_chart_label("Name: {marker.name}");
+            #description
+            #(#notes)*
+
+            <Self as #krate::MarkerFields>::add_schema_rows(&mut schema);
+
+            schema
+        }
+    };
+    // A `static` in a generic function would be shared by every instantiation,
+    // so generic markers look their schema up by type instead.
+    let (memoized, memoized_json) = if generics.params.is_empty() {
+        (
+            quote! {
+                static SCHEMA: ::std::sync::OnceLock<#krate::MarkerSchema> =
+                    ::std::sync::OnceLock::new();
+                SCHEMA.get_or_init(#build)
+            },
+            quote! {
+                static SCHEMA_JSON: ::std::sync::OnceLock<::std::string::String> =
+                    ::std::sync::OnceLock::new();
+                SCHEMA_JSON.get_or_init(#krate::schema_json_string::<Self>)
+            },
+        )
+    } else {
+        (
+            quote! { #krate::memoized_schema::<Self>(#build) },
+            quote! { #krate::memoized_schema_json::<Self>() },
+        )
+    };
+    // The runtime crate only defines `schema_checksum()` and
+    // `to_schema_proto()` with its `checksum` and `protobuf` features, so leave
+    // it to macros of the runtime crate to generate them. Generic markers use
+    // the provided methods instead, which keep a checksum per instantiation.
+    let feature_fns = if generics.params.is_empty() {
+        quote! {
+            #krate::__schema_checksum_fn!();
+            #krate::__schema_proto_fn!();
+        }
+    } else {
+        quote!()
+    };
+    quote! {
+        fn marker_type_display() -> #krate::MarkerSchema {
+            <Self as #krate::ProfilerMarker>::marker_type_display_ref().clone()
+        }
+
+        fn marker_type_display_ref() -> &'static #krate::MarkerSchema {
+            #memoized
+        }
+
+        fn schema_json() -> &'static str {
+            #memoized_json
+        }
+
+        #feature_fns
+    }
+}
+
+/// Generate the `default_marker_options()` override for the options given by
+/// `#[marker_options(..)]`. The start or end of an interval is the time the
+/// options are created, i.e. when the marker is added.
+fn default_marker_options_impl(krate: &syn::Path, options: &MarkerOptionsAttribute) -> TokenStream {
+    let stack = if options.stack {
+        quote!(.stack(#krate::MarkerStack::Capture))
+    } else {
+        quote!()
+    };
+    let timing = match options.timing.as_ref().map(Ident::to_string).as_deref() {
+        Some("interval_start") => quote! {
+            .timing(#krate::MarkerTiming::IntervalStart(::std::time::Instant::now()))
+        },
+        Some("interval_end") => quote! {
+            .timing(#krate::MarkerTiming::IntervalEnd(::std::time::Instant::now()))
+        },
+        _ => quote!(),
+    };
+    quote! {
+        fn default_marker_options() -> #krate::MarkerOptions {
+            #krate::MarkerOptions::new() #timing #stack
+        }
+    }
+}
+
+/// Implement `ProfilerMarker_v2` for the marker, with the version and
+/// category from `#[marker_v2(..)]`.
+fn marker_v2_impl(
+    krate: &syn::Path,
+    self_ty: &TokenStream,
+    generics: &syn::Generics,
+    v2: &MarkerV2Attribute,
+) -> TokenStream {
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let version_fn = v2.version.as_ref().map(|version| {
+        quote! {
+            fn schema_version() -> u32 {
+                #version
+            }
+        }
+    });
+    let category_fn = v2.category.as_ref().map(|category| {
+        quote! {
+            fn category() -> ::std::option::Option<&'static str> {
+                ::std::option::Option::Some(#category)
+            }
+        }
+    });
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics #krate::ProfilerMarker_v2 for #self_ty #where_clause {
+            #version_fn
+            #category_fn
+
+            fn field_count() -> usize {
+                <Self as #krate::MarkerFields>::KEYS.len()
+            }
+        }
+    }
+}
+
+/// Generate `schema_version_const()`, the schema version from
+/// `#[marker_version(N)]` or `#[marker_v2(version = N)]` as a `const fn`, for
+/// const contexts where `ProfilerMarker_v2::schema_version()` can't be called.
+fn schema_version_const_impl(
+    name: &Ident,
+    generics: &syn::Generics,
+    version: Option<&syn::LitInt>,
+) -> TokenStream {
+    // The default of `ProfilerMarker_v2::schema_version()`.
+    let version = match version {
+        Some(version) => quote!(#version),
+        None => quote!(1),
+    };
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// The version of the marker's schema, as returned by
+            /// `ProfilerMarker_v2::schema_version()` when it's implemented.
+            pub const fn schema_version_const() -> u32 {
+                #version
+            }
+        }
+    }
+}
+
+/// Generate `type_name_bytes()`, the UTF-8 bytes of `marker_type_name()` as a
+/// `const fn`, for FFI.
+fn type_name_bytes_impl(name: &Ident, generics: &syn::Generics, type_name: &Ident) -> TokenStream {
+    let name_str = type_name.to_string();
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// The UTF-8 bytes of the name returned by
+            /// `ProfilerMarker::marker_type_name()`, without a null terminator.
+            pub const fn type_name_bytes() -> &'static [u8] {
+                #name_str.as_bytes()
+            }
+        }
+    }
+}
+
+fn marker_fields_impl(
+    krate: &syn::Path,
+    self_ty: &TokenStream,
+    generics: &syn::Generics,
+    fields: &[MarkerField],
+) -> TokenStream {
+    // The keys of the struct, including the keys of flattened fields, and a
+    // compile time check that they're unique.
+    let key_groups = fields.iter().map(|f| {
+        let ty = &f.ty;
+        let key = &f.key;
+        if f.flatten {
+            quote! { <#ty as #krate::MarkerFields>::KEYS }
+        } else {
+            quote! { &[#key] }
+        }
+    });
+    let key_counts = fields.iter().map(|f| {
+        let ty = &f.ty;
+        if f.flatten {
+            quote! { <#ty as #krate::MarkerFields>::KEYS.len() }
+        } else {
+            quote! { 1 }
+        }
+    });
+
+    // The keys of a generic marker can only be checked once it's instantiated,
+    // when its schema is built.
+    let (unique_keys_check, instantiated_unique_keys_check) = if generics.params.is_empty() {
+        let check = quote! {
+            const _: () = #krate::assert_unique_marker_keys(<#self_ty as #krate::MarkerFields>::KEYS);
+        };
+        (check, quote!())
+    } else {
+        let check = quote! {
+            const { #krate::assert_unique_marker_keys(<Self as #krate::MarkerFields>::KEYS) };
+        };
+        (quote!(), check)
+    };
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+    let add_schema_rows_fn = add_schema_rows_impl(krate, fields, instantiated_unique_keys_check);
+    let stream_fields_fn = stream_fields_impl(krate, fields);
+    let read_fields_fn = read_fields_impl(krate, fields);
+
+    let ts = quote! {
+        #[automatically_derived]
+        impl #impl_generics #krate::MarkerFields for #self_ty #where_clause {
+            const KEYS: &'static [&'static str] =
+                &#krate::concat_marker_keys::<{ 0 #(+ #key_counts)* }>(&[#(#key_groups),*]);
+
+            #add_schema_rows_fn
+            #stream_fields_fn
+            #read_fields_fn
+        }
+
+        #unique_keys_check
+    };
+
+    ts
+}
+
+fn add_schema_rows_impl(
+    krate: &syn::Path,
+    fields: &[MarkerField],
+    unique_keys_check: TokenStream,
+) -> TokenStream {
+    let key_label_formats = fields.iter().map(|f| {
+        let key = &f.key;
+        let label = &f.label;
+
+        if f.flatten {
+            let ty = &f.ty;
+            return quote! {
+                <#ty as #krate::MarkerFields>::add_schema_rows(schema);
+            };
+        }
+
+        let format = f
+            .format
+            .clone()
+            .unwrap_or_else(|| Ident::new("String", Span::call_site()));
+        let format_type = if f.uses_field_value_trait() {
+            let value_ty = f.value_ty();
+            quote!(<#value_ty as #krate::MarkerFieldValue>::FORMAT)
+        } else {
+            quote!(#krate::Format::#format)
+        };
+
+        if f.searchable {
+            quote! {
+                schema.add_key_label_format_searchable(#key, #label, #format_type, #krate::Searchable::Searchable);
+            }
+        } else {
+            quote! {
+                schema.add_key_label_format(#key, #label, #format_type);
+            }
+        }
+    });
+
+    // The derive can't see through type aliases (or `use .. as ..` renames), so
+    // check that fields with a flow format whose type may be an alias are
+    // integers through the `MarkerFieldValue` implementation of the type
+    // they stand for.
+    let flow_checks = fields.iter().filter_map(|f| {
+        let format = f.format.as_ref()?;
+        let value_ty = f.value_ty();
+        if !FLOW_FORMATS.contains(&format.to_string().as_str()) || !may_be_type_alias(value_ty) {
+            return None;
+        }
+        Some(quote_spanned! {value_ty.span()=>
+            const _: () = #krate::assert_flow_format(
+                <#value_ty as #krate::MarkerFieldValue>::FORMAT
+            );
+        })
+    });
+
+    let graphs = fields.iter().filter_map(|f| {
+        let key = &f.key;
+        let graph = f.graph.as_ref()?;
+        let graph_type = &graph.graph_type;
+        let format_check = if f.uses_field_value_trait() {
+            let value_ty = f.value_ty();
+            quote_spanned! {value_ty.span()=>
+                const _: () = #krate::assert_graph_format(
+                    <#value_ty as #krate::MarkerFieldValue>::FORMAT
+                );
+            }
+        } else {
+            quote!()
+        };
+        let add_graph = match &graph.color {
+            Some(color) => quote! {
+                schema.add_graph_line_with_color(#key, #krate::GraphType::#graph_type, #krate::GraphColor::#color);
+            },
+            None => quote! {
+                schema.add_graph_line(#key, #krate::GraphType::#graph_type);
+            },
+        };
+        Some(quote! {
+            #format_check
+            #add_graph
+        })
+    });
+
+    quote! {
+        fn add_schema_rows(schema: &mut #krate::MarkerSchema) {
+            #unique_keys_check
+            #(#flow_checks)*
+            #(#key_label_formats)*
+            #(#graphs)*
+        }
+    }
+}
+
+fn stream_fields_impl(krate: &syn::Path, fields: &[MarkerField]) -> TokenStream {
+    let properties = fields.iter().map(|f| {
+        let fname = &f.ident;
+        let key = &f.key;
+        if f.flatten {
+            return quote! {
+                #krate::MarkerFields::stream_fields(&self.#fname, json_writer);
+            };
+        }
+        if f.serde_property {
+            return quote! {
+                if json_writer.serde_property(#key, &self.#fname).is_err() {
+                    json_writer.null_property(#key);
+                }
+            };
+        }
+        let write_property = |ty, access| {
+            if f.uses_field_value_trait() {
+                // Point errors about a missing implementation at the field type.
+                let value_ty = f.value_ty();
+                quote_spanned! {value_ty.span()=>
+                    <#value_ty as #krate::MarkerFieldValue>::write(&#access, #key, json_writer);
+                }
+            } else {
+                write_property_impl(krate, key, &f.format_name(), ty, access)
+            }
+        };
+        match option_inner_type(&f.ty) {
+            Some(inner_ty) => {
+                let write = write_property(inner_ty, quote!((*value)));
+                quote! {
+                    match &self.#fname {
+                        Some(value) => { #write }
+                        None => json_writer.null_property(#key),
+                    }
+                }
+            }
+            None => write_property(&f.ty, quote!(self.#fname)),
+        }
+    });
+
+    let ts = quote! {
+        fn stream_fields(&self, json_writer: &mut #krate::JSONWriter) {
+            #(#properties)*
+        }
+    };
+    println!("Generated streaming json marker: {}", ts);
+
+    ts
+}
+
+/// Generate the `JSONWriter` call that streams the value at `access` (an
+/// expression of type `ty`) as the property `key`, according to its format.
+fn write_property_impl(
+    krate: &syn::Path,
+    key: &str,
+    format: &str,
+    ty: &syn::Type,
+    access: TokenStream,
+) -> TokenStream {
+    if map_types(ty).is_some() {
+        // Stream the entries as the properties of a nested object, ordered by
+        // key even when the map doesn't keep them ordered.
+        let entries = if is_hash_map_type(ty) {
+            quote! {
+                let mut entries: ::std::vec::Vec<_> = #access.iter().collect();
+                entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+            }
+        } else {
+            quote! { let entries = #access.iter(); }
+        };
+        return quote! {
+            json_writer.start_object_property(#key);
+            #entries
+            for (entry_key, value) in entries {
+                json_writer.property_from_serialize(
+                    ::std::convert::AsRef::<str>::as_ref(entry_key),
+                    value,
+                );
+            }
+            json_writer.end_object();
+        };
+    }
+    match format {
+        "Url" | "FilePath" | "SanitizedString" | "String" => {
+            if is_string_like_type(ty) {
+                quote! { json_writer.string_property(#key, #access.as_ref()); }
+            } else if is_vec_of_strings_type(ty) {
+                quote! {
+                    json_writer.array_of_strings(
+                        #key,
+                        &#access
+                            .iter()
+                            .map(|value| ::std::convert::AsRef::<str>::as_ref(value))
+                            .collect::<::std::vec::Vec<&str>>(),
+                    );
+                }
+            } else if is_primitive_type(ty) {
+                quote! { json_writer.string_property(#key, &#access.to_string()); }
+            } else {
+                quote! { json_writer.property_from_serialize(#key, &#access); }
+            }
+        }
+        "UniqueString" => {
+            if is_string_like_type(ty) {
+                quote! { json_writer.unique_string_property(#key, #access.as_ref()); }
+            } else {
+                quote! { json_writer.unique_string_property(#key, &#access.to_string()); }
+            }
+        }
+        "Integer" | "Bytes" | "Pid" | "Tid" => {
+            quote! { json_writer.int_property(#key, #access as i64); }
+        }
+        "Flow" | "TerminatingFlow" => {
+            quote! { json_writer.unsigned_property(#key, #access as u64); }
+        }
+        "Time" if is_instant_type(ty) => {
+            quote! { json_writer.float_property(#key, #krate::process_time(#access)); }
+        }
+        "List" => quote! { json_writer.property_from_serialize(#key, &#access); },
+        _ => quote! { json_writer.float_property(#key, #access as f64); },
+    }
+}
+
+fn read_fields_impl(krate: &syn::Path, fields: &[MarkerField]) -> TokenStream {
+    let field_values = fields.iter().map(|f| {
+        let fname = &f.ident;
+        let key = &f.key;
+        if f.flatten {
+            let ty = &f.ty;
+            return quote! { #fname: <#ty as #krate::MarkerFields>::read_fields(reader)? };
+        }
+        if f.serde_property {
+            let ty = &f.ty;
+            return quote! { #fname: reader.property::<#ty>(#key)? };
+        }
+        let value_ty = option_inner_type(&f.ty).unwrap_or(&f.ty);
+        let read_method = match f.format_name().as_str() {
+            "Url" | "FilePath" | "SanitizedString" | "String"
+                if is_string_like_type(value_ty) || is_primitive_type(value_ty) =>
+            {
+                quote!(string_property::<#value_ty>)
+            }
+            "UniqueString" => quote!(unique_string_property::<#value_ty>),
+            "Time" if is_instant_type(value_ty) => quote!(instant_property),
+            _ => quote!(property::<#value_ty>),
+        };
+        if option_inner_type(&f.ty).is_some() {
+            quote! {
+                #fname: if reader.is_null(#key)? {
+                    None
+                } else {
+                    Some(reader.#read_method(#key)?)
+                }
+            }
+        } else {
+            quote! { #fname: reader.#read_method(#key)? }
+        }
+    });
+
+    quote! {
+        fn read_fields(
+            reader: &#krate::JSONReader,
+        ) -> ::std::result::Result<Self, #krate::MarkerError> {
+            ::std::result::Result::Ok(Self {
+                #(#field_values),*
+            })
+        }
+    }
+}
+
+fn field_value_as_string_impl(krate: &syn::Path, fields: &[MarkerField]) -> TokenStream {
+    let arms = fields.iter().filter(|f| !f.flatten).map(|f| {
+        let fname = &f.ident;
+        let key = &f.key;
+        let format_value = |ty, access| {
+            if f.serde_property {
+                serde_value_impl(krate, access)
+            } else {
+                format_value_impl(krate, &f.format_name(), ty, access)
+            }
+        };
+        let value = match option_inner_type(&f.ty) {
+            Some(inner_ty) => {
+                let format = format_value(inner_ty, quote!((*value)));
+                quote! {
+                    match &self.#fname {
+                        ::std::option::Option::Some(value) => #format,
+                        ::std::option::Option::None => ::std::string::String::new(),
+                    }
+                }
+            }
+            None => format_value(&f.ty, quote!(self.#fname)),
+        };
+        quote! { #key => ::std::option::Option::Some(#value), }
+    });
+
+    let flattened = fields.iter().filter(|f| f.flatten).map(|f| {
+        let fname = &f.ident;
+        quote! { .or_else(|| #krate::ProfilerMarker::field_value_as_string(&self.#fname, key)) }
+    });
+
+    quote! {
+        fn field_value_as_string(
+            &self,
+            key: &str,
+        ) -> ::std::option::Option<::std::string::String> {
+            match key {
+                #(#arms)*
+                _ => ::std::option::Option::None #(#flattened)*,
+            }
+        }
+    }
+}
+
+/// Generate an expression that formats the value at `access` (an expression of
+/// type `ty`) as a `String`, the same way it's streamed for its format.
+fn format_value_impl(
+    krate: &syn::Path,
+    format: &str,
+    ty: &syn::Type,
+    access: TokenStream,
+) -> TokenStream {
+    if map_types(ty).is_some() {
+        return serde_value_impl(krate, access);
+    }
+    match format {
+        "UniqueString" if !is_string_like_type(ty) => quote! { #access.to_string() },
+        "Url" | "FilePath" | "SanitizedString" | "String" | "UniqueString" => {
+            if is_string_like_type(ty) {
+                quote! { ::std::convert::AsRef::<str>::as_ref(&*#access).to_string() }
+            } else if is_primitive_type(ty) {
+                quote! { #access.to_string() }
+            } else {
+                serde_value_impl(krate, access)
+            }
+        }
+        "Integer" | "Bytes" | "Pid" | "Tid" => quote! { (#access as i64).to_string() },
+        "Flow" | "TerminatingFlow" => quote! { (#access as u64).to_string() },
+        "Time" if is_instant_type(ty) => quote! { #krate::process_time(#access).to_string() },
+        "List" => serde_value_impl(krate, access),
+        _ => quote! { (#access as f64).to_string() },
+    }
+}
+
+/// Generate an expression that formats the value at `access` as its serde JSON
+/// representation, or the string itself if that's a JSON string.
+fn serde_value_impl(krate: &syn::Path, access: TokenStream) -> TokenStream {
+    quote! {
+        match #krate::serde_json::to_value(&#access) {
+            ::std::result::Result::Ok(#krate::serde_json::Value::String(value)) => value,
+            ::std::result::Result::Ok(value) => value.to_string(),
+            ::std::result::Result::Err(_) => ::std::string::String::new(),
+        }
+    }
+}
+
+fn field_keys_impl(
+    krate: &syn::Path,
+    name: &Ident,
+    self_ty: &TokenStream,
+    generics: &syn::Generics,
+    marker_locations: &[(syn::Ident, Option<String>)],
+    fields: &[MarkerField],
+) -> TokenStream {
+    // Flattened fields don't have a key of their own, their keys are the
+    // constants of the flattened type.
+    let constants = fields.iter().filter(|f| !f.flatten).map(|f| {
+        let fname = f.ident.unraw().to_string();
+        let const_name = Ident::new(
+            &format!("FIELD_KEY_{}", fname.to_uppercase()),
+            f.ident.span(),
+        );
+        let key = &f.key;
+        let doc = format!("The key of the `{}` field in the marker JSON.", fname);
+        // Follow with the field's own documentation, if it has any.
+        let field_docs = &f.docs;
+        let separator = if field_docs.is_empty() {
+            quote!()
+        } else {
+            quote!(#[doc = ""])
+        };
+        quote! {
+            #[doc = #doc]
+            #separator
+            #(#field_docs)*
+            pub const #const_name: &'static str = #key;
+        }
+    });
+    let locations = display_locations(marker_locations);
+    let field_count = quote!(<#self_ty as #krate::MarkerFields>::KEYS.len());
+
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics #name #ty_generics #where_clause {
+            #(#constants)*
+
+            /// The number of fields in the marker's schema, counting the fields
+            /// of flattened payloads.
+            pub const FIELD_COUNT: usize = #field_count;
+
+            /// The locations the marker is displayed in, as in its schema.
+            pub const LOCATIONS: &'static [#krate::Location] =
+                &[#(#krate::Location::#locations),*];
+
+            /// The number of fields in the marker's schema, counting the fields
+            /// of flattened payloads.
+            pub fn field_count() -> usize {
+                #field_count
+            }
+        }
+    }
+}
+
+/// A piece of the schema JSON written at compile time: JSON text, or an
+/// expression for the `json_name()` of a runtime type, which is written as a
+/// JSON string.
+enum JsonPart {
+    Text(String),
+    Name(TokenStream),
+}
+
+/// Write a JSON object, with its properties sorted by name.
+fn json_object(mut properties: Vec<(&str, Vec<JsonPart>)>) -> Vec<JsonPart> {
+    properties.sort_by(|a, b| a.0.cmp(b.0));
+    let mut json = vec![JsonPart::Text("{".to_string())];
+    for (i, (name, value)) in properties.into_iter().enumerate() {
+        let comma = if i == 0 { "" } else { "," };
+        json.push(JsonPart::Text(format!("{}{}:", comma, json_string(name))));
+        json.extend(value);
+    }
+    json.push(JsonPart::Text("}".to_string()));
+    json
+}
+
+/// Write a JSON array.
+fn json_array(items: Vec<Vec<JsonPart>>) -> Vec<JsonPart> {
+    let mut json = vec![JsonPart::Text("[".to_string())];
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            json.push(JsonPart::Text(",".to_string()));
+        }
+        json.extend(item);
+    }
+    json.push(JsonPart::Text("]".to_string()));
+    json
+}
+
+fn json_text(value: &str) -> Vec<JsonPart> {
+    vec![JsonPart::Text(json_string(value))]
+}
+
+/// Write the schema JSON of the marker, the same way as
+/// `MarkerSchema::to_json()` followed by `to_string()`: compact, with the
+/// properties of objects sorted by name. The names of locations, formats and
+/// graphs are the `json_name()`s of the runtime crate, so the JSON is built by
+/// a `const` expression. Returns `None` when the schema depends on other types,
+/// i.e. with flattened fields or fields whose format comes from their
+/// `MarkerFieldValue` implementation.
+fn schema_json(
+    krate: &syn::Path,
+    type_name: &Ident,
+    marker_locations: &[(syn::Ident, Option<String>)],
+    description: Option<&str>,
+    fields: &[MarkerField],
+) -> Option<TokenStream> {
+    if fields
+        .iter()
+        .any(|f| f.flatten || f.uses_field_value_trait())
+    {
+        return None;
+    }
+    let name = |expr: TokenStream| vec![JsonPart::Name(expr)];
+    let location_name = |location: &Ident| name(quote!(#krate::Location::#location.json_name()));
+
+    let locations = display_locations(marker_locations);
+    let mut properties = vec![
+        ("name", json_text(&type_name.to_string())),
+        (
+            "display",
+            json_array(locations.iter().map(location_name).collect()),
+        ),
+        ("chartLabel", json_text("Name: {marker.name}")),
+    ];
+    // A location can only have one note, the last one given. The notes are
+    // sorted by the JSON names of their locations, which sort the same as the
+    // locations themselves, once lowercased.
+    let mut notes: Vec<(&Ident, &str)> = vec![];
+    for (location, note) in marker_locations {
+        if let Some(note) = note {
+            notes.retain(|(l, _)| *l != location);
+            notes.push((location, note));
+        }
+    }
+    if !notes.is_empty() {
+        notes.sort_by_key(|(location, _)| location.to_string().to_lowercase());
+        let mut json = vec![JsonPart::Text("{".to_string())];
+        for (i, (location, note)) in notes.into_iter().enumerate() {
+            if i > 0 {
+                json.push(JsonPart::Text(",".to_string()));
+            }
+            json.extend(location_name(location));
+            json.push(JsonPart::Text(format!(":{}", json_string(note))));
+        }
+        json.push(JsonPart::Text("}".to_string()));
+        properties.push(("displayNotes", json));
+    }
+    if let Some(description) = description {
+        properties.push(("description", json_text(description)));
+    }
+    let rows = fields.iter().map(|f| {
+        let format = Ident::new(&f.format_name(), Span::call_site());
+        let mut row = vec![
+            ("key", json_text(&f.key)),
+            ("label", json_text(&f.label)),
+            ("format", name(quote!(#krate::Format::#format.json_name()))),
+        ];
+        if f.searchable {
+            row.push(("searchable", vec![JsonPart::Text("true".to_string())]));
+        }
+        json_object(row)
+    });
+    properties.push(("data", json_array(rows.collect())));
+    let graphs: Vec<Vec<JsonPart>> = fields
+        .iter()
+        .filter_map(|f| {
+            let graph = f.graph.as_ref()?;
+            let graph_type = &graph.graph_type;
+            let mut json = vec![
+                ("key", json_text(&f.key)),
+                (
+                    "type",
+                    name(quote!(#krate::GraphType::#graph_type.json_name())),
+                ),
+            ];
+            if let Some(color) = &graph.color {
+                json.push((
+                    "color",
+                    name(quote!(#krate::GraphColor::#color.json_name())),
+                ));
+            }
+            Some(json_object(json))
+        })
+        .collect();
+    if !graphs.is_empty() {
+        properties.push(("graphs", json_array(graphs)));
+    }
+
+    // Join the adjacent pieces of text, and quote the names.
+    let mut parts: Vec<TokenStream> = vec![];
+    let mut text = String::new();
+    for part in json_object(properties) {
+        match part {
+            JsonPart::Text(t) => text.push_str(&t),
+            JsonPart::Name(expr) => {
+                text.push('"');
+                parts.push(quote!(#text));
+                parts.push(expr);
+                text = String::from("\"");
+            }
+        }
+    }
+    parts.push(quote!(#text));
+    Some(quote! {
+        {
+            const PARTS: &[&str] = &[#(#parts),*];
+            const BYTES: [u8; #krate::concat_str_len(PARTS)] = #krate::concat_str_bytes(PARTS);
+            match ::std::str::from_utf8(&BYTES) {
+                ::std::result::Result::Ok(json) => json,
+                ::std::result::Result::Err(_) => panic!("the schema JSON isn't UTF-8"),
+            }
+        }
+    })
+}
+
+/// Quote `value` as a JSON string, escaped the same way as by `serde_json`.
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\u{8}' => json.push_str("\\b"),
+            '\u{c}' => json.push_str("\\f"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Generate `fields_present()`, which returns a bitfield of the fields of the
+/// marker that have a value, in declaration order.
+fn fields_present_impl(
+    name: &Ident,
+    generics: &syn::Generics,
+    fields: &[MarkerField],
+) -> TokenStream {
+    let bits = fields.iter().take(64).enumerate().map(|(i, f)| {
+        let fname = &f.ident;
+        let ty = &f.ty;
+        let primitive = last_path_segment(ty)
+            .filter(|_| is_primitive_type(ty))
+            .map(|segment| segment.ident.to_string());
+        let present = if option_inner_type(ty).is_some() {
+            quote!(self.#fname.is_some())
+        } else if is_string_like_type(ty) {
+            quote!(!self.#fname.is_empty())
+        } else {
+            match primitive.as_deref() {
+                Some("bool") => quote!(self.#fname),
+                Some("char") | None => quote!(true),
+                Some(_) => quote!(self.#fname != 0 as #ty),
+            }
+        };
+        quote! {
+            if #present {
+                present |= 1 << #i;
+            }
+        }
+    });
+
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// A bitfield of the fields that have a value, where bit N is set
+            /// when the Nth field is `Some`, a non-empty string, a non-zero
+            /// number, or `true`. Fields of other types are always present,
+            /// and fields after the 64th aren't represented.
+            pub fn fields_present(&self) -> u64 {
+                let mut present = 0u64;
+                #(#bits)*
+                present
+            }
+        }
+    }
+}
+
+/// Generate `with_field_override()`, which returns a copy of the marker with
+/// one of its fields replaced, from its key and a JSON value, e.g. for test
+/// fixtures. The marker is copied through its payload JSON, so it doesn't have
+/// to implement `Clone`. `generics` are those of the `ProfilerMarker`
+/// implementation.
+fn with_field_override_impl(
+    krate: &syn::Path,
+    name: &Ident,
+    generics: &syn::Generics,
+) -> TokenStream {
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// A copy of the marker with the field with the given key, which
+            /// may be a field of a flattened payload, set to `value`, which is
+            /// read like the value streamed for the field. Fails if there's no
+            /// field with the key, or if `value` can't be read as its type.
+            pub fn with_field_override(
+                &self,
+                key: &str,
+                value: #krate::serde_json::Value,
+            ) -> ::std::result::Result<Self, ::std::string::String> {
+                #krate::override_marker_field(self, key, value)
+            }
+        }
+    }
+}
+
+/// Implement `Mul<f64>` for the marker, multiplying each numeric field, or
+/// `Option` of a numeric field, by the scalar. Integers are scaled by
+/// `ScalableInteger::scale()`, so the results saturate at the bounds of the
+/// field's type. Fields with an identifier format, like `Pid`, or a string
+/// format, and the other fields, including flattened payloads, are moved over
+/// unchanged.
+fn mul_impl(
+    krate: &syn::Path,
+    self_ty: &TokenStream,
+    generics: &syn::Generics,
+    fields: &[MarkerField],
+) -> TokenStream {
+    let field_values = fields.iter().map(|f| {
+        let fname = &f.ident;
+        let value_ty = f.value_ty();
+        let is_numeric = is_primitive_type(value_ty)
+            && !matches!(
+                last_path_segment(value_ty)
+                    .map(|segment| segment.ident.to_string())
+                    .as_deref(),
+                Some("bool" | "char")
+            );
+        let is_scaled_format = f.format.as_ref().is_none_or(|format| {
+            let format = format.to_string();
+            !ID_FORMATS.contains(&format.as_str()) && !STRING_FORMATS.contains(&format.as_str())
+        });
+        if f.flatten || !is_numeric || !is_scaled_format {
+            return quote!(#fname: self.#fname);
+        }
+        let product = if is_integer_type(value_ty) {
+            quote!(#krate::ScalableInteger::scale(value, rhs))
+        } else {
+            quote!((value as f64 * rhs) as #value_ty)
+        };
+        if option_inner_type(&f.ty).is_some() {
+            quote!(#fname: self.#fname.map(|value| #product))
+        } else {
+            quote! {
+                #fname: {
+                    let value = self.#fname;
+                    #product
+                }
+            }
+        }
+    });
+
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics ::std::ops::Mul<f64> for #self_ty #where_clause {
+            type Output = Self;
+
+            fn mul(self, rhs: f64) -> Self {
+                Self {
+                    #(#field_values,)*
+                }
+            }
+        }
+    }
+}
+
+/// Check that every field of a `#[marker_diffable]` marker can be subtracted:
+/// it's either flattened, or has a numeric format, and isn't a map or an
+/// `Instant`.
+fn check_diffable_fields(diffable: &syn::Attribute, fields: &[MarkerField]) {
+    for f in fields.iter().filter(|f| !f.flatten) {
+        let value_ty = f.value_ty();
+        let format = f.format_name();
+        let reason = if map_types(value_ty).is_some() {
+            Some("map fields can't be subtracted".to_string())
+        } else if is_instant_type(value_ty) {
+            Some("`Instant` fields can't be subtracted".to_string())
+        } else if STRING_FORMATS.contains(&format.as_str()) || format == "List" {
+            Some(if f.format.is_some() {
+                format!("the field has the non-numeric format `{}`", format)
+            } else {
+                "the field has the default `String` format, add a numeric `#[format(..)]` \
+                 such as `#[format(Integer)]`"
+                    .to_string()
+            })
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            emit_error!(
+                f.ident,
+                "'marker_diffable' requires every field to have a numeric format";
+                note = "{}", reason;
+                note = diffable.span() => "'marker_diffable' is given here";
+            );
+        }
+    }
+}
+
+/// Implement `Sub` for the marker, for the delta between two markers, e.g. of
+/// counters in incremental captures. Each numeric field, or `Option` of one, is
+/// the difference of the fields. Integers saturate at the bounds of the
+/// field's type. Fields with an identifier format, like `Pid`, are kept from
+/// the left-hand side, as are `Option` fields that are `None` on either side.
+/// Flattened payloads are subtracted with their own `Sub` implementation.
+fn sub_impl(
+    self_ty: &TokenStream,
+    generics: &syn::Generics,
+    fields: &[MarkerField],
+) -> TokenStream {
+    let field_values = fields.iter().map(|f| {
+        let fname = &f.ident;
+        if f.flatten {
+            return quote!(#fname: self.#fname - rhs.#fname);
+        }
+        if ID_FORMATS.contains(&f.format_name().as_str()) {
+            return quote!(#fname: self.#fname);
+        }
+        let difference = if is_integer_type(f.value_ty()) {
+            quote!(a.saturating_sub(b))
+        } else {
+            quote!(a - b)
+        };
+        if option_inner_type(&f.ty).is_some() {
+            quote! {
+                #fname: match (self.#fname, rhs.#fname) {
+                    (::std::option::Option::Some(a), ::std::option::Option::Some(b)) => {
+                        ::std::option::Option::Some(#difference)
+                    }
+                    (a, _) => a,
+                }
+            }
+        } else {
+            quote! {
+                #fname: {
+                    let (a, b) = (self.#fname, rhs.#fname);
+                    #difference
+                }
+            }
+        }
+    });
+
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics ::std::ops::Sub for #self_ty #where_clause {
+            type Output = Self;
+
+            fn sub(self, rhs: Self) -> Self {
+                Self {
+                    #(#field_values,)*
+                }
+            }
+        }
+    }
+}
+
+/// Find the field that a `#[marker_metrics]` marker is converted to a
+/// `metrics::Label` with: its first searchable field with a string format and
+/// type.
+fn metrics_label_field<'a>(
+    metrics: &syn::Attribute,
+    fields: &'a [MarkerField],
+) -> Option<&'a MarkerField> {
+    let field = fields.iter().find(|f| {
+        f.searchable
+            && !f.serde_property
+            && STRING_FORMATS.contains(&f.format_name().as_str())
+            && is_string_like_type(f.value_ty())
+    });
+    if field.is_none() {
+        emit_error!(
+            metrics,
+            "'marker_metrics' requires a searchable string field";
+            help = "add `#[searchable]` to the field to use as the value of the label";
+        );
+    }
+    field
+}
+
+/// Implement `From<Marker>` for `metrics::Label`, from the key and value of
+/// `field`. `None` is converted to an empty value. The `metrics` crate is a
+/// dependency of the crate using the derive, not of the runtime crate, found at
+/// `metrics`.
+fn metrics_label_impl(
+    metrics: &syn::Path,
+    self_ty: &TokenStream,
+    generics: &syn::Generics,
+    field: &MarkerField,
+) -> TokenStream {
+    let fname = &field.ident;
+    let key = &field.key;
+    let value = if option_inner_type(&field.ty).is_some() {
+        quote! {
+            marker.#fname
+                .as_deref()
+                .map(::std::string::ToString::to_string)
+                .unwrap_or_default()
+        }
+    } else {
+        quote!(::std::string::ToString::to_string(&marker.#fname))
+    };
+
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics ::std::convert::From<#self_ty> for #metrics::Label #where_clause {
+            fn from(marker: #self_ty) -> Self {
+                #metrics::Label::new(#key, #value)
+            }
+        }
+    }
+}
+
+/// Whether the field is a number shown with the `Decimal` or `Percentage`
+/// format, either given by `#[format(..)]`, or the default of `f32` and `f64`
+/// fields.
+fn is_exp_field(f: &MarkerField) -> bool {
+    let value_ty = f.value_ty();
+    let is_numeric = is_primitive_type(value_ty)
+        && !matches!(
+            last_path_segment(value_ty)
+                .map(|segment| segment.ident.to_string())
+                .as_deref(),
+            Some("bool" | "char")
+        );
+    let format = match &f.format {
+        Some(format) => format.to_string(),
+        None => infer_format(value_ty).to_string(),
+    };
+    !f.flatten && is_numeric && (format == "Decimal" || format == "Percentage")
+}
+
+/// Implement `LowerExp` or `UpperExp`, given as `fmt_trait`, for markers with
+/// `Decimal` or `Percentage` fields. All the fields are written like `Debug`
+/// output, e.g. `CpuMarker { load: 1.5e-3, name: "cpu0" }`, with the `Decimal`
+/// and `Percentage` fields in scientific notation. The formatter's options,
+/// like the precision, apply to each of those values, while the other fields
+/// are written with their `Debug` implementations and no options.
+fn exp_fmt_impl(
+    self_ty: &TokenStream,
+    generics: &syn::Generics,
+    name: &Ident,
+    fields: &[MarkerField],
+    fmt_trait: TokenStream,
+) -> TokenStream {
+    let field_values = fields.iter().enumerate().map(|(i, f)| {
+        let fname = &f.ident;
+        let label = format!("{}{}: ", if i == 0 { " { " } else { ", " }, fname);
+        let value = if !is_exp_field(f) {
+            quote!(::std::write!(f, "{:?}", self.#fname)?;)
+        } else if option_inner_type(&f.ty).is_some() {
+            quote! {
+                match &self.#fname {
+                    ::std::option::Option::Some(value) => {
+                        f.write_str("Some(")?;
+                        #fmt_trait::fmt(value, f)?;
+                        f.write_str(")")?;
+                    }
+                    ::std::option::Option::None => f.write_str("None")?,
+                }
+            }
+        } else {
+            quote!(#fmt_trait::fmt(&self.#fname, f)?;)
+        };
+        quote! {
+            f.write_str(#label)?;
+            #value
+        }
+    });
+    let name = name.to_string();
+
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics #fmt_trait for #self_ty #where_clause {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                f.write_str(#name)?;
+                #(#field_values)*
+                f.write_str(" }")
+            }
+        }
+    }
+}
+
+/// Generate an expression that converts the value of the field `f` at `access`
+/// to a `serde_json::Value`, through its `Serialize` implementation. Errors are
+/// returned with `?`, as a `serde_json::Error`.
+fn json_value_impl(krate: &syn::Path, f: &MarkerField, access: TokenStream) -> TokenStream {
+    // `Instant` doesn't implement `Serialize`, so use the value it's streamed
+    // as.
+    if f.format_name() == "Time" && is_instant_type(f.value_ty()) {
+        let value = if option_inner_type(&f.ty).is_some() {
+            quote!(#access.map(#krate::process_time))
+        } else {
+            quote!(#krate::process_time(#access))
+        };
+        return quote!(#krate::serde_json::Value::from(#value));
+    }
+    quote!(#krate::serde_json::to_value(&#access)?)
+}
+
+/// Implement `TryFrom<&Marker>` for `Vec<(String, serde_json::Value)>`, the
+/// keys and values of the fields in the order they're declared in, with those
+/// of flattened fields in place of the flattened field. Fields that fail to
+/// serialize are returned as the error.
+fn vec_from_impl(
+    krate: &syn::Path,
+    self_ty: &TokenStream,
+    generics: &syn::Generics,
+    fields: &[MarkerField],
+) -> TokenStream {
+    let entries = fields.iter().map(|f| {
+        let fname = &f.ident;
+        let key = &f.key;
+        if f.flatten {
+            return quote! {
+                entries.extend(::std::vec::Vec::<(
+                    ::std::string::String,
+                    #krate::serde_json::Value,
+                )>::try_from(&marker.#fname)?);
+            };
+        }
+        let value = json_value_impl(krate, f, quote!(marker.#fname));
+        quote! {
+            entries.push((#key.to_string(), #value));
+        }
+    });
+
+    let mut ref_generics = generics.clone();
+    ref_generics.params.insert(0, syn::parse_quote!('__marker));
+    let (impl_generics, _, where_clause) = ref_generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics ::std::convert::TryFrom<&'__marker #self_ty>
+            for ::std::vec::Vec<(::std::string::String, #krate::serde_json::Value)>
+            #where_clause
+        {
+            type Error = #krate::serde_json::Error;
+
+            fn try_from(
+                marker: &'__marker #self_ty,
+            ) -> ::std::result::Result<Self, #krate::serde_json::Error> {
+                let mut entries = ::std::vec::Vec::new();
+                #(#entries)*
+                ::std::result::Result::Ok(entries)
+            }
+        }
+    }
+}
+
+/// Implement `TryFrom<&HashMap<String, String>>`, which parses each field from
+/// the value of its key, e.g. to build a marker from query parameters. Fields
+/// of primitive and `String` types are parsed with `str::parse`, other types
+/// with `Deserialize`, from the string or the JSON it contains. Missing keys
+/// are errors, except for `Option` fields, which are `None`. Flattened fields
+/// are parsed from the same map.
+fn try_from_map_impl(
+    krate: &syn::Path,
+    self_ty: &TokenStream,
+    generics: &syn::Generics,
+    fields: &[MarkerField],
+) -> TokenStream {
+    let map_ty = quote! {
+        ::std::collections::HashMap<::std::string::String, ::std::string::String>
+    };
+    let field_values = fields.iter().map(|f| {
+        let fname = &f.ident;
+        let key = &f.key;
+        if f.flatten {
+            let ty = &f.ty;
+            return quote! {
+                #fname: <#ty as ::std::convert::TryFrom<&#map_ty>>::try_from(map)?
+            };
+        }
+        let value_ty = f.value_ty();
+        let parse = if f.format_name() == "Time" && is_instant_type(value_ty) {
+            quote!(#krate::parse_map_instant(#key, value)?)
+        } else if is_primitive_type(value_ty) || is_str_or_string(value_ty) {
+            quote!(#krate::parse_map_value::<#value_ty>(#key, value)?)
+        } else {
+            quote!(#krate::deserialize_map_value::<#value_ty>(#key, value)?)
+        };
+        if option_inner_type(&f.ty).is_some() {
+            quote! {
+                #fname: match map.get(#key) {
+                    ::std::option::Option::Some(value) => ::std::option::Option::Some(#parse),
+                    ::std::option::Option::None => ::std::option::Option::None,
+                }
+            }
+        } else {
+            quote! {
+                #fname: {
+                    let value = #krate::required_map_value(map, #key)?;
+                    #parse
+                }
+            }
+        }
+    });
+
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics ::std::convert::TryFrom<&#map_ty> for #self_ty #where_clause {
+            type Error = ::std::string::String;
+
+            fn try_from(map: &#map_ty) -> ::std::result::Result<Self, ::std::string::String> {
+                ::std::result::Result::Ok(Self {
+                    #(#field_values),*
+                })
+            }
+        }
+    }
+}
+
+/// Implement `TryFrom<Marker>` for `BTreeMap<String, serde_json::Value>`, from
+/// the key and value of each field, with the fields of flattened fields
+/// inlined. Fields that fail to serialize are returned as the error.
+fn btree_map_from_impl(
+    krate: &syn::Path,
+    self_ty: &TokenStream,
+    generics: &syn::Generics,
+    fields: &[MarkerField],
+) -> TokenStream {
+    let entries = fields.iter().map(|f| {
+        let fname = &f.ident;
+        let key = &f.key;
+        if f.flatten {
+            let ty = &f.ty;
+            return quote! {
+                map.extend(::std::collections::BTreeMap::<
+                    ::std::string::String,
+                    #krate::serde_json::Value,
+                >::try_from(marker.#fname)?);
+            };
+        }
+        let value = json_value_impl(krate, f, quote!(marker.#fname));
+        quote! {
+            map.insert(#key.to_string(), #value);
+        }
+    });
+
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics ::std::convert::TryFrom<#self_ty>
+            for ::std::collections::BTreeMap<::std::string::String, #krate::serde_json::Value>
+            #where_clause
+        {
+            type Error = #krate::serde_json::Error;
+
+            fn try_from(
+                marker: #self_ty,
+            ) -> ::std::result::Result<Self, #krate::serde_json::Error> {
+                let mut map = ::std::collections::BTreeMap::new();
+                #(#entries)*
+                ::std::result::Result::Ok(map)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{impl_summary, parse_named_fields};
+    use proc_macro2::Span;
+    use syn::Ident;
+
+    fn summary(fields: syn::FieldsNamed) -> String {
+        let name = Ident::new("TestMarker", Span::call_site());
+        impl_summary(&name, &parse_named_fields(&fields, None))
+    }
+
+    #[test]
+    fn impl_summary_fields() {
+        let fields: syn::FieldsNamed = syn::parse_quote!({
+            #[format(Integer)]
+            count: u32,
+            name: String,
+            #[flatten]
+            timing: Timing,
+            #[serde_property]
+            tags: Vec<String>,
+            value: Option<Custom>,
+            r#type: String,
+        });
+        assert_eq!(
+            summary(fields),
+            "Generated by the ProfilerMarker derive for TestMarker with fields: \
+             count (Integer), name (String), timing (flattened Timing), \
+             tags (serialized), value (format of Custom), type (String)."
+        );
+    }
+
+    #[test]
+    fn impl_summary_no_fields() {
+        assert_eq!(
+            summary(syn::parse_quote!({})),
+            "Generated by the ProfilerMarker derive for TestMarker, which has no fields."
+        );
+    }
 }
@@ -1,7 +1,25 @@
 #![allow(unused_variables, dead_code, non_camel_case_types)]
 
+// Allow generated code that refers to `::fx_markers` to be used within this crate too.
+extern crate self as fx_markers;
+
 use serde::{de::DeserializeOwned, Serialize};
+pub use serde_json;
+use std::any::TypeId;
+use std::backtrace::Backtrace;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, OnceLock, RwLock};
+use std::task::{Context, Poll};
+use std::time::Instant;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MarkerSchema_Location {
     MarkerChart = 0,
     MarkerTable = 1,
@@ -15,6 +33,7 @@ pub enum MarkerSchema_Location {
 /// Marker locations to be displayed in the profiler front-end.
 pub type Location = MarkerSchema_Location;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MarkerSchema_Format {
     Url = 0,
     FilePath = 1,
@@ -31,11 +50,22 @@ pub enum MarkerSchema_Format {
     Percentage = 12,
     Integer = 13,
     Decimal = 14,
+    // A u64 identifier that's unique across processes. The markers with the
+    // same flow id, up to a terminating flow with that id, are linked together.
+    Flow = 15,
+    // Ends the flow with the given id, which can then be reused.
+    TerminatingFlow = 16,
+    // Process and thread ids, streamed as integers.
+    Pid = 17,
+    Tid = 18,
+    // A list of values, streamed as a JSON array.
+    List = 19,
 }
 
 /// Formats of marker properties for profiler front-end.
 pub type Format = MarkerSchema_Format;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MarkerSchema_Searchable {
     NotSearchable = 0,
     Searchable = 1,
@@ -44,6 +74,310 @@ pub enum MarkerSchema_Searchable {
 /// Whether it's searchable or not in the profiler front-end.
 pub type Searchable = MarkerSchema_Searchable;
 
+/// `!searchable` toggles searchability, e.g. for a row that's searchable
+/// exactly when another one isn't.
+impl std::ops::Not for MarkerSchema_Searchable {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        match self {
+            Searchable::Searchable => Searchable::NotSearchable,
+            Searchable::NotSearchable => Searchable::Searchable,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MarkerSchema_GraphType {
+    Line = 0,
+    Bar = 1,
+    FilledLine = 2,
+}
+
+/// How a graph of numeric marker data is drawn in the timeline track.
+pub type GraphType = MarkerSchema_GraphType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MarkerSchema_GraphColor {
+    Blue = 0,
+    Green = 1,
+    Grey = 2,
+    Ink = 3,
+    Magenta = 4,
+    Orange = 5,
+    Purple = 6,
+    Red = 7,
+    Teal = 8,
+    Yellow = 9,
+}
+
+/// Colors of marker graphs in the profiler front-end.
+pub type GraphColor = MarkerSchema_GraphColor;
+
+impl MarkerSchema_Location {
+    /// Every location, in declaration order.
+    pub const ALL: &'static [Location] = &[
+        Location::MarkerChart,
+        Location::MarkerTable,
+        Location::TimelineOverview,
+        Location::TimelineMemory,
+        Location::TimelineIPC,
+        Location::TimelineFileIO,
+        Location::StackChart,
+    ];
+
+    /// The name of the location in the schema JSON.
+    pub const fn json_name(self) -> &'static str {
+        match self {
+            Location::MarkerChart => "marker-chart",
+            Location::MarkerTable => "marker-table",
+            Location::TimelineOverview => "timeline-overview",
+            Location::TimelineMemory => "timeline-memory",
+            Location::TimelineIPC => "timeline-ipc",
+            Location::TimelineFileIO => "timeline-fileio",
+            Location::StackChart => "stack-chart",
+        }
+    }
+
+    /// The location with the given name in the schema JSON, if there is one.
+    pub fn from_json_name(name: &str) -> Option<Self> {
+        Location::ALL
+            .iter()
+            .copied()
+            .find(|location| location.json_name() == name)
+    }
+}
+
+impl MarkerSchema_Format {
+    /// The name of the format in the schema JSON.
+    pub const fn json_name(self) -> &'static str {
+        match self {
+            Format::Url => "url",
+            Format::FilePath => "file-path",
+            Format::SanitizedString => "sanitized-string",
+            Format::String => "string",
+            Format::UniqueString => "unique-string",
+            Format::Duration => "duration",
+            Format::Time => "time",
+            Format::Seconds => "seconds",
+            Format::Milliseconds => "milliseconds",
+            Format::Microseconds => "microseconds",
+            Format::Nanoseconds => "nanoseconds",
+            Format::Bytes => "bytes",
+            Format::Percentage => "percentage",
+            Format::Integer => "integer",
+            Format::Decimal => "decimal",
+            Format::Flow => "flow-id",
+            Format::TerminatingFlow => "terminating-flow-id",
+            Format::Pid => "pid",
+            Format::Tid => "tid",
+            Format::List => "list",
+        }
+    }
+
+    /// The format with the given name in the schema JSON, if there is one.
+    pub fn from_json_name(name: &str) -> Option<Self> {
+        [
+            Format::Url,
+            Format::FilePath,
+            Format::SanitizedString,
+            Format::String,
+            Format::UniqueString,
+            Format::Duration,
+            Format::Time,
+            Format::Seconds,
+            Format::Milliseconds,
+            Format::Microseconds,
+            Format::Nanoseconds,
+            Format::Bytes,
+            Format::Percentage,
+            Format::Integer,
+            Format::Decimal,
+            Format::Flow,
+            Format::TerminatingFlow,
+            Format::Pid,
+            Format::Tid,
+            Format::List,
+        ]
+        .into_iter()
+        .find(|format| format.json_name() == name)
+    }
+
+    /// Whether values of this format are streamed as JSON strings. Unique
+    /// strings are streamed as their index in the unique string table.
+    pub fn is_string(self) -> bool {
+        matches!(
+            self,
+            Format::Url | Format::FilePath | Format::SanitizedString | Format::String
+        )
+    }
+
+    /// Format a value, as returned by `ProfilerMarker::field_value_as_string()`,
+    /// for people to read, e.g. `"42.3ms"` for a `Duration`, or `"1.23 MB"` for
+    /// `Bytes`. Numeric values that can't be parsed are returned unchanged.
+    /// With the `terminal` feature, `Url` values are written as terminal
+    /// hyperlinks.
+    pub fn pretty_format_value(self, value: &str) -> String {
+        if self.is_string() || self == Format::UniqueString {
+            return match self {
+                #[cfg(feature = "terminal")]
+                Format::Url if !value.is_empty() => {
+                    format!("\x1b]8;;{0}\x1b\\{0}\x1b]8;;\x1b\\", value)
+                }
+                _ => value.to_string(),
+            };
+        }
+        let Ok(number) = value.parse::<f64>() else {
+            return value.to_string();
+        };
+        match self {
+            Format::Duration | Format::Time | Format::Milliseconds => {
+                format!("{}ms", trimmed_decimal(number, 3))
+            }
+            Format::Seconds => format!("{}s", trimmed_decimal(number, 3)),
+            Format::Microseconds => format!("{}µs", trimmed_decimal(number, 3)),
+            Format::Nanoseconds => format!("{}ns", trimmed_decimal(number, 3)),
+            Format::Bytes => {
+                const UNITS: [&str; 5] = ["kB", "MB", "GB", "TB", "PB"];
+                if number.abs() < 1000.0 {
+                    return format!("{} B", number);
+                }
+                let mut scaled = number / 1000.0;
+                let mut unit = 0;
+                while scaled.abs() >= 1000.0 && unit < UNITS.len() - 1 {
+                    scaled /= 1000.0;
+                    unit += 1;
+                }
+                format!("{:.2} {}", scaled, UNITS[unit])
+            }
+            Format::Percentage => format!("{}%", trimmed_decimal(number * 100.0, 2)),
+            Format::Decimal => trimmed_decimal(number, 3),
+            _ => value.to_string(),
+        }
+    }
+}
+
+/// The type of JSON value that a marker property is streamed as, e.g. for
+/// generating or validating a JSON schema of marker payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JsonTypeHint {
+    Number,
+    String,
+    Boolean,
+    Null,
+    Array,
+    Object,
+}
+
+impl JsonTypeHint {
+    /// The name of the type in JSON Schema.
+    pub fn json_name(self) -> &'static str {
+        match self {
+            JsonTypeHint::Number => "number",
+            JsonTypeHint::String => "string",
+            JsonTypeHint::Boolean => "boolean",
+            JsonTypeHint::Null => "null",
+            JsonTypeHint::Array => "array",
+            JsonTypeHint::Object => "object",
+        }
+    }
+}
+
+/// The type of JSON value that properties of the format are streamed as by
+/// the derive. Unique strings are streamed as their index in the unique string
+/// table, so they're numbers.
+impl From<Format> for JsonTypeHint {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Url | Format::FilePath | Format::SanitizedString | Format::String => {
+                JsonTypeHint::String
+            }
+            Format::List => JsonTypeHint::Array,
+            Format::UniqueString
+            | Format::Duration
+            | Format::Time
+            | Format::Seconds
+            | Format::Milliseconds
+            | Format::Microseconds
+            | Format::Nanoseconds
+            | Format::Bytes
+            | Format::Percentage
+            | Format::Integer
+            | Format::Decimal
+            | Format::Flow
+            | Format::TerminatingFlow
+            | Format::Pid
+            | Format::Tid => JsonTypeHint::Number,
+        }
+    }
+}
+
+/// Write `value` with at most `digits` fractional digits, without trailing zeros.
+fn trimmed_decimal(value: f64, digits: usize) -> String {
+    let formatted = format!("{:.*}", digits, value);
+    if !formatted.contains('.') {
+        return formatted;
+    }
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+impl MarkerSchema_GraphType {
+    /// The name of the graph type in the schema JSON.
+    pub const fn json_name(self) -> &'static str {
+        match self {
+            GraphType::Line => "line",
+            GraphType::Bar => "bar",
+            GraphType::FilledLine => "line-filled",
+        }
+    }
+
+    /// The graph type with the given name in the schema JSON, if there is one.
+    pub fn from_json_name(name: &str) -> Option<Self> {
+        [GraphType::Line, GraphType::Bar, GraphType::FilledLine]
+            .into_iter()
+            .find(|graph_type| graph_type.json_name() == name)
+    }
+}
+
+impl MarkerSchema_GraphColor {
+    /// The name of the color in the schema JSON.
+    pub const fn json_name(self) -> &'static str {
+        match self {
+            GraphColor::Blue => "blue",
+            GraphColor::Green => "green",
+            GraphColor::Grey => "grey",
+            GraphColor::Ink => "ink",
+            GraphColor::Magenta => "magenta",
+            GraphColor::Orange => "orange",
+            GraphColor::Purple => "purple",
+            GraphColor::Red => "red",
+            GraphColor::Teal => "teal",
+            GraphColor::Yellow => "yellow",
+        }
+    }
+
+    /// The color with the given name in the schema JSON, if there is one.
+    pub fn from_json_name(name: &str) -> Option<Self> {
+        [
+            GraphColor::Blue,
+            GraphColor::Green,
+            GraphColor::Grey,
+            GraphColor::Ink,
+            GraphColor::Magenta,
+            GraphColor::Orange,
+            GraphColor::Purple,
+            GraphColor::Red,
+            GraphColor::Teal,
+            GraphColor::Yellow,
+        ]
+        .into_iter()
+        .find(|color| color.json_name() == name)
+    }
+}
+
 /// This object collects all the information necessary to stream the JSON schema
 /// that informs the front-end how to display a type of markers.
 /// It will be created and populated in `marker_type_display()` functions in each
@@ -51,20 +385,155 @@ pub type Searchable = MarkerSchema_Searchable;
 ///
 /// It's a RAII object that constructs and destroys a C++ MarkerSchema object
 /// pointed to a specified reference.
+///
+/// Schemas are `Send` and `Sync`: the schema memoized by
+/// `marker_type_display_ref()` is shared by every thread that adds markers of
+/// its type, so whatever backs a schema must stay safe to share.
+#[derive(Debug, Clone, PartialEq)]
 pub struct MarkerSchema {
-    pub(crate) pin: u32,
+    locations: Vec<Location>,
+    location_notes: Vec<(Location, String)>,
+    special_frontend_location: bool,
+    chart_label: Option<String>,
+    tooltip_label: Option<String>,
+    table_label: Option<String>,
+    description: Option<String>,
+    rows: Vec<SchemaRow>,
+    graphs: Vec<SchemaGraph>,
+}
+
+/// A row in the full marker description, see the `add_...` functions of
+/// `MarkerSchema`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaRow {
+    /// A data element streamed by `stream_json_marker_data()`.
+    Dynamic {
+        key: String,
+        label: Option<String>,
+        format: Format,
+        searchable: Searchable,
+    },
+    /// A static label and value.
+    Static { label: String, value: String },
+}
+
+/// Rows are ordered by key, with static rows, which have no key, after the
+/// dynamic ones, as by `MarkerSchema::sort_rows_by_key()`. Rows with the same
+/// key are ordered by their other fields, so that only equal rows are equal.
+impl Ord for SchemaRow {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        fn sort_key(row: &SchemaRow) -> (u8, &str, Option<&str>, u8, u8) {
+            match row {
+                SchemaRow::Dynamic {
+                    key,
+                    label,
+                    format,
+                    searchable,
+                } => (
+                    0,
+                    key.as_str(),
+                    label.as_deref(),
+                    *format as u8,
+                    *searchable as u8,
+                ),
+                SchemaRow::Static { label, value } => {
+                    (1, label.as_str(), Some(value.as_str()), 0, 0)
+                }
+            }
+        }
+        sort_key(self).cmp(&sort_key(other))
+    }
+}
+
+impl PartialOrd for SchemaRow {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl SchemaRow {
+    /// The key of a dynamic row, or `None` for a static row.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            SchemaRow::Dynamic { key, .. } => Some(key),
+            SchemaRow::Static { .. } => None,
+        }
+    }
+}
+
+/// A graph of a numeric data element, drawn as a track in the timeline.
+/// See `MarkerSchema::add_graph_line`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaGraph {
+    pub key: String,
+    pub graph_type: GraphType,
+    pub color: Option<GraphColor>,
+}
+
+/// The size of a compact JSON array or object whose items (or properties) have
+/// the given sizes: the items, separated by commas, between brackets.
+fn json_container_size(item_sizes: impl IntoIterator<Item = usize>) -> usize {
+    let (count, total) = item_sizes
+        .into_iter()
+        .fold((0usize, 0), |(count, total), size| (count + 1, total + size));
+    2 + total + count.saturating_sub(1)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The number of schemas created on this thread, so that tests can check
+    /// when schemas are built.
+    static SCHEMAS_CREATED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
 }
 
 impl MarkerSchema {
     // Initialize a marker schema with the given `Location`s.
     pub fn new(locations: &[Location]) -> Self {
-        MarkerSchema { pin: 42 }
+        #[cfg(test)]
+        SCHEMAS_CREATED.with(|created| created.set(created.get() + 1));
+        MarkerSchema {
+            locations: locations.to_vec(),
+            location_notes: vec![],
+            special_frontend_location: false,
+            chart_label: None,
+            tooltip_label: None,
+            table_label: None,
+            description: None,
+            rows: vec![],
+            graphs: vec![],
+        }
+    }
+
+    /// Marker schema for markers displayed in every location, i.e.
+    /// `MarkerSchema::new(Location::ALL)`.
+    pub fn new_with_all_locations() -> Self {
+        MarkerSchema::new(Location::ALL)
+    }
+
+    /// Marker schema for the given locations, with the given rows. Collecting
+    /// rows into a `MarkerSchema` gives a schema without any location.
+    pub fn from_rows_with_locations<I>(locations: &[Location], rows: I) -> Self
+    where
+        I: IntoIterator<Item = SchemaRow>,
+    {
+        let mut schema = MarkerSchema::new(locations);
+        schema.rows.extend(rows);
+        schema
     }
 
     /// Marker schema for types that have special frontend handling.
     /// Nothing else should be set in this case.
     pub fn new_with_special_frontend_location() -> Self {
-        MarkerSchema { pin: 42 }
+        MarkerSchema {
+            special_frontend_location: true,
+            ..MarkerSchema::new(&[])
+        }
+    }
+
+    /// Replace the locations the marker is displayed in.
+    pub fn set_locations(&mut self, locations: &[Location]) -> &mut Self {
+        self.locations = locations.to_vec();
+        self
     }
 
     /// Optional label in the marker chart.
@@ -72,6 +541,7 @@ impl MarkerSchema {
     /// can contain element keys in braces to include data elements streamed by
     /// `stream_json_marker_data()`. E.g.: "This is {marker.data.text}"
     pub fn set_chart_label(&mut self, label: &str) -> &mut Self {
+        self.chart_label = Some(label.to_string());
         self
     }
 
@@ -80,6 +550,7 @@ impl MarkerSchema {
     /// can contain element keys in braces to include data elements streamed by
     /// `stream_json_marker_data()`. E.g.: "This is {marker.data.text}"
     pub fn set_tooltip_label(&mut self, label: &str) -> &mut Self {
+        self.tooltip_label = Some(label.to_string());
         self
     }
 
@@ -88,6 +559,7 @@ impl MarkerSchema {
     /// can contain element keys in braces to include data elements streamed by
     /// `stream_json_marker_data()`. E.g.: "This is {marker.data.text}"
     pub fn set_table_label(&mut self, label: &str) -> &mut Self {
+        self.table_label = Some(label.to_string());
         self
     }
 
@@ -96,6 +568,110 @@ impl MarkerSchema {
     /// in braces to include data elements streamed by `stream_json_marker_data()`.
     /// E.g.: "This is {marker.data.text}"
     pub fn set_all_labels(&mut self, label: &str) -> &mut Self {
+        self.set_chart_label(label)
+            .set_tooltip_label(label)
+            .set_table_label(label)
+    }
+
+    /// Optional documentation of the marker type, which the front-end can show
+    /// when the user asks for help on it.
+    pub fn set_description(&mut self, description: &str) -> &mut Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    /// Optional documentation for one of the schema's locations, which the
+    /// front-end shows as a tooltip on the toggle for that location.
+    /// Replaces any note previously set for the location.
+    pub fn set_location_note(&mut self, location: Location, note: &str) -> &mut Self {
+        match self.location_notes.iter_mut().find(|(l, _)| *l == location) {
+            Some((_, existing)) => *existing = note.to_string(),
+            None => self.location_notes.push((location, note.to_string())),
+        }
+        self
+    }
+
+    // The `with_...` functions below are owned versions of the `set_...` and
+    // `add_...` functions, so that a schema can be built in one expression, e.g.
+    // `MarkerSchema::new(&[..]).with_chart_label("..").with_key_format(..)`.
+
+    /// Same as `set_chart_label`, for building a schema in one expression.
+    pub fn with_chart_label(mut self, label: &str) -> Self {
+        self.set_chart_label(label);
+        self
+    }
+
+    /// Same as `set_tooltip_label`, for building a schema in one expression.
+    pub fn with_tooltip_label(mut self, label: &str) -> Self {
+        self.set_tooltip_label(label);
+        self
+    }
+
+    /// Same as `set_table_label`, for building a schema in one expression.
+    pub fn with_table_label(mut self, label: &str) -> Self {
+        self.set_table_label(label);
+        self
+    }
+
+    /// Same as `set_all_labels`, for building a schema in one expression.
+    pub fn with_all_labels(mut self, label: &str) -> Self {
+        self.set_all_labels(label);
+        self
+    }
+
+    /// Same as `set_description`, for building a schema in one expression.
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.set_description(description);
+        self
+    }
+
+    /// Same as `set_location_note`, for building a schema in one expression.
+    pub fn with_location_note(mut self, location: Location, note: &str) -> Self {
+        self.set_location_note(location, note);
+        self
+    }
+
+    /// Same as `add_key_format`, for building a schema in one expression.
+    pub fn with_key_format(mut self, key: &str, format: Format) -> Self {
+        self.add_key_format(key, format);
+        self
+    }
+
+    /// Same as `add_key_label_format`, for building a schema in one expression.
+    pub fn with_key_label_format(mut self, key: &str, label: &str, format: Format) -> Self {
+        self.add_key_label_format(key, label, format);
+        self
+    }
+
+    /// Same as `add_key_format_searchable`, for building a schema in one
+    /// expression.
+    pub fn with_key_format_searchable(
+        mut self,
+        key: &str,
+        format: Format,
+        searchable: Searchable,
+    ) -> Self {
+        self.add_key_format_searchable(key, format, searchable);
+        self
+    }
+
+    /// Same as `add_key_label_format_searchable`, for building a schema in one
+    /// expression.
+    pub fn with_key_label_format_searchable(
+        mut self,
+        key: &str,
+        label: &str,
+        format: Format,
+        searchable: Searchable,
+    ) -> Self {
+        self.add_key_label_format_searchable(key, label, format, searchable);
+        self
+    }
+
+    /// Same as `add_static_label_value`, for building a schema in one
+    /// expression.
+    pub fn with_static_label_value(mut self, label: &str, value: &str) -> Self {
+        self.add_static_label_value(label, value);
         self
     }
 
@@ -112,7 +688,7 @@ impl MarkerSchema {
     /// - `key`: Element property name as streamed by `stream_json_marker_data()`.
     /// - `format`: How to format the data element value, see `Format` above.
     pub fn add_key_format(&mut self, key: &str, format: Format) -> &mut Self {
-        self
+        self.add_dynamic_row(key, None, format, Searchable::NotSearchable)
     }
 
     /// Add a key / label / format row for the marker data element.
@@ -120,7 +696,7 @@ impl MarkerSchema {
     /// - `label`: Optional label. Defaults to the key name.
     /// - `format`: How to format the data element value, see `Format` above.
     pub fn add_key_label_format(&mut self, key: &str, label: &str, format: Format) -> &mut Self {
-        self
+        self.add_dynamic_row(key, Some(label), format, Searchable::NotSearchable)
     }
 
     /// Add a key / format / searchable row for the marker data element.
@@ -132,7 +708,7 @@ impl MarkerSchema {
         format: Format,
         searchable: Searchable,
     ) -> &mut Self {
-        self
+        self.add_dynamic_row(key, None, format, searchable)
     }
 
     /// Add a key / label / format / searchable row for the marker data element.
@@ -148,94 +724,7590 @@ impl MarkerSchema {
         format: Format,
         searchable: Searchable,
     ) -> &mut Self {
-        self
+        self.add_dynamic_row(key, Some(label), format, searchable)
     }
 
     /// Add a key / value static row.
     /// - `key`: Element property name as streamed by `stream_json_marker_data()`.
     /// - `value`: Static value to display.
     pub fn add_static_label_value(&mut self, label: &str, value: &str) -> &mut Self {
+        self.rows.push(SchemaRow::Static {
+            label: label.to_string(),
+            value: value.to_string(),
+        });
         self
     }
-}
 
-pub type SpliceableJSONWriter = str;
+    /// Add a graph of a numeric data element, drawn as a track in the timeline.
+    /// - `key`: Element property name as streamed by `stream_json_marker_data()`.
+    /// - `graph_type`: How to draw the graph, see `GraphType` above.
+    pub fn add_graph_line(&mut self, key: &str, graph_type: GraphType) -> &mut Self {
+        self.add_graph(key, graph_type, None)
+    }
+
+    /// Add a graph of a numeric data element, drawn as a track in the timeline.
+    /// - `key`: Element property name as streamed by `stream_json_marker_data()`.
+    /// - `graph_type`: How to draw the graph, see `GraphType` above.
+    /// - `color`: The color to draw the graph with, see `GraphColor` above.
+    pub fn add_graph_line_with_color(
+        &mut self,
+        key: &str,
+        graph_type: GraphType,
+        color: GraphColor,
+    ) -> &mut Self {
+        self.add_graph(key, graph_type, Some(color))
+    }
 
-#[derive(Debug)]
-pub struct JSONWriter<'a>(&'a mut SpliceableJSONWriter);
+    fn add_graph(
+        &mut self,
+        key: &str,
+        graph_type: GraphType,
+        color: Option<GraphColor>,
+    ) -> &mut Self {
+        self.graphs.push(SchemaGraph {
+            key: key.to_string(),
+            graph_type,
+            color,
+        });
+        self
+    }
 
-impl<'a> JSONWriter<'a> {
-    /// Constructor for the JSONWriter object. It takes a C++ SpliceableJSONWriter
-    /// reference as its argument and stores it for later accesses.
-    pub(crate) fn new(json_writer: &'a mut SpliceableJSONWriter) -> Self {
-        JSONWriter(json_writer)
+    fn add_dynamic_row(
+        &mut self,
+        key: &str,
+        label: Option<&str>,
+        format: Format,
+        searchable: Searchable,
+    ) -> &mut Self {
+        self.rows.push(SchemaRow::Dynamic {
+            key: key.to_string(),
+            label: label.map(str::to_string),
+            format,
+            searchable,
+        });
+        self
     }
 
-    /// Adds an int property to the JSON.
-    /// Prints: "<name>": <value>
-    pub fn int_property(&mut self, name: &str, value: i64) {
-        unimplemented!()
+    /// The locations the marker is displayed in.
+    pub fn locations(&self) -> &[Location] {
+        &self.locations
     }
 
-    /// Adds a float property to the JSON.
-    /// Prints: "<name>": <value>
-    pub fn float_property(&mut self, name: &str, value: f64) {
-        unimplemented!()
+    /// The note set for `location` with `set_location_note()`, if any.
+    pub fn location_note(&self, location: Location) -> Option<&str> {
+        self.location_notes
+            .iter()
+            .find(|(l, _)| *l == location)
+            .map(|(_, note)| note.as_str())
     }
 
-    /// Adds an bool property to the JSON.
-    /// Prints: "<name>": <value>
-    pub fn bool_property(&mut self, name: &str, value: bool) {
-        unimplemented!()
+    /// Whether this schema was created with `new_with_special_frontend_location()`.
+    pub fn is_special_frontend_location(&self) -> bool {
+        self.special_frontend_location
     }
 
-    /// Adds a string property to the JSON.
-    /// Prints: "<name>": "<value>"
-    pub fn string_property(&mut self, name: &str, value: &str) {
-        unimplemented!()
+    pub fn chart_label(&self) -> Option<&str> {
+        self.chart_label.as_deref()
     }
 
-    /// Adds a unique string property to the JSON.
-    /// Prints: "<name>": <string_table_index>
-    pub fn unique_string_property(&mut self, name: &str, value: &str) {
-        unimplemented!()
+    pub fn tooltip_label(&self) -> Option<&str> {
+        self.tooltip_label.as_deref()
     }
 
-    /// Adds a null property to the JSON.
-    /// Prints: "<name>": null
-    pub fn null_property(&mut self, name: &str) {
-        unimplemented!()
+    pub fn table_label(&self) -> Option<&str> {
+        self.table_label.as_deref()
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// The rows of the full marker description, in the order they were added.
+    pub fn rows(&self) -> &[SchemaRow] {
+        &self.rows
+    }
+
+    /// Whether the schema has a dynamic row with the given key.
+    pub fn has_key(&self, key: &str) -> bool {
+        self.rows.iter().any(|row| row.key() == Some(key))
+    }
+
+    /// Check that the schema has a dynamic row for each of `required_keys`,
+    /// e.g. the minimum a consumer of the markers expects. Otherwise, the
+    /// missing keys are returned, in the order they're required in.
+    pub fn satisfies_requirements(&self, required_keys: &[&str]) -> Result<(), Vec<String>> {
+        let missing: Vec<String> = required_keys
+            .iter()
+            .filter(|key| !self.has_key(key))
+            .map(|key| key.to_string())
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(missing)
+        }
+    }
+
+    /// The keys of the dynamic rows, in the order they were added.
+    pub fn field_keys(&self) -> Vec<&str> {
+        self.rows.iter().filter_map(SchemaRow::key).collect()
+    }
+
+    /// The dynamic rows with the given format, in the order they were added.
+    pub fn iter_rows_with_format(&self, format: Format) -> impl Iterator<Item = &SchemaRow> {
+        self.rows
+            .iter()
+            .filter(move |row| matches!(row, SchemaRow::Dynamic { format: f, .. } if *f == format))
+    }
+
+    /// The number of rows the schema can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.rows.capacity()
+    }
+
+    /// Reserve space for at least `additional` more rows, like `Vec::reserve`.
+    pub fn reserve(&mut self, additional: usize) {
+        self.rows.reserve(additional);
+    }
+
+    /// Merge the locations, rows and graphs of `other` into this schema, e.g. to
+    /// combine the schemas of markers whose payloads are streamed together.
+    /// Locations, and their notes, are added if this schema doesn't already
+    /// have them. Rows and graphs are appended, except for those whose key is
+    /// already used by this schema. Labels, and the description, are only taken
+    /// from `other` if this schema has none.
+    pub fn merge(&mut self, other: &MarkerSchema) -> &mut Self {
+        for location in &other.locations {
+            if !self.locations.contains(location) {
+                self.locations.push(*location);
+                if let Some(note) = other.location_note(*location) {
+                    self.location_notes.push((*location, note.to_string()));
+                }
+            }
+        }
+        let labels = [
+            (&mut self.chart_label, &other.chart_label),
+            (&mut self.tooltip_label, &other.tooltip_label),
+            (&mut self.table_label, &other.table_label),
+            (&mut self.description, &other.description),
+        ];
+        for (label, other_label) in labels {
+            if label.is_none() {
+                label.clone_from(other_label);
+            }
+        }
+        for row in &other.rows {
+            if row.key().is_none_or(|key| self.row_index_of(key).is_none()) {
+                self.rows.push(row.clone());
+            }
+        }
+        for graph in &other.graphs {
+            if !self.graphs.iter().any(|g| g.key == graph.key) {
+                self.graphs.push(graph.clone());
+            }
+        }
+        self
+    }
+
+    /// A new schema with the locations of `a` and `b`, the rows of `a`, and the
+    /// rows of `b` whose key isn't used by `a`, as by `a.clone().merge(b)`.
+    pub fn union(a: &MarkerSchema, b: &MarkerSchema) -> MarkerSchema {
+        let mut schema = a.clone();
+        schema.merge(b);
+        schema
+    }
+
+    /// Estimate the size in bytes of the schema JSON written by `to_json()`,
+    /// without building it, e.g. to allocate buffers up front. The size is
+    /// exact for compact JSON, except that the marker type name isn't counted,
+    /// nor are the escapes of characters like quotes in the strings.
+    pub fn approximate_json_size(&self) -> usize {
+        let quoted = |value: &str| value.len() + 2;
+        let property = |name: &str, value_size: usize| quoted(name) + 1 + value_size;
+
+        let mut properties = vec![property("name", quoted(""))];
+        if self.special_frontend_location {
+            properties.push(property("display", json_container_size([])));
+            return json_container_size(properties);
+        }
+        let display = self.locations.iter().map(|l| quoted(l.json_name()));
+        properties.push(property("display", json_container_size(display)));
+        if !self.location_notes.is_empty() {
+            let notes = self
+                .location_notes
+                .iter()
+                .map(|(l, note)| property(l.json_name(), quoted(note)));
+            properties.push(property("displayNotes", json_container_size(notes)));
+        }
+        let labels = [
+            ("chartLabel", &self.chart_label),
+            ("tooltipLabel", &self.tooltip_label),
+            ("tableLabel", &self.table_label),
+            ("description", &self.description),
+        ];
+        for (name, label) in labels {
+            if let Some(label) = label {
+                properties.push(property(name, quoted(label)));
+            }
+        }
+
+        let rows = self.rows.iter().map(|row| match row {
+            SchemaRow::Dynamic {
+                key,
+                label,
+                format,
+                searchable,
+            } => {
+                let label = label.as_ref().map(|label| property("label", quoted(label)));
+                let searchable = (*searchable == Searchable::Searchable)
+                    .then(|| property("searchable", "true".len()));
+                let properties = [
+                    Some(property("key", quoted(key))),
+                    label,
+                    Some(property("format", quoted(format.json_name()))),
+                    searchable,
+                ];
+                json_container_size(properties.into_iter().flatten())
+            }
+            SchemaRow::Static { label, value } => json_container_size([
+                property("label", quoted(label)),
+                property("value", quoted(value)),
+            ]),
+        });
+        properties.push(property("data", json_container_size(rows)));
+
+        if !self.graphs.is_empty() {
+            let graphs = self.graphs.iter().map(|graph| {
+                let color = graph
+                    .color
+                    .map(|color| property("color", quoted(color.json_name())));
+                let properties = [
+                    Some(property("key", quoted(&graph.key))),
+                    Some(property("type", quoted(graph.graph_type.json_name()))),
+                    color,
+                ];
+                json_container_size(properties.into_iter().flatten())
+            });
+            properties.push(property("graphs", json_container_size(graphs)));
+        }
+
+        json_container_size(properties)
+    }
+
+    /// Keep only the rows for which `predicate` returns true, in order, like
+    /// `Vec::retain`, e.g. to drop the rows that aren't searchable. Graphs are
+    /// left as they are, even if their row is removed.
+    pub fn retain_rows<F>(&mut self, predicate: F)
+    where
+        F: FnMut(&SchemaRow) -> bool,
+    {
+        self.rows.retain(predicate);
+    }
+
+    /// Sort the dynamic rows by key, so that the schema doesn't depend on the
+    /// order in which the fields were declared. The sort is stable, and static
+    /// rows, which have no key, are moved after the dynamic ones, in order.
+    pub fn sort_rows_by_key(&mut self) {
+        self.rows.sort_by(|a, b| match (a.key(), b.key()) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        });
+    }
+
+    /// Remove every row whose key was already used by an earlier row, keeping
+    /// the first occurrence of each key. Static rows are always kept. Duplicate
+    /// keys are a bug in the code that built the schema, as the front-end only
+    /// shows one of them.
+    pub fn dedup_rows(&mut self) {
+        let mut seen = HashSet::new();
+        self.rows.retain(|row| match row.key() {
+            Some(key) => seen.insert(key.to_string()),
+            None => true,
+        });
+    }
+
+    /// A copy of this schema, with the rows with duplicate keys removed as by
+    /// `dedup_rows`.
+    pub fn deduped(&self) -> MarkerSchema {
+        let mut schema = self.clone();
+        schema.dedup_rows();
+        schema
+    }
+
+    /// The row at `index`, if there is one.
+    pub fn row_at(&self, index: usize) -> Option<&SchemaRow> {
+        self.rows.get(index)
+    }
+
+    /// The index of the first row with the given key, if there is one.
+    pub fn row_index_of(&self, key: &str) -> Option<usize> {
+        self.rows.iter().position(|row| row.key() == Some(key))
+    }
+
+    /// A map from the key of each dynamic row to its format, label and
+    /// searchability, for code that looks up many rows by key. The label is
+    /// the key for rows without one, as in the front-end. If several rows have
+    /// the same key, the first one is used, like `row_index_of()`. The map is a
+    /// snapshot, which doesn't follow later changes to the schema.
+    pub fn to_hashmap(&self) -> HashMap<&str, (Format, &str, Searchable)> {
+        let mut map = HashMap::with_capacity(self.rows.len());
+        for row in &self.rows {
+            if let SchemaRow::Dynamic {
+                key,
+                label,
+                format,
+                searchable,
+            } = row
+            {
+                map.entry(key.as_str()).or_insert((
+                    *format,
+                    label.as_deref().unwrap_or(key),
+                    *searchable,
+                ));
+            }
+        }
+        map
+    }
+
+    /// The `(key, label)` of each row that has both, in the order they were
+    /// added. Static rows, and rows added without a label, are skipped.
+    pub fn key_label_pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.rows.iter().filter_map(|row| match row {
+            SchemaRow::Dynamic {
+                key,
+                label: Some(label),
+                ..
+            } => Some((key.as_str(), label.as_str())),
+            _ => None,
+        })
+    }
+
+    /// The graphs drawn in the timeline track, in the order they were added.
+    pub fn graphs(&self) -> &[SchemaGraph] {
+        &self.graphs
+    }
+
+    /// Write the schema for the marker type `name` as TOML, with the same
+    /// structure as the JSON written by `to_json()`, e.g. the rows are the
+    /// `[[data]]` tables. It's read back by `from_toml()`.
+    #[cfg(feature = "toml")]
+    pub fn to_toml(&self, name: &str) -> String {
+        toml::to_string(&self.to_json(name)).expect("the schema JSON is a TOML table")
+    }
+
+    /// Parse a schema written by `to_toml()`, with the same checks as parsing
+    /// the JSON written by `to_json()`. The name of the marker type is ignored.
+    #[cfg(feature = "toml")]
+    pub fn from_toml(s: &str) -> Result<MarkerSchema, toml::de::Error> {
+        let json: serde_json::Value = toml::from_str(s)?;
+        schema_from_json(&json).map_err(<toml::de::Error as serde::de::Error>::custom)
+    }
+
+    /// Generate Rust source for a block expression that evaluates to a
+    /// `MarkerSchema` equal to this one. This is intended for embedding
+    /// pre-computed schemas in generated code, e.g. from a `build.rs` script.
+    pub fn to_rust_literal(&self) -> String {
+        let mut calls = vec![];
+        let labels = [
+            ("set_chart_label", &self.chart_label),
+            ("set_tooltip_label", &self.tooltip_label),
+            ("set_table_label", &self.table_label),
+            ("set_description", &self.description),
+        ];
+        for (setter, label) in labels {
+            if let Some(label) = label {
+                calls.push(format!("{}({:?})", setter, label));
+            }
+        }
+        for (location, note) in &self.location_notes {
+            calls.push(format!(
+                "set_location_note(::fx_markers::Location::{:?}, {:?})",
+                location, note
+            ));
+        }
+        for row in &self.rows {
+            calls.push(match row {
+                SchemaRow::Dynamic {
+                    key,
+                    label: Some(label),
+                    format,
+                    searchable,
+                } => format!(
+                    "add_key_label_format_searchable({:?}, {:?}, ::fx_markers::Format::{:?}, ::fx_markers::Searchable::{:?})",
+                    key, label, format, searchable
+                ),
+                SchemaRow::Dynamic {
+                    key,
+                    label: None,
+                    format,
+                    searchable,
+                } => format!(
+                    "add_key_format_searchable({:?}, ::fx_markers::Format::{:?}, ::fx_markers::Searchable::{:?})",
+                    key, format, searchable
+                ),
+                SchemaRow::Static { label, value } => {
+                    format!("add_static_label_value({:?}, {:?})", label, value)
+                }
+            });
+        }
+        for graph in &self.graphs {
+            calls.push(match graph.color {
+                Some(color) => format!(
+                    "add_graph_line_with_color({:?}, ::fx_markers::GraphType::{:?}, ::fx_markers::GraphColor::{:?})",
+                    graph.key, graph.graph_type, color
+                ),
+                None => format!(
+                    "add_graph_line({:?}, ::fx_markers::GraphType::{:?})",
+                    graph.key, graph.graph_type
+                ),
+            });
+        }
+
+        let constructor = if self.special_frontend_location {
+            "::fx_markers::MarkerSchema::new_with_special_frontend_location()".to_string()
+        } else {
+            let locations: Vec<String> = self
+                .locations
+                .iter()
+                .map(|location| format!("::fx_markers::Location::{:?}", location))
+                .collect();
+            format!(
+                "::fx_markers::MarkerSchema::new(&[{}])",
+                locations.join(", ")
+            )
+        };
+        let binding = if calls.is_empty() {
+            "schema"
+        } else {
+            "mut schema"
+        };
+
+        let mut out = format!("{{\n    let {} = {};\n", binding, constructor);
+        for call in calls {
+            out.push_str(&format!("    schema.{};\n", call));
+        }
+        out.push_str("    schema\n}");
+        out
+    }
+
+    /// The JSON schema for the marker type `name`, as the front-end reads it.
+    /// Location notes are written to a `displayNotes` object, keyed by the
+    /// location's name in `display`.
+    pub fn to_json(&self, name: &str) -> serde_json::Value {
+        let mut schema = serde_json::Map::new();
+        schema.insert("name".to_string(), name.into());
+        if self.special_frontend_location {
+            schema.insert("display".to_string(), serde_json::Value::Array(vec![]));
+            return serde_json::Value::Object(schema);
+        }
+
+        let display = self.locations.iter().map(|l| l.json_name());
+        schema.insert("display".to_string(), display.collect::<Vec<_>>().into());
+        if !self.location_notes.is_empty() {
+            let notes = self
+                .location_notes
+                .iter()
+                .map(|(l, note)| (l.json_name().to_string(), note.as_str().into()));
+            schema.insert(
+                "displayNotes".to_string(),
+                serde_json::Value::Object(notes.collect()),
+            );
+        }
+        let labels = [
+            ("chartLabel", &self.chart_label),
+            ("tooltipLabel", &self.tooltip_label),
+            ("tableLabel", &self.table_label),
+            ("description", &self.description),
+        ];
+        for (property, label) in labels {
+            if let Some(label) = label {
+                schema.insert(property.to_string(), label.as_str().into());
+            }
+        }
+
+        let data = self.rows.iter().map(|row| match row {
+            SchemaRow::Dynamic {
+                key,
+                label,
+                format,
+                searchable,
+            } => {
+                let mut row = serde_json::Map::new();
+                row.insert("key".to_string(), key.as_str().into());
+                if let Some(label) = label {
+                    row.insert("label".to_string(), label.as_str().into());
+                }
+                row.insert("format".to_string(), format.json_name().into());
+                if *searchable == Searchable::Searchable {
+                    row.insert("searchable".to_string(), true.into());
+                }
+                serde_json::Value::Object(row)
+            }
+            SchemaRow::Static { label, value } => {
+                serde_json::json!({ "label": label, "value": value })
+            }
+        });
+        schema.insert("data".to_string(), data.collect());
+
+        if !self.graphs.is_empty() {
+            let graphs = self.graphs.iter().map(|graph| {
+                let mut json = serde_json::Map::new();
+                json.insert("key".to_string(), graph.key.as_str().into());
+                json.insert("type".to_string(), graph.graph_type.json_name().into());
+                if let Some(color) = graph.color {
+                    json.insert("color".to_string(), color.json_name().into());
+                }
+                serde_json::Value::Object(json)
+            });
+            schema.insert("graphs".to_string(), graphs.collect());
+        }
+
+        serde_json::Value::Object(schema)
+    }
+
+    /// Compare the JSON schema for the marker type `name` with `schema_json`, a
+    /// schema previously written by `to_json()`, e.g. a baseline stored to
+    /// detect schema changes in CI. Returns a description of each difference,
+    /// from the baseline to this schema. Rows are matched by their key (or the
+    /// label of static rows), and graphs by their key, so reordering them isn't
+    /// reported.
+    pub fn diff_against_json(&self, name: &str, schema_json: &str) -> Vec<String> {
+        let baseline: serde_json::Value = match serde_json::from_str(schema_json) {
+            Ok(baseline) => baseline,
+            Err(e) => return vec![format!("the baseline schema is not valid JSON: {}", e)],
+        };
+        let current = self.to_json(name);
+        let mut differences = vec![];
+        let properties = [
+            "name",
+            "display",
+            "displayNotes",
+            "chartLabel",
+            "tooltipLabel",
+            "tableLabel",
+            "description",
+        ];
+        for property in properties {
+            diff_json_value(
+                property,
+                baseline.get(property),
+                current.get(property),
+                &mut differences,
+            );
+        }
+        let row_id = |row: &serde_json::Value| {
+            row.get("key")
+                .or_else(|| row.get("label"))
+                .map(ToString::to_string)
+        };
+        diff_json_arrays("row", &baseline, &current, "data", row_id, &mut differences);
+        let graph_id = |graph: &serde_json::Value| graph.get("key").map(ToString::to_string);
+        diff_json_arrays(
+            "graph",
+            &baseline,
+            &current,
+            "graphs",
+            graph_id,
+            &mut differences,
+        );
+        differences
     }
 }
 
-pub trait ProfilerMarker: Serialize + DeserializeOwned {
-    /// A static method that returns the name of the marker type.
-    fn marker_type_name() -> &'static str;
-    /// A static method that returns a `MarkerSchema`, which contains all the
-    /// information needed to stream the display schema associated with a
-    /// marker type.
-    fn marker_type_display() -> MarkerSchema;
-    /// A method that streams the marker payload data as JSON object properties.
-    /// Please see the [JSONWriter] struct to see its methods.
-    fn stream_json_marker_data(&self, json_writer: &mut JSONWriter);
+/// Describe the difference between a property of a baseline schema JSON and of
+/// the current one, if there is any.
+fn diff_json_value(
+    path: &str,
+    baseline: Option<&serde_json::Value>,
+    current: Option<&serde_json::Value>,
+    differences: &mut Vec<String>,
+) {
+    match (baseline, current) {
+        (Some(baseline), Some(current)) if baseline != current => {
+            differences.push(format!("{} changed from {} to {}", path, baseline, current));
+        }
+        (Some(baseline), None) => differences.push(format!("{} {} was removed", path, baseline)),
+        (None, Some(current)) => differences.push(format!("{} {} was added", path, current)),
+        _ => {}
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::ProfilerMarker;
-    use profiler_macros::ProfilerMarker;
-    use serde::{Deserialize, Serialize};
-    
-    #[derive(Debug, Serialize, Deserialize, ProfilerMarker)]
-    #[marker_display(MarkerChart, MarkerTable, TimelineIPC)]
-    pub struct ExampleMarker {
-        #[searchable]
-        #[format(Integer)]
-        field1: u32,
-        #[format(String)]
-        field2: String,
-        #[format(Integer)]
-        field3: std::option::Option<f32>,
+/// Describe the differences between the elements of the array `property` of a
+/// baseline schema JSON and of the current one, matching the elements by `id`.
+fn diff_json_arrays(
+    kind: &str,
+    baseline: &serde_json::Value,
+    current: &serde_json::Value,
+    property: &str,
+    id: impl Fn(&serde_json::Value) -> Option<String>,
+    differences: &mut Vec<String>,
+) {
+    let elements = |schema: &serde_json::Value| -> Vec<(String, serde_json::Value)> {
+        let Some(elements) = schema.get(property).and_then(|e| e.as_array()) else {
+            return vec![];
+        };
+        elements
+            .iter()
+            .filter_map(|element| Some((id(element)?, element.clone())))
+            .collect()
+    };
+    let baseline = elements(baseline);
+    let current = elements(current);
+    let find = |elements: &[(String, serde_json::Value)], id: &str| {
+        elements
+            .iter()
+            .find(|(i, _)| i == id)
+            .map(|(_, e)| e.clone())
+    };
+    for (id, element) in &baseline {
+        match find(&current, id) {
+            None => differences.push(format!("{} {} was removed", kind, id)),
+            Some(current) => {
+                let mut keys: Vec<&String> = element
+                    .as_object()
+                    .into_iter()
+                    .chain(current.as_object())
+                    .flat_map(|object| object.keys())
+                    .collect();
+                keys.sort();
+                keys.dedup();
+                for key in keys {
+                    diff_json_value(
+                        &format!("{} {}: {}", kind, id, key),
+                        element.get(key),
+                        current.get(key),
+                        differences,
+                    );
+                }
+            }
+        }
+    }
+    for (id, _) in &current {
+        if find(&baseline, id).is_none() {
+            differences.push(format!("{} {} was added", kind, id));
+        }
+    }
+}
+
+/// Parse a JSON schema, as written by `MarkerSchema::to_json()`, back into a
+/// `MarkerSchema`. The name of the marker type in the JSON is ignored, as the
+/// schema doesn't hold it. Unknown locations, formats, graph types and colors
+/// are errors, as are rows and graphs without the properties `to_json()` writes.
+impl std::str::FromStr for MarkerSchema {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let json: serde_json::Value = serde_json::from_str(s)?;
+        schema_from_json(&json).map_err(<serde_json::Error as serde::de::Error>::custom)
+    }
+}
+
+fn schema_from_json(json: &serde_json::Value) -> Result<MarkerSchema, String> {
+    let str_property = |value: &serde_json::Value, key: &str| -> Result<Option<String>, String> {
+        match value.get(key) {
+            None => Ok(None),
+            Some(serde_json::Value::String(s)) => Ok(Some(s.clone())),
+            Some(other) => Err(format!(
+                "expected a string for \"{}\", found {}",
+                key, other
+            )),
+        }
+    };
+    let required = |value: &serde_json::Value, key: &str| {
+        str_property(value, key)?.ok_or_else(|| format!("missing \"{}\" in {}", key, value))
+    };
+    let array =
+        |value: &'_ serde_json::Value, key: &str| -> Result<Vec<serde_json::Value>, String> {
+            match value.get(key) {
+                None => Ok(vec![]),
+                Some(serde_json::Value::Array(elements)) => Ok(elements.clone()),
+                Some(other) => Err(format!(
+                    "expected an array for \"{}\", found {}",
+                    key, other
+                )),
+            }
+        };
+
+    if !json.is_object() {
+        return Err(format!("expected a schema object, found {}", json));
+    }
+    // Schemas with special front-end handling only have a name and an empty
+    // `display`, whereas every other schema has `data`.
+    if json.get("data").is_none() {
+        return Ok(MarkerSchema::new_with_special_frontend_location());
+    }
+
+    let mut locations = vec![];
+    for location in array(json, "display")? {
+        let name = location.as_str().unwrap_or_default();
+        locations.push(
+            Location::from_json_name(name)
+                .ok_or_else(|| format!("unknown location {}", location))?,
+        );
+    }
+    let mut schema = MarkerSchema::new(&locations);
+    if let Some(notes) = json.get("displayNotes").and_then(|n| n.as_object()) {
+        // Keep the notes in the order of their locations, rather than the order
+        // of the JSON object.
+        for location in &locations {
+            if let Some(note) = str_property(&json["displayNotes"], location.json_name())? {
+                schema.set_location_note(*location, &note);
+            }
+        }
+        if let Some(name) = notes
+            .keys()
+            .find(|name| !locations.iter().any(|l| l.json_name() == name.as_str()))
+        {
+            return Err(format!("note for the unknown location \"{}\"", name));
+        }
+    }
+    if let Some(label) = str_property(json, "chartLabel")? {
+        schema.set_chart_label(&label);
+    }
+    if let Some(label) = str_property(json, "tooltipLabel")? {
+        schema.set_tooltip_label(&label);
+    }
+    if let Some(label) = str_property(json, "tableLabel")? {
+        schema.set_table_label(&label);
+    }
+    if let Some(description) = str_property(json, "description")? {
+        schema.set_description(&description);
+    }
+
+    for row in array(json, "data")? {
+        if row.get("key").is_none() {
+            let label = required(&row, "label")?;
+            let value = required(&row, "value")?;
+            schema.add_static_label_value(&label, &value);
+            continue;
+        }
+        let key = required(&row, "key")?;
+        let format = required(&row, "format")?;
+        let format = Format::from_json_name(&format)
+            .ok_or_else(|| format!("unknown format \"{}\"", format))?;
+        let searchable = match row.get("searchable") {
+            Some(serde_json::Value::Bool(true)) => Searchable::Searchable,
+            _ => Searchable::NotSearchable,
+        };
+        match str_property(&row, "label")? {
+            Some(label) => schema.add_key_label_format_searchable(&key, &label, format, searchable),
+            None => schema.add_key_format_searchable(&key, format, searchable),
+        };
+    }
+
+    for graph in array(json, "graphs")? {
+        let key = required(&graph, "key")?;
+        let graph_type = required(&graph, "type")?;
+        let graph_type = GraphType::from_json_name(&graph_type)
+            .ok_or_else(|| format!("unknown graph type \"{}\"", graph_type))?;
+        match str_property(&graph, "color")? {
+            Some(color) => {
+                let color = GraphColor::from_json_name(&color)
+                    .ok_or_else(|| format!("unknown graph color \"{}\"", color))?;
+                schema.add_graph_line_with_color(&key, graph_type, color)
+            }
+            None => schema.add_graph_line(&key, graph_type),
+        };
+    }
+    Ok(schema)
+}
+
+/// `schema += &other` merges `other` into `schema`, see `MarkerSchema::merge`.
+impl std::ops::AddAssign<&MarkerSchema> for MarkerSchema {
+    fn add_assign(&mut self, other: &MarkerSchema) {
+        self.merge(other);
+    }
+}
+
+/// `rows.into_iter().collect::<MarkerSchema>()` builds a schema without any
+/// location, see `MarkerSchema::from_rows_with_locations`.
+impl std::iter::FromIterator<SchemaRow> for MarkerSchema {
+    fn from_iter<I: IntoIterator<Item = SchemaRow>>(rows: I) -> Self {
+        MarkerSchema::from_rows_with_locations(&[], rows)
+    }
+}
+
+/// In-memory stand-in for the C++ SpliceableJSONWriter: the buffer that the
+/// marker payload JSON is streamed into.
+pub type SpliceableJSONWriter = String;
+
+/// The table of unique strings referenced by `unique_string_property`.
+/// Each distinct string is stored once, and streamed as its index in the table.
+#[derive(Debug, Default, Clone)]
+pub struct UniqueStrings {
+    strings: Vec<String>,
+}
+
+impl UniqueStrings {
+    pub const fn new() -> Self {
+        UniqueStrings { strings: vec![] }
+    }
+
+    /// Get the index of `value` in the table, adding it if it isn't there yet.
+    pub fn index_of(&mut self, value: &str) -> u32 {
+        match self.strings.iter().position(|s| s == value) {
+            Some(index) => index as u32,
+            None => {
+                self.strings.push(value.to_string());
+                (self.strings.len() - 1) as u32
+            }
+        }
+    }
+
+    /// Get the string stored at `index`, if any.
+    pub fn get(&self, index: u32) -> Option<&str> {
+        self.strings.get(index as usize).map(String::as_str)
+    }
+}
+
+/// Writes the JSON of a marker payload. Cloning a writer checkpoints it: the
+/// clone has its own copy of everything written so far, including the unique
+/// strings, and writes to either don't affect the other.
+#[derive(Clone)]
+pub struct JSONWriter {
+    buffer: SpliceableJSONWriter,
+    unique_strings: UniqueStrings,
+    needs_comma: bool,
+    /// The properties written so far, kept when the writer belongs to a
+    /// `RecordingWriter`.
+    recording: Option<Recording>,
+    /// The prefixes of the enclosing `property_group`s, concatenated.
+    prefix: String,
+    /// For each enclosing `property_group`, the number of open objects when it
+    /// started, and where its prefix starts in `prefix`.
+    prefix_groups: Vec<(usize, usize)>,
+    /// The number of objects started by `start_object_property` that haven't
+    /// been ended yet.
+    open_objects: usize,
+    /// The number of properties written so far, see `count_properties`.
+    properties: usize,
+    /// Whether `abort` was called in the current `transaction`.
+    aborted: bool,
+    /// The deepest nesting of objects and arrays written so far, see
+    /// `max_depth`.
+    max_depth: usize,
+    /// Whether commas are written between properties, see `auto_comma_mode`.
+    auto_comma: bool,
+}
+
+impl JSONWriter {
+    /// Constructor for the JSONWriter object. It starts a new JSON object that
+    /// the marker payload properties are added to.
+    pub fn new() -> Self {
+        JSONWriter {
+            buffer: String::from("{"),
+            unique_strings: UniqueStrings::new(),
+            needs_comma: false,
+            recording: None,
+            prefix: String::new(),
+            prefix_groups: vec![],
+            open_objects: 0,
+            properties: 0,
+            aborted: false,
+            max_depth: 1,
+            auto_comma: true,
+        }
+    }
+
+    /// Consume the writer, and return the JSON that has been written so far.
+    /// Note that the object started by `new` is not closed.
+    pub fn take_string(self) -> String {
+        self.buffer
+    }
+
+    /// Consume the writer, and return the complete JSON object: the object
+    /// started by `new` is closed, along with any objects started by
+    /// `start_object_property` that haven't been ended.
+    pub fn seal(mut self) -> String {
+        for _ in 0..=self.open_objects {
+            self.buffer.push('}');
+        }
+        self.buffer
+    }
+
+    /// The JSON that has been written so far, without consuming the writer.
+    /// Note that the object started by `new` is not closed.
+    pub fn as_str(&self) -> &str {
+        &self.buffer
+    }
+
+    /// The length in bytes of the JSON written so far, including the opening
+    /// brace of the object started by `new`.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The number of properties written so far, counting object properties
+    /// along with the properties written to them, and the properties of the
+    /// objects written by `repeat_property`.
+    pub fn count_properties(&self) -> usize {
+        self.properties
+    }
+
+    /// The deepest nesting of objects and arrays written so far, counting the
+    /// object started by `new`, so 1 for a payload without nested objects or
+    /// arrays. Unexpectedly deep payloads can point to a bug, like unbounded
+    /// recursion when streaming nested payloads.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Note that a value nested `depth` levels deep was written in the current
+    /// object, e.g. 1 for an array of strings.
+    fn reach_depth(&mut self, depth: usize) {
+        self.max_depth = self.max_depth.max(1 + self.open_objects + depth);
+    }
+
+    /// Whether nothing has been written since `new`, i.e. no properties.
+    pub fn is_empty(&self) -> bool {
+        self.buffer == "{"
+    }
+
+    /// The unique strings referenced by the `unique_string_property`s written so far.
+    pub fn unique_strings(&self) -> &UniqueStrings {
+        &self.unique_strings
+    }
+
+    /// Set whether a comma is written before each property that follows
+    /// another one, which is the default. When disabled, commas are only
+    /// written by `comma`, for JSON that's put together by hand. Commas between
+    /// the items of arrays are always written. The objects written by
+    /// `repeat_property` use the mode of the writer.
+    pub fn auto_comma_mode(&mut self, enabled: bool) -> &mut Self {
+        self.auto_comma = enabled;
+        self
+    }
+
+    /// Write a comma, for when `auto_comma_mode` is disabled.
+    /// Prints: ,
+    pub fn comma(&mut self) {
+        self.buffer.push(',');
+    }
+
+    /// Write the `"<name>":` prefix of a property, preceded by a comma if needed.
+    fn property_name(&mut self, name: &str) {
+        if self.needs_comma && self.auto_comma {
+            self.buffer.push(',');
+        }
+        self.needs_comma = true;
+        self.properties += 1;
+        let name = self.prefixed(name);
+        write_escaped_string(&mut self.buffer, &name);
+        self.buffer.push(':');
+    }
+
+    /// The name of a property, with the prefixes of the enclosing
+    /// `property_group`s that were started in the current object.
+    fn prefixed<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let start = self
+            .prefix_groups
+            .iter()
+            .rev()
+            .take_while(|(open_objects, _)| *open_objects == self.open_objects)
+            .last()
+            .map_or(self.prefix.len(), |(_, start)| *start);
+        if start == self.prefix.len() {
+            Cow::Borrowed(name)
+        } else {
+            Cow::Owned(format!("{}{}", &self.prefix[start..], name))
+        }
+    }
+
+    /// Call `f` to write a group of properties whose names all start with
+    /// `prefix`, to namespace them without nesting them in an object. Groups
+    /// can be nested, in which case their prefixes are concatenated.
+    /// E.g. writing "start" and "end" in the group "timing_" prints:
+    /// "timing_start": <value>, "timing_end": <value>
+    /// Only the properties of the current object are prefixed, not those of
+    /// the objects started in the group.
+    pub fn property_group<F>(&mut self, prefix: &str, f: F)
+    where
+        F: FnOnce(&mut JSONWriter),
+    {
+        let len = self.prefix.len();
+        self.prefix.push_str(prefix);
+        self.prefix_groups.push((self.open_objects, len));
+        f(self);
+        self.prefix_groups.pop();
+        self.prefix.truncate(len);
+    }
+
+    /// Call `f` to write properties that should be written either all or not
+    /// at all. If `f` returns `None`, or calls `abort`, everything it wrote,
+    /// including unique strings and changes to `auto_comma_mode`, is reverted,
+    /// and `None` is returned. Transactions can be nested, in which case
+    /// `abort` reverts the innermost one.
+    pub fn transaction<F, R>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut JSONWriter) -> Option<R>,
+    {
+        let len = self.buffer.len();
+        let (needs_comma, open_objects, properties, max_depth, auto_comma) = (
+            self.needs_comma,
+            self.open_objects,
+            self.properties,
+            self.max_depth,
+            self.auto_comma,
+        );
+        let unique_strings = self.unique_strings.strings.len();
+        let recording = self.recording.clone();
+        let outer_aborted = std::mem::replace(&mut self.aborted, false);
+
+        let result = f(self);
+        let aborted = std::mem::replace(&mut self.aborted, outer_aborted);
+        if aborted || result.is_none() {
+            self.buffer.truncate(len);
+            self.needs_comma = needs_comma;
+            self.open_objects = open_objects;
+            self.properties = properties;
+            self.max_depth = max_depth;
+            self.auto_comma = auto_comma;
+            self.unique_strings.strings.truncate(unique_strings);
+            self.recording = recording;
+            return None;
+        }
+        result
+    }
+
+    /// Abort the innermost `transaction` that's being written, so that its
+    /// writes are reverted when its closure returns. Outside of a transaction,
+    /// this does nothing.
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    /// Adds an int property to the JSON.
+    /// Prints: "<name>": <value>
+    pub fn int_property(&mut self, name: &str, value: i64) {
+        self.property_name(name);
+        self.buffer.push_str(&value.to_string());
+        self.record(name, || PropertyValue::Int(value));
+    }
+
+    /// Adds an unsigned int property to the JSON, e.g. for flow ids, which use
+    /// the full range of `u64`.
+    /// Prints: "<name>": <value>
+    pub fn unsigned_property(&mut self, name: &str, value: u64) {
+        self.property_name(name);
+        self.buffer.push_str(&value.to_string());
+        self.record(name, || PropertyValue::UInt(value));
+    }
+
+    /// Adds a float property to the JSON.
+    /// Prints: "<name>": <value>
+    /// The value is written with the fewest digits that parse back to the same
+    /// `f64`. JSON has no representation of NaN or infinities, so they are
+    /// written as `null`.
+    pub fn float_property(&mut self, name: &str, value: f64) {
+        self.property_name(name);
+        write_float(&mut self.buffer, value);
+        self.record(name, || PropertyValue::Float(value));
+    }
+
+    /// Adds a float property to the JSON, rounded to `digits` decimal places.
+    /// Prints: "<name>": <value>
+    /// As with `float_property`, NaN and infinities are written as `null`.
+    pub fn double_property_with_precision(&mut self, name: &str, value: f64, digits: usize) {
+        self.property_name(name);
+        if value.is_finite() {
+            self.buffer.push_str(&format!("{:.*}", digits, value));
+        } else {
+            self.buffer.push_str("null");
+        }
+        self.record(name, || PropertyValue::Float(value));
+    }
+
+    /// Adds an bool property to the JSON.
+    /// Prints: "<name>": <value>
+    pub fn bool_property(&mut self, name: &str, value: bool) {
+        self.property_name(name);
+        self.buffer.push_str(if value { "true" } else { "false" });
+        self.record(name, || PropertyValue::Bool(value));
+    }
+
+    /// Adds a string property to the JSON.
+    /// Prints: "<name>": "<value>"
+    pub fn string_property(&mut self, name: &str, value: &str) {
+        self.property_name(name);
+        write_escaped_string(&mut self.buffer, value);
+        self.record(name, || PropertyValue::Str(value.to_string()));
+    }
+
+    /// Adds an array of strings property to the JSON.
+    /// Prints: "<name>": ["<value>", "<value>", ..]
+    pub fn array_of_strings(&mut self, name: &str, values: &[&str]) {
+        self.property_name(name);
+        self.buffer.push('[');
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                self.buffer.push(',');
+            }
+            write_escaped_string(&mut self.buffer, value);
+        }
+        self.buffer.push(']');
+        self.reach_depth(1);
+        self.record(name, || {
+            PropertyValue::Array(
+                values
+                    .iter()
+                    .map(|value| PropertyValue::Str(value.to_string()))
+                    .collect(),
+            )
+        });
+    }
+
+    /// Adds a unique string property to the JSON.
+    /// Prints: "<name>": <string_table_index>
+    pub fn unique_string_property(&mut self, name: &str, value: &str) {
+        let index = self.unique_strings.index_of(value);
+        self.property_name(name);
+        self.buffer.push_str(&index.to_string());
+        self.record(name, || PropertyValue::UniqueStr(value.to_string()));
+    }
+
+    /// Starts an object property, whose properties are added to it until the
+    /// matching `end_object`.
+    /// Prints: "<name>": {
+    pub fn start_object_property(&mut self, name: &str) {
+        self.property_name(name);
+        let name = self.prefixed(name).into_owned();
+        self.buffer.push('{');
+        self.needs_comma = false;
+        self.open_objects += 1;
+        self.reach_depth(0);
+        if let Some(recording) = &mut self.recording {
+            recording.open_objects.push((name, vec![]));
+        }
+    }
+
+    /// Ends the object started by `start_object_property`.
+    /// Prints: }
+    pub fn end_object(&mut self) {
+        self.buffer.push('}');
+        self.needs_comma = true;
+        self.open_objects = self.open_objects.saturating_sub(1);
+        if let Some(recording) = &mut self.recording {
+            if let Some((name, properties)) = recording.open_objects.pop() {
+                recording.record(&name, PropertyValue::Object(properties));
+            }
+        }
+    }
+
+    /// Adds an array property of `count` objects, e.g. for the samples of a
+    /// marker. Each object's properties are written by calling `f` with a new
+    /// writer, and the object's index. The new writer shares this writer's
+    /// unique strings.
+    /// Prints: "<name>": [{..}, {..}, ..]
+    pub fn repeat_property<F>(&mut self, name: &str, count: usize, mut f: F)
+    where
+        F: FnMut(&mut JSONWriter, usize),
+    {
+        self.property_name(name);
+        self.buffer.push('[');
+        self.reach_depth(1);
+        let mut recorded_items = self.recording.as_ref().map(|_| vec![]);
+        for index in 0..count {
+            if index > 0 {
+                self.buffer.push(',');
+            }
+            let mut item = JSONWriter::new();
+            item.auto_comma = self.auto_comma;
+            item.unique_strings = std::mem::take(&mut self.unique_strings);
+            if recorded_items.is_some() {
+                item.recording = Some(Recording::default());
+            }
+            f(&mut item, index);
+            self.unique_strings = std::mem::take(&mut item.unique_strings);
+            self.properties += item.properties;
+            self.reach_depth(1 + item.max_depth);
+            self.buffer.push_str(&item.buffer);
+            self.buffer.push('}');
+            if let (Some(items), Some(recording)) = (&mut recorded_items, item.recording) {
+                items.push(PropertyValue::Object(recording.properties));
+            }
+        }
+        self.buffer.push(']');
+        self.record(name, || {
+            PropertyValue::Array(recorded_items.unwrap_or_default())
+        });
+    }
+
+    /// Adds a null property to the JSON.
+    /// Prints: "<name>": null
+    pub fn null_property(&mut self, name: &str) {
+        self.property_name(name);
+        self.buffer.push_str("null");
+        self.record(name, || PropertyValue::Null);
+    }
+
+    /// Adds a null property to the JSON if `condition` is false, e.g. for
+    /// optional data that's absent, and nothing otherwise, in which case the
+    /// caller writes the actual value.
+    /// Prints: "<name>": null
+    pub fn conditional_null_property(&mut self, name: &str, condition: bool) {
+        if !condition {
+            self.null_property(name);
+        }
+    }
+
+    /// Adds the property `name` for optional data: if `value` is `Some`, `f` is
+    /// called with it to write the property, and a null property is added
+    /// otherwise.
+    /// Prints: "<name>": <value written by f> or "<name>": null
+    pub fn property_or_null<T, F>(&mut self, name: &str, value: Option<T>, f: F)
+    where
+        F: FnOnce(&mut Self, T),
+    {
+        match value {
+            Some(value) => f(self, value),
+            None => self.null_property(name),
+        }
+    }
+
+    /// Adds a property with the serde JSON representation of `value`, for types
+    /// that have no dedicated method.
+    /// Prints: "<name>": <serialized value>
+    /// If `value` fails to serialize, null is written instead.
+    pub fn property_from_serialize<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) {
+        if self.serde_property(name, value).is_err() {
+            self.null_property(name);
+        }
+    }
+
+    /// Adds a property for each data row of the schema of `marker`, with the
+    /// value returned by `field_value_as_string()` for the row's key, as an
+    /// alternative to `stream_json_marker_data()` that's driven by the schema.
+    /// Values are written according to the row's format: numbers are written
+    /// as integers when they are one, and as null when they can't be parsed,
+    /// e.g. the empty string of a `None` field. Lists are written as the JSON
+    /// they're formatted as.
+    pub fn kv_from_schema<M: ProfilerMarker>(&mut self, marker: &M) {
+        for row in M::marker_type_display().rows() {
+            let SchemaRow::Dynamic { key, format, .. } = row else {
+                continue;
+            };
+            let Some(value) = marker.field_value_as_string(key) else {
+                continue;
+            };
+            match format {
+                Format::UniqueString => self.unique_string_property(key, &value),
+                format if format.is_string() => self.string_property(key, &value),
+                Format::List => match serde_json::from_str::<serde_json::Value>(&value) {
+                    Ok(list) => self.property_from_serialize(key, &list),
+                    Err(_) => self.null_property(key),
+                },
+                _ => {
+                    if let Ok(value) = value.parse::<i64>() {
+                        self.int_property(key, value);
+                    } else if let Ok(value) = value.parse::<u64>() {
+                        self.unsigned_property(key, value);
+                    } else if let Ok(value) = value.parse::<f64>() {
+                        self.float_property(key, value);
+                    } else {
+                        self.null_property(key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds a property with the serde JSON representation of `value`, which is
+    /// serialized straight into the JSON, without an intermediate
+    /// `serde_json::Value`.
+    /// Prints: "<name>": <serialized value>
+    /// If `value` can't be serialized as JSON, e.g. because it's a map with keys
+    /// that aren't strings or numbers, nothing is written and the error is
+    /// returned.
+    pub fn serde_property<T: Serialize + ?Sized>(
+        &mut self,
+        name: &str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        let (len, needs_comma) = (self.buffer.len(), self.needs_comma);
+        self.property_name(name);
+        let value_start = self.buffer.len();
+        let result = value.serialize(ValueSerializer {
+            buffer: &mut self.buffer,
+        });
+        if result.is_err() {
+            self.buffer.truncate(len);
+            self.needs_comma = needs_comma;
+            self.properties -= 1;
+            return result;
+        }
+        self.reach_depth(json_depth(&self.buffer[value_start..]));
+        if self.recording.is_some() {
+            let value = serde_json::from_str::<serde_json::Value>(&self.buffer[value_start..])
+                .map_or(PropertyValue::Null, PropertyValue::from);
+            self.record(name, || value);
+        }
+        result
+    }
+
+    /// Adds an object property with the serde JSON representation of `value`,
+    /// for nested payloads, e.g. structs or maps.
+    /// Prints: "<name>": {<serialized fields>}
+    /// If `value` fails to serialize, or doesn't serialize to a JSON object, an
+    /// empty object is written instead, so the property is always an object.
+    pub fn object_from_serialize<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) {
+        self.property_name(name);
+        let value_start = self.buffer.len();
+        let result = value.serialize(ValueSerializer {
+            buffer: &mut self.buffer,
+        });
+        if result.is_err() || !self.buffer[value_start..].starts_with('{') {
+            self.buffer.truncate(value_start);
+            self.buffer.push_str("{}");
+        }
+        self.reach_depth(json_depth(&self.buffer[value_start..]));
+        if self.recording.is_some() {
+            let value = serde_json::from_str::<serde_json::Value>(&self.buffer[value_start..])
+                .map_or(PropertyValue::Object(vec![]), PropertyValue::from);
+            self.record(name, || value);
+        }
+    }
+
+    /// Call `f` with the writer, and return its result along with the number of
+    /// bytes of JSON it wrote, including commas between properties.
+    pub fn measure<F, R>(&mut self, f: F) -> (R, usize)
+    where
+        F: FnOnce(&mut Self) -> R,
+    {
+        let len = self.buffer.len();
+        let result = f(self);
+        (result, self.buffer.len().saturating_sub(len))
+    }
+
+    /// Record the property `name`, if the writer is recording.
+    fn record(&mut self, name: &str, value: impl FnOnce() -> PropertyValue) {
+        let name = self.prefixed(name);
+        if let Some(recording) = &mut self.recording {
+            recording.record(&name, value());
+        }
+    }
+}
+
+impl Default for JSONWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Writers are compared and hashed by the JSON written so far, so that
+// identical payloads can be deduplicated. Note that unique strings are
+// compared by their index, not by the string they refer to.
+impl PartialEq for JSONWriter {
+    fn eq(&self, other: &Self) -> bool {
+        self.buffer == other.buffer
+    }
+}
+
+impl Eq for JSONWriter {}
+
+impl Hash for JSONWriter {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.buffer.hash(state);
+    }
+}
+
+/// Writers are displayed as the JSON written so far, without closing the
+/// object started by `new`.
+impl std::fmt::Display for JSONWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.buffer)
+    }
+}
+
+// The address of the writer, to tell writers apart in debugging output, e.g.
+// when several threads stream markers at once.
+impl std::fmt::Pointer for JSONWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Pointer::fmt(&(self as *const Self), f)
+    }
+}
+
+// Only the JSON written so far is shown, as that's what's useful when
+// debugging how a marker is streamed.
+impl std::fmt::Debug for JSONWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JSONWriter")
+            .field("buffer", &self.as_str())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The properties recorded by a `JSONWriter` that belongs to a `RecordingWriter`.
+#[derive(Debug, Default, Clone)]
+struct Recording {
+    properties: Vec<(String, PropertyValue)>,
+    /// The objects started by `start_object_property` that haven't been ended
+    /// yet, innermost last, with the properties written to them so far.
+    open_objects: Vec<(String, Vec<(String, PropertyValue)>)>,
+}
+
+impl Recording {
+    fn record(&mut self, name: &str, value: PropertyValue) {
+        let properties = match self.open_objects.last_mut() {
+            Some((_, properties)) => properties,
+            None => &mut self.properties,
+        };
+        properties.push((name.to_string(), value));
+    }
+}
+
+/// A property value recorded by a `RecordingWriter`, as passed to the
+/// `JSONWriter` method that wrote it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    Int(i64),
+    /// From `unsigned_property`.
+    UInt(u64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    /// The string itself, rather than its index in the unique string table.
+    UniqueStr(String),
+    Null,
+    /// From `start_object_property`, or a value written with serde. The
+    /// properties of serde values are ordered by name.
+    Object(Vec<(String, PropertyValue)>),
+    Array(Vec<PropertyValue>),
+}
+
+impl PropertyValue {
+    /// The property `name` of an object value, or `None` if this isn't an
+    /// object or it has no such property.
+    pub fn get(&self, name: &str) -> Option<&PropertyValue> {
+        match self {
+            PropertyValue::Object(properties) => find_property(properties, name),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Value> for PropertyValue {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => PropertyValue::Null,
+            serde_json::Value::Bool(value) => PropertyValue::Bool(value),
+            serde_json::Value::Number(number) => match (number.as_i64(), number.as_u64()) {
+                (Some(value), _) => PropertyValue::Int(value),
+                (None, Some(value)) => PropertyValue::UInt(value),
+                (None, None) => PropertyValue::Float(number.as_f64().unwrap_or(f64::NAN)),
+            },
+            serde_json::Value::String(value) => PropertyValue::Str(value),
+            serde_json::Value::Array(values) => {
+                PropertyValue::Array(values.into_iter().map(PropertyValue::from).collect())
+            }
+            serde_json::Value::Object(properties) => PropertyValue::Object(
+                properties
+                    .into_iter()
+                    .map(|(name, value)| (name, PropertyValue::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<PropertyValue> for serde_json::Value {
+    /// Unique strings are converted to the string itself. Non-finite floats,
+    /// which JSON can't represent, are converted to null.
+    fn from(value: PropertyValue) -> Self {
+        match value {
+            PropertyValue::Int(value) => value.into(),
+            PropertyValue::UInt(value) => value.into(),
+            PropertyValue::Float(value) => value.into(),
+            PropertyValue::Bool(value) => value.into(),
+            PropertyValue::Str(value) | PropertyValue::UniqueStr(value) => value.into(),
+            PropertyValue::Null => serde_json::Value::Null,
+            PropertyValue::Object(properties) => serde_json::Value::Object(
+                properties
+                    .into_iter()
+                    .map(|(name, value)| (name, value.into()))
+                    .collect(),
+            ),
+            PropertyValue::Array(values) => {
+                serde_json::Value::Array(values.into_iter().map(Into::into).collect())
+            }
+        }
+    }
+}
+
+fn find_property<'a>(
+    properties: &'a [(String, PropertyValue)],
+    name: &str,
+) -> Option<&'a PropertyValue> {
+    properties
+        .iter()
+        .find(|(property, _)| property == name)
+        .map(|(_, value)| value)
+}
+
+/// A `JSONWriter` that also records each property written to it, so that tests
+/// can check what a `stream_json_marker_data()` implementation wrote without
+/// parsing the JSON. It derefs to the `JSONWriter` it records, so it can be
+/// passed wherever one is expected, e.g.
+/// `marker.stream_json_marker_data(&mut recorder)`, followed by checks like
+/// `recorder.assert_int("field1", 42)`.
+#[derive(Debug)]
+pub struct RecordingWriter {
+    writer: JSONWriter,
+}
+
+impl RecordingWriter {
+    pub fn new() -> Self {
+        RecordingWriter {
+            writer: JSONWriter {
+                recording: Some(Recording::default()),
+                ..JSONWriter::new()
+            },
+        }
+    }
+
+    /// Record the payload streamed by `marker`.
+    pub fn record<T: ProfilerMarker>(marker: &T) -> Self {
+        let mut recorder = RecordingWriter::new();
+        marker.stream_json_marker_data(&mut recorder);
+        recorder
+    }
+
+    /// The properties written so far, in order. Properties of objects that
+    /// haven't been ended yet aren't included.
+    pub fn properties(&self) -> &[(String, PropertyValue)] {
+        match &self.writer.recording {
+            Some(recording) => &recording.properties,
+            None => &[],
+        }
+    }
+
+    /// The value of the property `name`, if it has been written.
+    pub fn get(&self, name: &str) -> Option<&PropertyValue> {
+        find_property(self.properties(), name)
+    }
+
+    /// Consume the recorder, and return the `JSONWriter` it recorded.
+    pub fn into_writer(self) -> JSONWriter {
+        self.writer
+    }
+
+    #[track_caller]
+    fn assert_property(&self, name: &str, expected: PropertyValue) {
+        assert_eq!(
+            self.get(name),
+            Some(&expected),
+            "unexpected value for property {:?}",
+            name
+        );
+    }
+
+    /// Assert that the property `name` was written by `int_property` with `value`.
+    #[track_caller]
+    pub fn assert_int(&self, name: &str, value: i64) {
+        self.assert_property(name, PropertyValue::Int(value));
+    }
+
+    /// Assert that the property `name` was written as the float `value`.
+    #[track_caller]
+    pub fn assert_float(&self, name: &str, value: f64) {
+        self.assert_property(name, PropertyValue::Float(value));
+    }
+
+    /// Assert that the property `name` was written by `bool_property` with `value`.
+    #[track_caller]
+    pub fn assert_bool(&self, name: &str, value: bool) {
+        self.assert_property(name, PropertyValue::Bool(value));
+    }
+
+    /// Assert that the property `name` was written by `string_property` with `value`.
+    #[track_caller]
+    pub fn assert_str(&self, name: &str, value: &str) {
+        self.assert_property(name, PropertyValue::Str(value.to_string()));
+    }
+
+    /// Assert that the property `name` was written by `unique_string_property`
+    /// with `value`.
+    #[track_caller]
+    pub fn assert_unique_str(&self, name: &str, value: &str) {
+        self.assert_property(name, PropertyValue::UniqueStr(value.to_string()));
+    }
+
+    /// Assert that the property `name` was written as null.
+    #[track_caller]
+    pub fn assert_null(&self, name: &str) {
+        self.assert_property(name, PropertyValue::Null);
+    }
+}
+
+impl Default for RecordingWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::ops::Deref for RecordingWriter {
+    type Target = JSONWriter;
+
+    fn deref(&self) -> &JSONWriter {
+        &self.writer
+    }
+}
+
+impl std::ops::DerefMut for RecordingWriter {
+    fn deref_mut(&mut self) -> &mut JSONWriter {
+        &mut self.writer
+    }
+}
+
+/// Write `value` to `buffer` as a JSON number, using the shortest representation
+/// that round-trips. Like JavaScript's `Number.prototype.toString`, exponent
+/// notation is only used for very large or very small magnitudes. Non-finite
+/// values are written as `null`.
+fn write_float(buffer: &mut String, value: f64) {
+    if !value.is_finite() {
+        buffer.push_str("null");
+    } else if value != 0.0 && (value.abs() >= 1e21 || value.abs() < 1e-6) {
+        buffer.push_str(&format!("{:e}", value));
+    } else {
+        buffer.push_str(&value.to_string());
+    }
+}
+
+/// Write `value` to `buffer` as a quoted JSON string, escaping as necessary.
+fn write_escaped_string(buffer: &mut String, value: &str) {
+    buffer.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => buffer.push_str("\\\""),
+            '\\' => buffer.push_str("\\\\"),
+            '\n' => buffer.push_str("\\n"),
+            '\r' => buffer.push_str("\\r"),
+            '\t' => buffer.push_str("\\t"),
+            c if (c as u32) < 0x20 => buffer.push_str(&format!("\\u{:04x}", c as u32)),
+            c => buffer.push(c),
+        }
+    }
+    buffer.push('"');
+}
+
+/// The deepest nesting of objects and arrays in the JSON value `json`, e.g. 0
+/// for a number, and 2 for `[{}]`.
+fn json_depth(json: &str) -> usize {
+    let (mut depth, mut max_depth) = (0usize, 0);
+    let (mut in_string, mut escaped) = (false, false);
+    for c in json.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '[' | '{' if !in_string => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            ']' | '}' if !in_string => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max_depth
+}
+
+/// An error serializing a value with `JSONWriter::serde_property`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerializeError(String);
+
+impl std::fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "can't serialize value as JSON: {}", self.0)
+    }
+}
+
+impl std::error::Error for SerializeError {}
+
+impl serde::ser::Error for SerializeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        SerializeError(msg.to_string())
+    }
+}
+
+/// A `serde::Serializer` that writes a value as JSON to the buffer of a
+/// `JSONWriter`, in the same representation as `serde_json`.
+struct ValueSerializer<'a> {
+    buffer: &'a mut String,
+}
+
+impl<'a> ValueSerializer<'a> {
+    /// Start a `{"<variant>":` wrapper, which `close` must end.
+    fn variant(self, variant: &str, open: char, close: &'static str) -> Compound<'a> {
+        self.buffer.push('{');
+        write_escaped_string(self.buffer, variant);
+        self.buffer.push(':');
+        self.buffer.push(open);
+        Compound {
+            buffer: self.buffer,
+            first: true,
+            close,
+        }
+    }
+
+    fn compound(self, open: char, close: &'static str) -> Compound<'a> {
+        self.buffer.push(open);
+        Compound {
+            buffer: self.buffer,
+            first: true,
+            close,
+        }
+    }
+}
+
+impl<'a> serde::Serializer for ValueSerializer<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), SerializeError> {
+        self.buffer.push_str(if v { "true" } else { "false" });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), SerializeError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), SerializeError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), SerializeError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), SerializeError> {
+        self.buffer.push_str(&v.to_string());
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), SerializeError> {
+        self.buffer.push_str(&v.to_string());
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), SerializeError> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), SerializeError> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), SerializeError> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), SerializeError> {
+        self.buffer.push_str(&v.to_string());
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), SerializeError> {
+        self.buffer.push_str(&v.to_string());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), SerializeError> {
+        // Written with the `f32` digits, rather than those of `v as f64`.
+        if v.is_finite() {
+            self.buffer.push_str(&v.to_string());
+        } else {
+            self.buffer.push_str("null");
+        }
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), SerializeError> {
+        write_float(self.buffer, v);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), SerializeError> {
+        write_escaped_string(self.buffer, v.encode_utf8(&mut [0; 4]));
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), SerializeError> {
+        write_escaped_string(self.buffer, v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), SerializeError> {
+        use serde::ser::SerializeSeq;
+        let mut seq = self.serialize_seq(Some(v.len()))?;
+        for byte in v {
+            seq.serialize_element(byte)?;
+        }
+        seq.end()
+    }
+
+    fn serialize_none(self) -> Result<(), SerializeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), SerializeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), SerializeError> {
+        self.buffer.push_str("null");
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), SerializeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), SerializeError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.buffer.push('{');
+        write_escaped_string(self.buffer, variant);
+        self.buffer.push(':');
+        value.serialize(ValueSerializer {
+            buffer: &mut *self.buffer,
+        })?;
+        self.buffer.push('}');
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.compound('[', "]"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.compound('[', "]"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.compound('[', "]"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.variant(variant, '[', "]}"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.compound('{', "}"))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.compound('{', "}"))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.variant(variant, '{', "}}"))
+    }
+}
+
+/// The state of a JSON array or object being written by `ValueSerializer`.
+struct Compound<'a> {
+    buffer: &'a mut String,
+    first: bool,
+    /// What to write once all the elements have been written.
+    close: &'static str,
+}
+
+impl Compound<'_> {
+    fn comma(&mut self) {
+        if !self.first {
+            self.buffer.push(',');
+        }
+        self.first = false;
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        self.comma();
+        value.serialize(ValueSerializer {
+            buffer: &mut *self.buffer,
+        })
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), SerializeError> {
+        self.comma();
+        write_escaped_string(self.buffer, key);
+        self.buffer.push(':');
+        value.serialize(ValueSerializer {
+            buffer: &mut *self.buffer,
+        })
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        self.buffer.push_str(self.close);
+        Ok(())
+    }
+}
+
+impl serde::ser::SerializeSeq for Compound<'_> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Compound::end(self)
+    }
+}
+
+impl serde::ser::SerializeTuple for Compound<'_> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Compound::end(self)
+    }
+}
+
+impl serde::ser::SerializeTupleStruct for Compound<'_> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Compound::end(self)
+    }
+}
+
+impl serde::ser::SerializeTupleVariant for Compound<'_> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Compound::end(self)
+    }
+}
+
+impl serde::ser::SerializeMap for Compound<'_> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerializeError> {
+        // JSON object keys are strings, so numeric keys are quoted, as
+        // `serde_json` does.
+        let mut json = String::new();
+        key.serialize(ValueSerializer { buffer: &mut json })?;
+        self.comma();
+        match json.chars().next() {
+            Some('"') => self.buffer.push_str(&json),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                self.buffer.push('"');
+                self.buffer.push_str(&json);
+                self.buffer.push('"');
+            }
+            _ => {
+                return Err(SerializeError(format!(
+                    "map keys must be strings or numbers, found {}",
+                    json
+                )))
+            }
+        }
+        self.buffer.push(':');
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        value.serialize(ValueSerializer {
+            buffer: &mut *self.buffer,
+        })
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Compound::end(self)
+    }
+}
+
+impl serde::ser::SerializeStruct for Compound<'_> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Compound::end(self)
+    }
+}
+
+impl serde::ser::SerializeStructVariant for Compound<'_> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Compound::end(self)
+    }
+}
+
+/// An error reconstructing a marker payload from its JSON representation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarkerError {
+    /// The payload is not a JSON object.
+    NotAnObject,
+    /// The payload has no property with the given key.
+    MissingProperty(String),
+    /// The property with the given key can't be converted to the field type.
+    TypeMismatch { key: String, message: String },
+    /// The property with the given key refers to a string that isn't in the
+    /// unique string table.
+    UnknownUniqueString { key: String, index: u64 },
+}
+
+impl std::fmt::Display for MarkerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MarkerError::NotAnObject => write!(f, "marker payload is not a JSON object"),
+            MarkerError::MissingProperty(key) => {
+                write!(f, "marker payload has no property \"{}\"", key)
+            }
+            MarkerError::TypeMismatch { key, message } => {
+                write!(
+                    f,
+                    "marker payload property \"{}\" is invalid: {}",
+                    key, message
+                )
+            }
+            MarkerError::UnknownUniqueString { key, index } => write!(
+                f,
+                "marker payload property \"{}\" refers to unknown unique string {}",
+                key, index
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MarkerError {}
+
+/// Reads marker payload properties back out of the JSON object streamed by a
+/// `JSONWriter`. This is the counterpart of `JSONWriter`, used by
+/// `from_json_marker_data()`.
+pub struct JSONReader<'a> {
+    object: &'a serde_json::Map<String, serde_json::Value>,
+    unique_strings: &'a UniqueStrings,
+}
+
+impl<'a> JSONReader<'a> {
+    /// Create a reader for the payload `json`, which must be an object. Unique
+    /// string properties are resolved through `unique_strings`.
+    pub fn new(
+        json: &'a serde_json::Value,
+        unique_strings: &'a UniqueStrings,
+    ) -> Result<Self, MarkerError> {
+        match json {
+            serde_json::Value::Object(object) => Ok(JSONReader {
+                object,
+                unique_strings,
+            }),
+            _ => Err(MarkerError::NotAnObject),
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<&'a serde_json::Value, MarkerError> {
+        self.object
+            .get(key)
+            .ok_or_else(|| MarkerError::MissingProperty(key.to_string()))
+    }
+
+    /// Whether the property was written by `null_property`.
+    pub fn is_null(&self, key: &str) -> Result<bool, MarkerError> {
+        Ok(self.get(key)?.is_null())
+    }
+
+    /// Reads a property written by `int_property`, `float_property` or `bool_property`.
+    pub fn property<T: DeserializeOwned>(&self, key: &str) -> Result<T, MarkerError> {
+        T::deserialize(self.get(key)?).map_err(|e| type_mismatch(key, e))
+    }
+
+    /// Reads a property written by `float_property` with the `process_time()`
+    /// of an `Instant`.
+    pub fn instant_property(&self, key: &str) -> Result<Instant, MarkerError> {
+        let milliseconds = self.property::<f64>(key)?;
+        instant_from_process_time(milliseconds).ok_or_else(|| MarkerError::TypeMismatch {
+            key: key.to_string(),
+            message: format!("{} is out of the range of `Instant`", milliseconds),
+        })
+    }
+
+    /// Reads a property written by `string_property`.
+    pub fn string_property<T: DeserializeOwned>(&self, key: &str) -> Result<T, MarkerError> {
+        match self.get(key)? {
+            serde_json::Value::String(value) => from_string(key, value),
+            value => Err(MarkerError::TypeMismatch {
+                key: key.to_string(),
+                message: format!("expected a string, found {}", value),
+            }),
+        }
+    }
+
+    /// Reads a property written by `unique_string_property`, resolving the
+    /// string table index.
+    pub fn unique_string_property<T: DeserializeOwned>(&self, key: &str) -> Result<T, MarkerError> {
+        let value = self.get(key)?;
+        let index = value.as_u64().ok_or_else(|| MarkerError::TypeMismatch {
+            key: key.to_string(),
+            message: format!("expected a unique string index, found {}", value),
+        })?;
+        let string = u32::try_from(index)
+            .ok()
+            .and_then(|index| self.unique_strings.get(index))
+            .ok_or_else(|| MarkerError::UnknownUniqueString {
+                key: key.to_string(),
+                index,
+            })?;
+        from_string(key, string)
+    }
+}
+
+fn type_mismatch(key: &str, error: serde_json::Error) -> MarkerError {
+    MarkerError::TypeMismatch {
+        key: key.to_string(),
+        message: error.to_string(),
+    }
+}
+
+/// Convert a streamed string back to the field type. Non-string fields are
+/// streamed with their `Display` output, so if the string itself doesn't
+/// deserialize as `T`, try the JSON value it contains (e.g. `"42"` for a `u32`).
+fn from_string<T: DeserializeOwned>(key: &str, value: &str) -> Result<T, MarkerError> {
+    T::deserialize(serde_json::Value::String(value.to_string()))
+        .or_else(|e| serde_json::from_str(value).map_err(|_| type_mismatch(key, e)))
+}
+
+/// Return the value of `key` in a string map that a marker is parsed from by
+/// the `TryFrom<&HashMap<String, String>>` implementation of the derive.
+#[doc(hidden)]
+pub fn required_map_value<'a>(
+    map: &'a HashMap<String, String>,
+    key: &str,
+) -> Result<&'a str, String> {
+    map.get(key)
+        .map(String::as_str)
+        .ok_or_else(|| format!("missing field \"{}\"", key))
+}
+
+/// Parse the value of `key` in a string map with `str::parse`, for fields of
+/// primitive and `String` types.
+#[doc(hidden)]
+pub fn parse_map_value<T>(key: &str, value: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("invalid value {:?} for field \"{}\": {}", value, key, e))
+}
+
+/// Parse the value of `key` in a string map for `Instant` fields with
+/// `#[format(Time)]`, from their `process_time()`.
+#[doc(hidden)]
+pub fn parse_map_instant(key: &str, value: &str) -> Result<Instant, String> {
+    instant_from_process_time(parse_map_value(key, value)?).ok_or_else(|| {
+        format!(
+            "invalid value {:?} for field \"{}\": out of the range of `Instant`",
+            value, key
+        )
+    })
+}
+
+/// Parse the value of `key` in a string map for fields of other types, like
+/// `JSONReader::string_property()`: as a string, or as the JSON it contains.
+#[doc(hidden)]
+pub fn deserialize_map_value<T: DeserializeOwned>(key: &str, value: &str) -> Result<T, String> {
+    from_string(key, value).map_err(|e| match e {
+        MarkerError::TypeMismatch { message, .. } => format!(
+            "invalid value {:?} for field \"{}\": {}",
+            value, key, message
+        ),
+        e => e.to_string(),
+    })
+}
+
+/// A copy of `marker`, with the data row `key` set to `value`, for the
+/// `with_field_override()` method of the derive. The marker is streamed, and
+/// read back with `value` in place of the streamed value, so that `value` is
+/// converted like the rest of the payload. Unique strings can be given as the
+/// string itself.
+#[doc(hidden)]
+pub fn override_marker_field<T: ProfilerMarker + 'static>(
+    marker: &T,
+    key: &str,
+    value: serde_json::Value,
+) -> Result<T, String> {
+    let format = T::marker_type_display_ref()
+        .rows()
+        .iter()
+        .find_map(|row| match row {
+            SchemaRow::Dynamic { key: k, format, .. } if k == key => Some(*format),
+            _ => None,
+        })
+        .ok_or_else(|| format!("unknown field \"{}\"", key))?;
+
+    let mut json_writer = JSONWriter::new();
+    marker.stream_json_marker_data(&mut json_writer);
+    let mut unique_strings = json_writer.unique_strings().clone();
+    let mut json: serde_json::Value =
+        serde_json::from_str(&json_writer.seal()).map_err(|e| e.to_string())?;
+    json[key] = match (format, value) {
+        (Format::UniqueString, serde_json::Value::String(value)) => {
+            unique_strings.index_of(&value).into()
+        }
+        (_, value) => value,
+    };
+    T::from_json_marker_data(&json, &unique_strings).map_err(|e| match e {
+        MarkerError::TypeMismatch { message, .. } => format!(
+            "invalid value {} for field \"{}\": {}",
+            json[key], key, message
+        ),
+        e => e.to_string(),
+    })
+}
+
+/// Integers that the fields of `#[marker_scalable]` markers can have, scaled
+/// by the `Mul<f64>` implementation of the derive.
+#[doc(hidden)]
+pub trait ScalableInteger: Sized {
+    /// Multiply by `rhs`, saturating at the bounds of the type. Whole factors
+    /// are applied with integer arithmetic, so that values above 2^53, which
+    /// `f64` can't represent exactly, keep their precision. Other factors go
+    /// through `f64`.
+    fn scale(self, rhs: f64) -> Self;
+}
+
+macro_rules! impl_scalable_integer {
+    ($($ty:ty),*) => {
+        $(
+            impl ScalableInteger for $ty {
+                fn scale(self, rhs: f64) -> Self {
+                    let whole_factor = rhs.fract() == 0.0 && rhs.abs() < i128::MAX as f64;
+                    match i128::try_from(self) {
+                        Ok(value) if whole_factor => {
+                            let product = value.saturating_mul(rhs as i128);
+                            <$ty>::try_from(product).unwrap_or(if product < 0 {
+                                <$ty>::MIN
+                            } else {
+                                <$ty>::MAX
+                            })
+                        }
+                        _ => (self as f64 * rhs) as $ty,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_scalable_integer!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+/// The fields of a marker payload. This is implemented by
+/// `#[derive(ProfilerMarker)]`, and lets a marker payload be embedded in other
+/// marker payloads with `#[flatten]`: its schema rows and properties are then
+/// inlined into the outer marker's, rather than nested in an object.
+pub trait MarkerFields: Sized {
+    /// The keys of all the properties streamed by `stream_fields()`.
+    const KEYS: &'static [&'static str];
+    /// Add the rows (and graphs) describing the fields to `schema`.
+    fn add_schema_rows(schema: &mut MarkerSchema);
+    /// Stream the fields as JSON object properties.
+    fn stream_fields(&self, json_writer: &mut JSONWriter);
+    /// Read the fields back from the properties streamed by `stream_fields()`.
+    fn read_fields(reader: &JSONReader) -> Result<Self, MarkerError>;
+}
+
+/// A type that knows how to stream itself as a marker field. Fields of
+/// `#[derive(ProfilerMarker)]` structs that have no `#[format(..)]` attribute,
+/// and aren't a primitive, string or map type, are described and streamed
+/// through this trait, so that e.g. a `struct Pid(u32)` newtype only needs
+/// to be taught how to stream once, rather than at every marker using it.
+///
+/// The field is still read back with its `Deserialize` implementation, so
+/// `write` must stream a value that this deserializes from.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't be used as a marker field without a `#[format(..)]` attribute",
+    label = "`{Self}` doesn't implement `MarkerFieldValue`",
+    note = "implement `fx_markers::MarkerFieldValue` for `{Self}`, or add a `#[format(..)]` \
+            or `#[serde_property]` attribute to the field"
+)]
+pub trait MarkerFieldValue {
+    /// The format of the field in the marker schema.
+    const FORMAT: Format;
+    /// Stream the value as the property `key`.
+    fn write(&self, key: &str, json_writer: &mut JSONWriter);
+}
+
+macro_rules! impl_marker_field_value {
+    ($format:ident, $method:ident as $as:ty: $($ty:ty),*) => {
+        $(
+            impl MarkerFieldValue for $ty {
+                const FORMAT: Format = Format::$format;
+                fn write(&self, key: &str, json_writer: &mut JSONWriter) {
+                    json_writer.$method(key, *self as $as);
+                }
+            }
+        )*
+    };
+}
+
+impl_marker_field_value!(Integer, int_property as i64: i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+impl_marker_field_value!(Decimal, float_property as f64: f32, f64);
+
+impl MarkerFieldValue for bool {
+    const FORMAT: Format = Format::String;
+    fn write(&self, key: &str, json_writer: &mut JSONWriter) {
+        json_writer.string_property(key, if *self { "true" } else { "false" });
+    }
+}
+
+impl MarkerFieldValue for char {
+    const FORMAT: Format = Format::String;
+    fn write(&self, key: &str, json_writer: &mut JSONWriter) {
+        json_writer.string_property(key, self.encode_utf8(&mut [0; 4]));
+    }
+}
+
+impl MarkerFieldValue for str {
+    const FORMAT: Format = Format::String;
+    fn write(&self, key: &str, json_writer: &mut JSONWriter) {
+        json_writer.string_property(key, self);
+    }
+}
+
+impl MarkerFieldValue for String {
+    const FORMAT: Format = Format::String;
+    fn write(&self, key: &str, json_writer: &mut JSONWriter) {
+        json_writer.string_property(key, self);
+    }
+}
+
+/// Panic if `format` is streamed as a string or a list, and so can't be drawn
+/// as a graph.
+/// The derive evaluates this at compile time for `#[graph(..)]` fields whose
+/// format comes from `MarkerFieldValue::FORMAT`.
+#[doc(hidden)]
+pub const fn assert_graph_format(format: Format) {
+    if matches!(
+        format,
+        Format::Url
+            | Format::FilePath
+            | Format::SanitizedString
+            | Format::String
+            | Format::UniqueString
+            | Format::List
+    ) {
+        panic!("'graph' can only be used on fields with a numeric format");
+    }
+}
+
+/// Panic if `format` isn't `Integer`, as only integers can be flow IDs.
+/// The derive evaluates this at compile time for `#[format(Flow)]` and
+/// `#[format(TerminatingFlow)]` fields whose type may be an alias.
+#[doc(hidden)]
+pub const fn assert_flow_format(format: Format) {
+    if !matches!(format, Format::Integer) {
+        panic!("The flow formats can only be used on integer fields, e.g. `u64`");
+    }
+}
+
+/// The total length of `parts`, the size of the array returned by
+/// `concat_str_bytes`.
+#[doc(hidden)]
+pub const fn concat_str_len(parts: &[&str]) -> usize {
+    let mut len = 0;
+    let mut i = 0;
+    while i < parts.len() {
+        len += parts[i].len();
+        i += 1;
+    }
+    len
+}
+
+/// Concatenate the bytes of `parts`, used to build the `SCHEMA_JSON` of
+/// derived markers at compile time. `N` must be `concat_str_len(parts)`.
+#[doc(hidden)]
+pub const fn concat_str_bytes<const N: usize>(parts: &[&str]) -> [u8; N] {
+    let mut bytes = [0u8; N];
+    let mut n = 0;
+    let mut i = 0;
+    while i < parts.len() {
+        let part = parts[i].as_bytes();
+        let mut j = 0;
+        while j < part.len() {
+            bytes[n] = part[j];
+            n += 1;
+            j += 1;
+        }
+        i += 1;
+    }
+    bytes
+}
+
+/// Concatenate groups of marker keys, used to build `MarkerFields::KEYS` at
+/// compile time. `N` must be the total number of keys.
+#[doc(hidden)]
+pub const fn concat_marker_keys<const N: usize>(groups: &[&[&'static str]]) -> [&'static str; N] {
+    let mut keys = [""; N];
+    let mut n = 0;
+    let mut i = 0;
+    while i < groups.len() {
+        let mut j = 0;
+        while j < groups[i].len() {
+            keys[n] = groups[i][j];
+            n += 1;
+            j += 1;
+        }
+        i += 1;
+    }
+    keys
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Panic if `keys` contains the same key twice. The derive evaluates this at
+/// compile time, so that key collisions (e.g. between a field and the fields
+/// of a flattened marker) fail the build.
+#[doc(hidden)]
+pub const fn assert_unique_marker_keys(keys: &[&str]) {
+    let mut i = 0;
+    while i < keys.len() {
+        let mut j = i + 1;
+        while j < keys.len() {
+            if str_eq(keys[i], keys[j]) {
+                panic_duplicate_marker_key(keys[i]);
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+/// Const panics can only format a single `&str`, so assemble the message by hand.
+const fn panic_duplicate_marker_key(key: &str) -> ! {
+    const PREFIX: &[u8] = b"duplicate marker key: ";
+    let mut message = [0u8; 256];
+    let mut len = 0;
+    while len < PREFIX.len() {
+        message[len] = PREFIX[len];
+        len += 1;
+    }
+    let key = key.as_bytes();
+    let mut i = 0;
+    while i < key.len() && len < message.len() {
+        message[len] = key[i];
+        len += 1;
+        i += 1;
+    }
+    match std::str::from_utf8(message.split_at(len).0) {
+        Ok(message) => panic!("{}", message),
+        Err(_) => panic!("duplicate marker key"),
+    }
+}
+
+pub trait ProfilerMarker: Serialize + DeserializeOwned {
+    /// A static method that returns the name of the marker type.
+    fn marker_type_name() -> &'static str;
+    /// A static method that returns a URL-safe identifier for the marker type,
+    /// for HTTP APIs and other bridges that expose marker types. It's
+    /// `marker_type_name()` converted by `type_url_from_name()`, e.g.
+    /// "disk-io-marker" for "DiskIOMarker".
+    fn type_url() -> &'static str
+    where
+        Self: 'static,
+    {
+        memoized_type_url::<Self>()
+    }
+    /// A static method that returns a `MarkerSchema`, which contains all the
+    /// information needed to stream the display schema associated with a
+    /// marker type.
+    fn marker_type_display() -> MarkerSchema;
+    /// A static method that returns the schema of `marker_type_display()`,
+    /// built only the first time it's called, so that it can be used on hot
+    /// paths. The derive builds the schema itself in this method, and clones
+    /// it for `marker_type_display()`.
+    fn marker_type_display_ref() -> &'static MarkerSchema
+    where
+        Self: 'static,
+    {
+        memoized_schema::<Self>(Self::marker_type_display)
+    }
+    /// A static method that returns the options markers of this type are added
+    /// with by `add_marker`: an instant at the time they're added, without a
+    /// stack, unless the derive was given `#[marker_options(..)]`.
+    fn default_marker_options() -> MarkerOptions {
+        MarkerOptions::default()
+    }
+    /// A static method that returns the JSON schema of the marker type, as
+    /// `MarkerSchema::to_json()` builds it, serialized only the first time it's
+    /// called.
+    fn schema_json() -> &'static str
+    where
+        Self: 'static,
+    {
+        memoized_schema_json::<Self>()
+    }
+    /// A static method that returns the SHA-256 hash of `schema_json()`, as a
+    /// fingerprint of the schema for versioning binary formats. It's only
+    /// computed the first time it's called.
+    #[cfg(feature = "checksum")]
+    fn schema_checksum() -> [u8; 32]
+    where
+        Self: 'static,
+    {
+        memoized_schema_checksum::<Self>()
+    }
+    /// A method that converts the schema of the marker type, along with the
+    /// marker's payload, to a Protocol Buffers message.
+    #[cfg(feature = "protobuf")]
+    fn to_schema_proto(&self) -> proto::SchemaProto
+    where
+        Self: 'static,
+    {
+        proto::SchemaProto::from_marker(self)
+    }
+    /// A static method that returns the schema of the marker type as TOML, as
+    /// `MarkerSchema::to_toml()` writes it, for tools configured with TOML.
+    #[cfg(feature = "toml")]
+    fn schema_as_toml() -> String
+    where
+        Self: 'static,
+    {
+        Self::marker_type_display_ref().to_toml(Self::marker_type_name())
+    }
+    /// A static method that compares the schema of the marker type with
+    /// `schema_json`, a baseline returned by `schema_json()` in an earlier
+    /// version, and describes each difference, as by
+    /// `MarkerSchema::diff_against_json()`.
+    fn schema_diff_against_json(schema_json: &str) -> Vec<String>
+    where
+        Self: 'static,
+    {
+        Self::marker_type_display_ref().diff_against_json(Self::marker_type_name(), schema_json)
+    }
+    /// A static method that checks whether `schema`, e.g. one read from a
+    /// profile file, is compatible with the marker type: every data row of the
+    /// marker type's schema must have a row with the same key and format in
+    /// `schema`. Extra rows in `schema`, labels, and static rows are ignored.
+    fn schema_matches(schema: &MarkerSchema) -> bool
+    where
+        Self: 'static,
+    {
+        Self::marker_type_display_ref().rows().iter().all(|row| match row {
+            SchemaRow::Dynamic { key, format, .. } => schema.rows().iter().any(|other| {
+                matches!(other, SchemaRow::Dynamic { key: k, format: f, .. } if k == key && f == format)
+            }),
+            SchemaRow::Static { .. } => true,
+        })
+    }
+    /// A method that streams the marker payload data as JSON object properties.
+    /// Please see the [JSONWriter] struct to see its methods.
+    fn stream_json_marker_data(&self, json_writer: &mut JSONWriter);
+    /// A static method that reconstructs the marker payload from the JSON
+    /// object streamed by `stream_json_marker_data()`, resolving unique strings
+    /// through `unique_strings`.
+    /// Please see the [JSONReader] struct to see its methods.
+    fn from_json_marker_data(
+        json: &serde_json::Value,
+        unique_strings: &UniqueStrings,
+    ) -> Result<Self, MarkerError>;
+    /// A method that returns the value of the field with the given key,
+    /// formatted according to its format, or `None` if there's no such field.
+    /// Fields that are `None` are formatted as an empty string.
+    fn field_value_as_string(&self, key: &str) -> Option<String>;
+    /// A static method that returns the key of the data row at `index` among
+    /// the data rows of the schema, i.e. of the field at `index` for derived
+    /// markers, with the fields of flattened payloads in place, or `None` if
+    /// there are fewer data rows.
+    fn key_at(index: usize) -> Option<&'static str>
+    where
+        Self: 'static,
+    {
+        Self::marker_type_display_ref()
+            .rows()
+            .iter()
+            .filter_map(SchemaRow::key)
+            .nth(index)
+    }
+    /// A method that estimates the size of the JSON payload streamed by
+    /// `stream_json_marker_data()`, from the rows of the marker's schema:
+    /// 20 bytes for each numeric value, and the length of each string value
+    /// plus 4 bytes for its quotes and escapes. Keys and punctuation are
+    /// counted too. Override this for types where the estimate is known to be
+    /// wrong.
+    fn estimated_payload_bytes(&self) -> usize {
+        // The braces around the object.
+        let mut bytes = 2;
+        for row in Self::marker_type_display().rows() {
+            if let SchemaRow::Dynamic { key, format, .. } = row {
+                // The quoted key, the colon, and the comma.
+                bytes += key.len() + 4;
+                bytes += if format.is_string() {
+                    self.field_value_as_string(key)
+                        .map_or(0, |value| value.len())
+                        + 4
+                } else {
+                    20
+                };
+            }
+        }
+        bytes
+    }
+    /// A method that returns the values streamed by `stream_json_marker_data()`
+    /// for each data row of the schema, keyed by the row's key rather than the
+    /// serde name of the field. Unique strings are returned as the string
+    /// itself, rather than their index.
+    fn fields_as_hashmap(&self) -> HashMap<&'static str, serde_json::Value>
+    where
+        Self: 'static,
+    {
+        let recorder = RecordingWriter::record(self);
+        Self::marker_type_display_ref()
+            .rows()
+            .iter()
+            .filter_map(|row| {
+                let key = row.key()?;
+                Some((key, recorder.get(key)?.clone().into()))
+            })
+            .collect()
+    }
+    /// A method that formats the marker for people to read, in CLI tools
+    /// rather than the front-end: the marker type name, followed by a line
+    /// for each row of the schema, with values formatted by
+    /// `Format::pretty_format_value()`.
+    fn pretty_print(&self) -> String {
+        let mut out = Self::marker_type_name().to_string();
+        for row in Self::marker_type_display().rows() {
+            let (label, value) = match row {
+                SchemaRow::Dynamic {
+                    key, label, format, ..
+                } => {
+                    let value = self.field_value_as_string(key).unwrap_or_default();
+                    (
+                        label.as_deref().unwrap_or(key),
+                        format.pretty_format_value(&value),
+                    )
+                }
+                SchemaRow::Static { label, value } => (label.as_str(), value.clone()),
+            };
+            out.push_str(&format!("\n  {}: {}", label, value));
+        }
+        out
+    }
+    /// A method that boxes the marker as a `ProfilerMarkerDyn`, to store
+    /// markers of different types together, e.g. in a `Vec`.
+    fn into_boxed(self) -> Box<dyn ProfilerMarkerDyn>
+    where
+        Self: 'static,
+    {
+        Box::new(self)
+    }
+}
+
+/// The parts of `ProfilerMarker` that can be used through a trait object,
+/// implemented for every `ProfilerMarker`. The static methods of
+/// `ProfilerMarker` take `&self` here, and have different names so that
+/// calls on a marker aren't ambiguous when both traits are in scope.
+pub trait ProfilerMarkerDyn {
+    /// The name of the marker type, see `ProfilerMarker::marker_type_name()`.
+    fn marker_name(&self) -> &'static str;
+    /// The schema of the marker type, see
+    /// `ProfilerMarker::marker_type_display_ref()`.
+    fn schema(&self) -> &'static MarkerSchema;
+    /// The options the marker is added with, see
+    /// `ProfilerMarker::default_marker_options()`.
+    fn default_options(&self) -> MarkerOptions;
+    /// Stream the marker payload, see `ProfilerMarker::stream_json_marker_data()`.
+    fn stream_json(&self, json_writer: &mut JSONWriter);
+    /// The formatted value of the field with the given key, see
+    /// `ProfilerMarker::field_value_as_string()`.
+    fn field_as_string(&self, key: &str) -> Option<String>;
+}
+
+impl<M: ProfilerMarker + 'static> ProfilerMarkerDyn for M {
+    fn marker_name(&self) -> &'static str {
+        M::marker_type_name()
+    }
+
+    fn schema(&self) -> &'static MarkerSchema {
+        M::marker_type_display_ref()
+    }
+
+    fn default_options(&self) -> MarkerOptions {
+        M::default_marker_options()
+    }
+
+    fn stream_json(&self, json_writer: &mut JSONWriter) {
+        self.stream_json_marker_data(json_writer)
+    }
+
+    fn field_as_string(&self, key: &str) -> Option<String> {
+        self.field_value_as_string(key)
+    }
+}
+
+/// An extension of `ProfilerMarker` with metadata about the marker type, for
+/// tools that catalogue marker types. Implemented by
+/// `#[derive(ProfilerMarker)]` when the struct has a
+/// `#[marker_v2(version = 2, category = "..")]` attribute, whose arguments are
+/// both optional. The version can also be given by `#[marker_version(2)]`,
+/// which on its own only generates `schema_version_const()`.
+#[allow(non_camel_case_types)]
+pub trait ProfilerMarker_v2: ProfilerMarker {
+    /// The version of the marker's schema, to be bumped when the fields change
+    /// incompatibly. Defaults to 1.
+    fn schema_version() -> u32 {
+        1
+    }
+    /// The profiling category that the marker belongs to, if any.
+    fn category() -> Option<&'static str> {
+        None
+    }
+    /// The number of fields streamed by the marker, including those of
+    /// flattened fields.
+    fn field_count() -> usize;
+}
+
+/// A cache of values that are built once per marker type, and leaked.
+type TypeCache<V> = OnceLock<RwLock<HashMap<TypeId, &'static V>>>;
+
+/// Return the value built by `build` for the type `T` from `cache`, building it
+/// the first time this is called for `T`.
+fn memoized<T: 'static, V: Sync + 'static>(
+    cache: &'static TypeCache<V>,
+    build: impl FnOnce() -> V,
+) -> &'static V {
+    let values = cache.get_or_init(Default::default);
+    let type_id = TypeId::of::<T>();
+    if let Some(value) = values
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&type_id)
+    {
+        return value;
+    }
+    // Build the value without holding the lock, as building it may look up
+    // the values of other marker types.
+    let value = build();
+    values
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(type_id)
+        .or_insert_with(|| Box::leak(Box::new(value)))
+}
+
+/// Return the schema built by `build` for the marker type `T`, building it the
+/// first time this is called for `T`. A `static` in a generic function is shared
+/// by all of its instantiations, so this keeps a schema per `TypeId` instead.
+/// The schemas are leaked, as there's only ever one per marker type.
+#[doc(hidden)]
+pub fn memoized_schema<T: 'static>(build: fn() -> MarkerSchema) -> &'static MarkerSchema {
+    static SCHEMAS: TypeCache<MarkerSchema> = OnceLock::new();
+    memoized::<T, _>(&SCHEMAS, build)
+}
+
+/// Return the schema JSON of the marker type `T`, serializing it the first time
+/// this is called for `T`, like `memoized_schema`.
+#[doc(hidden)]
+pub fn memoized_schema_json<T: ProfilerMarker + 'static>() -> &'static str {
+    static SCHEMA_JSONS: TypeCache<String> = OnceLock::new();
+    memoized::<T, String>(&SCHEMA_JSONS, schema_json_string::<T>)
+}
+
+/// Return the checksum of the schema JSON of the marker type `T`, hashing it
+/// the first time this is called for `T`, like `memoized_schema`.
+#[cfg(feature = "checksum")]
+#[doc(hidden)]
+pub fn memoized_schema_checksum<T: ProfilerMarker + 'static>() -> [u8; 32] {
+    static SCHEMA_CHECKSUMS: TypeCache<[u8; 32]> = OnceLock::new();
+    *memoized::<T, _>(&SCHEMA_CHECKSUMS, || schema_checksum_of(T::schema_json()))
+}
+
+/// The SHA-256 hash of a schema JSON string.
+#[cfg(feature = "checksum")]
+#[doc(hidden)]
+pub fn schema_checksum_of(schema_json: &str) -> [u8; 32] {
+    use sha2::Digest;
+    sha2::Sha256::digest(schema_json.as_bytes()).into()
+}
+
+/// Expands to the derive's `schema_checksum()` for a non-generic marker, which
+/// keeps the checksum in a `static`. Without the `checksum` feature, it expands
+/// to nothing, so that the derive doesn't need to know which features are on.
+#[cfg(feature = "checksum")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __schema_checksum_fn {
+    () => {
+        fn schema_checksum() -> [u8; 32] {
+            static SCHEMA_CHECKSUM: ::std::sync::OnceLock<[u8; 32]> = ::std::sync::OnceLock::new();
+            *SCHEMA_CHECKSUM.get_or_init(|| {
+                $crate::schema_checksum_of(<Self as $crate::ProfilerMarker>::schema_json())
+            })
+        }
+    };
+}
+
+#[cfg(not(feature = "checksum"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __schema_checksum_fn {
+    () => {};
+}
+
+/// Expands to the derive's `to_schema_proto()` for a non-generic marker, which
+/// keeps the schema part of the message in a `static`. Without the `protobuf`
+/// feature, it expands to nothing.
+#[cfg(feature = "protobuf")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __schema_proto_fn {
+    () => {
+        fn to_schema_proto(&self) -> $crate::proto::SchemaProto {
+            static SCHEMA_PROTO: ::std::sync::OnceLock<$crate::proto::SchemaProto> =
+                ::std::sync::OnceLock::new();
+            SCHEMA_PROTO
+                .get_or_init(|| {
+                    $crate::proto::SchemaProto::from_schema(
+                        <Self as $crate::ProfilerMarker>::marker_type_name(),
+                        <Self as $crate::ProfilerMarker>::marker_type_display_ref(),
+                    )
+                })
+                .clone()
+                .with_data(self)
+        }
+    };
+}
+
+#[cfg(not(feature = "protobuf"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __schema_proto_fn {
+    () => {};
+}
+
+/// Return the `type_url()` of the marker type `T`, converting its name the
+/// first time this is called for `T`, like `memoized_schema`.
+#[doc(hidden)]
+pub fn memoized_type_url<T: ProfilerMarker + 'static>() -> &'static str {
+    static TYPE_URLS: TypeCache<String> = OnceLock::new();
+    memoized::<T, String>(&TYPE_URLS, || type_url_from_name(T::marker_type_name()))
+}
+
+/// Convert a marker type name to a URL-safe identifier: camel case words are
+/// separated by hyphens, as are the words of snake case names, and everything
+/// is lowercased, e.g. "DiskIOMarker" becomes "disk-io-marker". Other
+/// characters that aren't letters or digits are replaced by hyphens too, and
+/// non-ASCII letters and digits are percent-encoded, e.g. "ÜberMarker" becomes
+/// "%C3%BCber-marker".
+pub fn type_url_from_name(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut url = String::with_capacity(name.len() + 4);
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !url.is_empty() && !url.ends_with('-') {
+                url.push('-');
+            }
+            continue;
+        }
+        if c.is_uppercase() && i > 0 && !url.ends_with('-') {
+            // Start a new word at a lowercase-to-uppercase boundary, e.g.
+            // "DiskIO", or at the last capital of an acronym, e.g. "IOMarker".
+            let previous = chars[i - 1];
+            let next_is_lowercase = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if previous.is_lowercase()
+                || previous.is_numeric()
+                || (previous.is_uppercase() && next_is_lowercase)
+            {
+                url.push('-');
+            }
+        }
+        for lower in c.to_lowercase() {
+            if lower.is_ascii() {
+                url.push(lower);
+            } else {
+                let mut bytes = [0; 4];
+                for byte in lower.encode_utf8(&mut bytes).bytes() {
+                    url.push_str(&format!("%{:02X}", byte));
+                }
+            }
+        }
+    }
+    while url.ends_with('-') {
+        url.pop();
+    }
+    url
+}
+
+/// Serialize the schema of the marker type `T`, as `MarkerSchema::to_json()`
+/// builds it.
+#[doc(hidden)]
+pub fn schema_json_string<T: ProfilerMarker + 'static>() -> String {
+    T::marker_type_display_ref()
+        .to_json(T::marker_type_name())
+        .to_string()
+}
+
+/// The instant that `Format::Time` values streamed from an `Instant` are
+/// relative to. It's taken the first time it's used.
+pub static PROCESS_START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Convert `instant` to the milliseconds since `PROCESS_START`, which is how
+/// `Instant` fields with `#[format(Time)]` are streamed. Instants from before
+/// `PROCESS_START` are negative.
+pub fn process_time(instant: Instant) -> f64 {
+    let start = *PROCESS_START;
+    match instant.checked_duration_since(start) {
+        Some(since) => since.as_secs_f64() * 1000.0,
+        None => -(start - instant).as_secs_f64() * 1000.0,
+    }
+}
+
+/// Convert milliseconds since `PROCESS_START` back to an `Instant`, the
+/// inverse of `process_time()`. Returns `None` for NaN, infinities, and times
+/// too far from `PROCESS_START` to be represented as an `Instant`.
+pub fn instant_from_process_time(milliseconds: f64) -> Option<Instant> {
+    let offset = std::time::Duration::try_from_secs_f64(milliseconds.abs() / 1000.0).ok()?;
+    if milliseconds < 0.0 {
+        PROCESS_START.checked_sub(offset)
+    } else {
+        PROCESS_START.checked_add(offset)
+    }
+}
+
+/// Serialize an `Instant` as its `process_time()`, as `Instant` doesn't
+/// implement serde's traits. For `Instant` fields of markers, with
+/// `#[serde(with = "fx_markers::serde_process_time")]`.
+pub mod serde_process_time {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::time::Instant;
+
+    pub fn serialize<S: Serializer>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(super::process_time(*instant))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+        let milliseconds = f64::deserialize(deserializer)?;
+        super::instant_from_process_time(milliseconds).ok_or_else(|| {
+            D::Error::custom(format!("{} is out of the range of `Instant`", milliseconds))
+        })
+    }
+}
+
+/// When a marker happened: at a single instant, or over an interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkerTiming {
+    /// The marker happened at a single point in time.
+    Instant(Instant),
+    /// The marker covers the time from `start` to `end`. Intervals whose `end`
+    /// is before their `start` are normalized by swapping the two, both by
+    /// `MarkerTiming::interval()` and when the marker is recorded.
+    Interval { start: Instant, end: Instant },
+    /// The start of an interval, whose end is recorded by a separate marker.
+    IntervalStart(Instant),
+    /// The end of an interval, whose start was recorded by a separate marker.
+    IntervalEnd(Instant),
+}
+
+impl MarkerTiming {
+    /// An instant timing at the current time.
+    pub fn instant_now() -> Self {
+        MarkerTiming::Instant(Instant::now())
+    }
+
+    /// An interval timing from `start` to `end`. If `end` is before `start`,
+    /// the two are swapped.
+    pub fn interval(start: Instant, end: Instant) -> Self {
+        if end < start {
+            MarkerTiming::Interval {
+                start: end,
+                end: start,
+            }
+        } else {
+            MarkerTiming::Interval { start, end }
+        }
+    }
+
+    /// An interval timing from `start` to the current time.
+    pub fn interval_until_now_from(start: Instant) -> Self {
+        MarkerTiming::interval(start, Instant::now())
+    }
+
+    /// The same timing, with the ends of an interval swapped if needed.
+    fn normalized(self) -> Self {
+        match self {
+            MarkerTiming::Interval { start, end } => MarkerTiming::interval(start, end),
+            timing => timing,
+        }
+    }
+}
+
+/// Whether to capture a stack when a marker is recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MarkerStack {
+    #[default]
+    NoStack,
+    /// Capture the stack of the thread that adds the marker.
+    Capture,
+}
+
+/// The timing of a marker, as given to `MarkerOptions`. Timings relative to
+/// the time the marker is added are resolved when it's recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimingSpec {
+    /// An instant at the time the marker is added.
+    Now,
+    /// An interval from the given time until the marker is added.
+    IntervalFrom(Instant),
+    Fixed(MarkerTiming),
+}
+
+/// The options that a marker is added with, besides its payload: its timing,
+/// and whether to capture a stack. By default, a marker is an instant at the
+/// time it's added, without a stack.
+///
+/// Marker types can choose their own defaults with
+/// `#[marker_options(..)]`, which the derive turns into an override of
+/// `ProfilerMarker::default_marker_options()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MarkerOptions {
+    timing: TimingSpec,
+    stack: MarkerStack,
+}
+
+impl Default for MarkerOptions {
+    fn default() -> Self {
+        MarkerOptions::new()
+    }
+}
+
+impl MarkerOptions {
+    pub const fn new() -> Self {
+        MarkerOptions {
+            timing: TimingSpec::Now,
+            stack: MarkerStack::NoStack,
+        }
+    }
+
+    /// Record the marker with the given timing.
+    pub fn timing(mut self, timing: MarkerTiming) -> Self {
+        self.timing = TimingSpec::Fixed(timing);
+        self
+    }
+
+    /// Record the marker as an interval from `start` until the time it's
+    /// added.
+    pub fn interval_from(mut self, start: Instant) -> Self {
+        self.timing = TimingSpec::IntervalFrom(start);
+        self
+    }
+
+    pub fn stack(mut self, stack: MarkerStack) -> Self {
+        self.stack = stack;
+        self
+    }
+
+    /// The timing of a marker added with these options at `now`.
+    pub fn resolve_timing(&self, now: Instant) -> MarkerTiming {
+        match self.timing {
+            TimingSpec::Now => MarkerTiming::Instant(now),
+            TimingSpec::IntervalFrom(start) => MarkerTiming::interval(start, now),
+            TimingSpec::Fixed(timing) => timing.normalized(),
+        }
+    }
+}
+
+/// A marker that has been recorded by `add_marker`.
+#[derive(Debug, Clone)]
+pub struct MarkerEntry {
+    /// The name the marker was added with.
+    pub name: String,
+    /// The name of the marker type, as returned by `marker_type_name()`.
+    pub marker_type: &'static str,
+    /// When the marker was added.
+    pub timestamp: Instant,
+    /// When the marker happened, from the `MarkerOptions` it was added with.
+    pub timing: MarkerTiming,
+    /// The stack captured when the marker was added, if its `MarkerOptions`
+    /// asked for one.
+    pub stack: Option<Arc<Backtrace>>,
+    /// The marker payload JSON object, as streamed by `stream_json_marker_data()`.
+    /// Unique strings are written as indices into the table of the `MarkerBuffer`
+    /// the marker was recorded in.
+    pub payload: String,
+}
+
+struct MarkerBufferInner {
+    entries: VecDeque<MarkerEntry>,
+    unique_strings: UniqueStrings,
+    /// The number of times `unique_strings` has been cleared. Strings are
+    /// only ever added to the table otherwise, so this and its length tell
+    /// whether it changed while a payload was streamed.
+    clears: u64,
+}
+
+/// An in-memory profiler backend, that records markers instead of sending them
+/// to Gecko. This lets tests check which markers a code path emits.
+///
+/// The buffer is bounded: once it holds `capacity` entries, adding a marker
+/// discards the oldest entry.
+pub struct MarkerBuffer {
+    inner: Mutex<MarkerBufferInner>,
+    capacity: usize,
+}
+
+/// The number of markers held by the global marker buffer.
+pub const DEFAULT_MARKER_BUFFER_CAPACITY: usize = 1024;
+
+static GLOBAL_MARKER_BUFFER: MarkerBuffer = MarkerBuffer::new(DEFAULT_MARKER_BUFFER_CAPACITY);
+
+impl MarkerBuffer {
+    /// Create an empty buffer that holds at most `capacity` markers.
+    pub const fn new(capacity: usize) -> Self {
+        MarkerBuffer {
+            inner: Mutex::new(MarkerBufferInner {
+                entries: VecDeque::new(),
+                unique_strings: UniqueStrings::new(),
+                clears: 0,
+            }),
+            capacity,
+        }
+    }
+
+    /// The buffer that `add_marker` records markers in.
+    pub fn global() -> &'static MarkerBuffer {
+        &GLOBAL_MARKER_BUFFER
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MarkerBufferInner> {
+        // A panic while holding the lock can't leave the entries in an
+        // inconsistent state, so just carry on.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Stream the payload of `marker`, and record it in the buffer with the
+    /// marker type's `default_marker_options()`.
+    pub fn add_marker<T: ProfilerMarker>(&self, name: &str, marker: &T) {
+        self.add_marker_with_options(name, T::default_marker_options(), marker);
+    }
+
+    /// Stream the payload of `marker`, and record it in the buffer with the
+    /// timing and stack given by `options`.
+    pub fn add_marker_with_options<T: ProfilerMarker>(
+        &self,
+        name: &str,
+        options: MarkerOptions,
+        marker: &T,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let timestamp = Instant::now();
+        let timing = options.resolve_timing(timestamp);
+        // Capture the stack before taking the lock, as it's slow.
+        let stack = match options.stack {
+            MarkerStack::NoStack => None,
+            MarkerStack::Capture => Some(Arc::new(Backtrace::force_capture())),
+        };
+
+        // Stream the payload without holding the lock, so that a payload that
+        // panics leaves the buffer as it was, and one that adds markers itself
+        // doesn't deadlock. The writer starts with a copy of the unique string
+        // table, and the strings it adds are added to the table afterwards. If
+        // the table changed in the meantime, the indices written may be wrong,
+        // so the payload is streamed again.
+        let (payload, mut inner) = loop {
+            let (unique_strings, clears) = {
+                let inner = self.lock();
+                (inner.unique_strings.clone(), inner.clears)
+            };
+            let known = unique_strings.strings.len();
+            let mut json_writer = JSONWriter::new();
+            json_writer.unique_strings = unique_strings;
+            marker.stream_json_marker_data(&mut json_writer);
+
+            let mut inner = self.lock();
+            if inner.clears == clears && inner.unique_strings.strings.len() == known {
+                let added = json_writer.unique_strings.strings.drain(known..);
+                inner.unique_strings.strings.extend(added);
+                break (json_writer.seal(), inner);
+            }
+        };
+        if inner.entries.len() == self.capacity {
+            inner.entries.pop_front();
+        }
+        inner.entries.push_back(MarkerEntry {
+            name: name.to_string(),
+            marker_type: T::marker_type_name(),
+            timestamp,
+            timing,
+            stack,
+            payload,
+        });
+    }
+
+    /// Remove all the recorded markers from the buffer, and return them in
+    /// the order they were added.
+    pub fn drain(&self) -> Vec<MarkerEntry> {
+        self.lock().entries.drain(..).collect()
+    }
+
+    /// Discard all the recorded markers, along with the unique string table.
+    pub fn clear(&self) {
+        let mut inner = self.lock();
+        inner.entries.clear();
+        inner.unique_strings = UniqueStrings::new();
+        inner.clears += 1;
+    }
+
+    /// The number of markers currently recorded.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Look up a unique string that was streamed into a recorded payload.
+    pub fn unique_string(&self, index: u32) -> Option<String> {
+        self.lock().unique_strings.get(index).map(str::to_string)
+    }
+}
+
+static PROFILER_ACTIVE: AtomicBool = AtomicBool::new(true);
+
+/// Whether the profiler is capturing markers. While it isn't, `add_marker` and
+/// `add_lazy_marker` return straight away, without streaming the payload.
+pub fn is_active() -> bool {
+    PROFILER_ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Start or stop capturing markers. Markers are captured by default.
+/// For now this is the only source of `is_active()`; the Gecko integration
+/// should forward the state of the real profiler here.
+pub fn set_active(active: bool) {
+    PROFILER_ACTIVE.store(active, Ordering::Relaxed);
+}
+
+/// Add a marker to the global marker buffer, if the profiler is active.
+pub fn add_marker<T: ProfilerMarker>(name: &str, marker: &T) {
+    if !is_active() {
+        return;
+    }
+    MarkerBuffer::global().add_marker(name, marker);
+}
+
+/// Add a marker to the global marker buffer with the given options, if the
+/// profiler is active.
+pub fn add_marker_with_options<T: ProfilerMarker>(name: &str, options: MarkerOptions, marker: &T) {
+    if !is_active() {
+        return;
+    }
+    MarkerBuffer::global().add_marker_with_options(name, options, marker);
+}
+
+/// Add the marker built by `make_marker` to the global marker buffer, if the
+/// profiler is active. Otherwise `make_marker` isn't called, so the payload
+/// isn't even built.
+pub fn add_lazy_marker<T: ProfilerMarker, F: FnOnce() -> T>(name: &str, make_marker: F) {
+    if !is_active() {
+        return;
+    }
+    MarkerBuffer::global().add_marker(name, &make_marker());
+}
+
+/// A label frame, which attributes the samples taken while it's entered to a
+/// profiling category (and optionally a subcategory).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProfilerLabel {
+    pub category: &'static str,
+    pub subcategory: Option<&'static str>,
+}
+
+impl ProfilerLabel {
+    pub const fn new(category: &'static str, subcategory: Option<&'static str>) -> Self {
+        ProfilerLabel {
+            category,
+            subcategory,
+        }
+    }
+}
+
+thread_local! {
+    static LABEL_STACK: RefCell<Vec<ProfilerLabel>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The label frames entered on the current thread, outermost first.
+pub fn label_stack() -> Vec<ProfilerLabel> {
+    LABEL_STACK.with(|stack| stack.borrow().clone())
+}
+
+/// The innermost label frame entered on the current thread.
+pub fn current_label() -> Option<ProfilerLabel> {
+    LABEL_STACK.with(|stack| stack.borrow().last().copied())
+}
+
+/// Enters a label frame on the current thread for as long as it's alive.
+/// Usually created through `gecko_profiler_label!`, or the
+/// `#[gecko_profiler_fn_label]` attribute.
+#[derive(Debug)]
+pub struct AutoProfilerLabel {
+    // The frame must be exited on the thread that entered it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl AutoProfilerLabel {
+    pub fn new(label: ProfilerLabel) -> Self {
+        LABEL_STACK.with(|stack| stack.borrow_mut().push(label));
+        AutoProfilerLabel {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for AutoProfilerLabel {
+    fn drop(&mut self) {
+        LABEL_STACK.with(|stack| stack.borrow_mut().pop());
+    }
+}
+
+/// Enter a label frame until the end of the enclosing scope, e.g.
+/// `gecko_profiler_label!(Layout)` or `gecko_profiler_label!(Javascript, IonMonkey)`.
+#[macro_export]
+macro_rules! gecko_profiler_label {
+    ($category:ident) => {
+        let _gecko_profiler_label =
+            $crate::AutoProfilerLabel::new($crate::ProfilerLabel::new(stringify!($category), None));
+    };
+    ($category:ident, $subcategory:ident) => {
+        let _gecko_profiler_label = $crate::AutoProfilerLabel::new($crate::ProfilerLabel::new(
+            stringify!($category),
+            Some(stringify!($subcategory)),
+        ));
+    };
+}
+
+/// A future that enters a label frame each time it's polled, and exits it
+/// again before returning. This is what `#[gecko_profiler_fn_label]` wraps the
+/// body of an `async fn` in, so that the label covers every part of the body
+/// that runs, on whichever thread polls it, but none of the time it's
+/// suspended at an `.await`.
+#[derive(Debug)]
+pub struct LabeledFuture<F> {
+    label: ProfilerLabel,
+    future: F,
+}
+
+impl<F: Future> LabeledFuture<F> {
+    pub fn new(label: ProfilerLabel, future: F) -> Self {
+        LabeledFuture { label, future }
+    }
+}
+
+impl<F: Future> Future for LabeledFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // SAFETY: `future` is never moved out of `self`, nor is `self` moved
+        // out of, so pinning `self` pins `future` too.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        let _label = AutoProfilerLabel::new(this.label);
+        future.poll(cx)
+    }
+}
+
+/// Protocol Buffers messages for marker schemas and payloads, for consumers
+/// that don't read JSON. The messages are:
+///
+/// ```proto
+/// message SchemaProto {
+///   string name = 1;
+///   repeated string locations = 2;
+///   repeated SchemaRowProto rows = 3;
+///   // The payload streamed by `stream_json_marker_data()`, as JSON.
+///   string data = 4;
+///   // The unique strings that the payload refers to, by index.
+///   repeated string unique_strings = 5;
+/// }
+///
+/// message SchemaRowProto {
+///   // Empty for static rows.
+///   string key = 1;
+///   string label = 2;
+///   string format = 3;
+///   bool searchable = 4;
+///   // The value of static rows.
+///   string value = 5;
+/// }
+/// ```
+///
+/// Locations and formats are written with their names in the schema JSON.
+#[cfg(feature = "protobuf")]
+pub mod proto {
+    use crate::{JSONWriter, MarkerSchema, ProfilerMarker, SchemaRow, Searchable};
+
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct SchemaProto {
+        pub name: String,
+        pub locations: Vec<String>,
+        pub rows: Vec<SchemaRowProto>,
+        pub data: String,
+        pub unique_strings: Vec<String>,
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct SchemaRowProto {
+        pub key: String,
+        pub label: String,
+        pub format: String,
+        pub searchable: bool,
+        pub value: String,
+    }
+
+    /// An error from decoding a message that isn't valid protobuf, or doesn't
+    /// match the message definition.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct DecodeError(&'static str);
+
+    impl std::fmt::Display for DecodeError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "invalid protobuf message: {}", self.0)
+        }
+    }
+
+    impl std::error::Error for DecodeError {}
+
+    impl SchemaProto {
+        /// The message for a schema, without a payload.
+        pub fn from_schema(name: &str, schema: &MarkerSchema) -> Self {
+            let rows = schema
+                .rows()
+                .iter()
+                .map(|row| match row {
+                    SchemaRow::Dynamic {
+                        key,
+                        label,
+                        format,
+                        searchable,
+                    } => SchemaRowProto {
+                        key: key.clone(),
+                        label: label.clone().unwrap_or_default(),
+                        format: format.json_name().to_string(),
+                        searchable: *searchable == Searchable::Searchable,
+                        value: String::new(),
+                    },
+                    SchemaRow::Static { label, value } => SchemaRowProto {
+                        label: label.clone(),
+                        value: value.clone(),
+                        ..Default::default()
+                    },
+                })
+                .collect();
+            SchemaProto {
+                name: name.to_string(),
+                locations: schema
+                    .locations()
+                    .iter()
+                    .map(|location| location.json_name().to_string())
+                    .collect(),
+                rows,
+                data: String::new(),
+                unique_strings: vec![],
+            }
+        }
+
+        /// The message for the schema of `T`, along with the payload of
+        /// `marker`. This is what `ProfilerMarker::to_schema_proto()` returns.
+        pub fn from_marker<T: ProfilerMarker + 'static>(marker: &T) -> Self {
+            Self::from_schema(T::marker_type_name(), T::marker_type_display_ref()).with_data(marker)
+        }
+
+        /// Replace the payload with that of `marker`.
+        pub fn with_data<T: ProfilerMarker>(mut self, marker: &T) -> Self {
+            let mut json_writer = JSONWriter::new();
+            marker.stream_json_marker_data(&mut json_writer);
+            self.unique_strings = json_writer.unique_strings().strings.clone();
+            self.data = json_writer.seal();
+            self
+        }
+
+        /// Encode the message in the protobuf wire format.
+        pub fn encode_to_vec(&self) -> Vec<u8> {
+            let mut buffer = vec![];
+            write_string(&mut buffer, 1, &self.name);
+            for location in &self.locations {
+                write_string(&mut buffer, 2, location);
+            }
+            for row in &self.rows {
+                write_bytes(&mut buffer, 3, &row.encode_to_vec());
+            }
+            write_string(&mut buffer, 4, &self.data);
+            for string in &self.unique_strings {
+                write_string(&mut buffer, 5, string);
+            }
+            buffer
+        }
+
+        /// Decode a message encoded in the protobuf wire format.
+        pub fn decode(mut bytes: &[u8]) -> Result<Self, DecodeError> {
+            let mut message = SchemaProto::default();
+            while !bytes.is_empty() {
+                match read_field(&mut bytes)? {
+                    (1, Field::Bytes(value)) => message.name = to_string(value)?,
+                    (2, Field::Bytes(value)) => message.locations.push(to_string(value)?),
+                    (3, Field::Bytes(value)) => message.rows.push(SchemaRowProto::decode(value)?),
+                    (4, Field::Bytes(value)) => message.data = to_string(value)?,
+                    (5, Field::Bytes(value)) => message.unique_strings.push(to_string(value)?),
+                    (1..=5, _) => return Err(DecodeError("unexpected wire type")),
+                    // Skip fields added by later versions of the message.
+                    _ => {}
+                }
+            }
+            Ok(message)
+        }
+    }
+
+    impl SchemaRowProto {
+        /// Encode the message in the protobuf wire format.
+        pub fn encode_to_vec(&self) -> Vec<u8> {
+            let mut buffer = vec![];
+            write_string(&mut buffer, 1, &self.key);
+            write_string(&mut buffer, 2, &self.label);
+            write_string(&mut buffer, 3, &self.format);
+            if self.searchable {
+                write_varint(&mut buffer, 4 << 3);
+                write_varint(&mut buffer, 1);
+            }
+            write_string(&mut buffer, 5, &self.value);
+            buffer
+        }
+
+        /// Decode a message encoded in the protobuf wire format.
+        pub fn decode(mut bytes: &[u8]) -> Result<Self, DecodeError> {
+            let mut row = SchemaRowProto::default();
+            while !bytes.is_empty() {
+                match read_field(&mut bytes)? {
+                    (1, Field::Bytes(value)) => row.key = to_string(value)?,
+                    (2, Field::Bytes(value)) => row.label = to_string(value)?,
+                    (3, Field::Bytes(value)) => row.format = to_string(value)?,
+                    (4, Field::Varint(value)) => row.searchable = value != 0,
+                    (5, Field::Bytes(value)) => row.value = to_string(value)?,
+                    (1..=5, _) => return Err(DecodeError("unexpected wire type")),
+                    _ => {}
+                }
+            }
+            Ok(row)
+        }
+    }
+
+    /// The value of a field, by wire type. Fixed-size values are only skipped.
+    enum Field<'a> {
+        Varint(u64),
+        Bytes(&'a [u8]),
+        Fixed,
+    }
+
+    fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            buffer.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        buffer.push(value as u8);
+    }
+
+    fn write_bytes(buffer: &mut Vec<u8>, field: u32, value: &[u8]) {
+        write_varint(buffer, u64::from(field) << 3 | 2);
+        write_varint(buffer, value.len() as u64);
+        buffer.extend_from_slice(value);
+    }
+
+    /// Write a string field, leaving it out if it's empty, which is its
+    /// default value.
+    fn write_string(buffer: &mut Vec<u8>, field: u32, value: &str) {
+        if !value.is_empty() {
+            write_bytes(buffer, field, value.as_bytes());
+        }
+    }
+
+    fn read_varint(bytes: &mut &[u8]) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = bytes.split_first().ok_or(DecodeError("truncated varint"))?;
+            *bytes = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError("varint is too long"))
+    }
+
+    fn take<'a>(bytes: &mut &'a [u8], len: u64) -> Result<&'a [u8], DecodeError> {
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= bytes.len())
+            .ok_or(DecodeError("truncated field"))?;
+        let (value, rest) = bytes.split_at(len);
+        *bytes = rest;
+        Ok(value)
+    }
+
+    fn read_field<'a>(bytes: &mut &'a [u8]) -> Result<(u64, Field<'a>), DecodeError> {
+        let key = read_varint(bytes)?;
+        let value = match key & 7 {
+            0 => Field::Varint(read_varint(bytes)?),
+            1 => {
+                take(bytes, 8)?;
+                Field::Fixed
+            }
+            2 => {
+                let len = read_varint(bytes)?;
+                Field::Bytes(take(bytes, len)?)
+            }
+            5 => {
+                take(bytes, 4)?;
+                Field::Fixed
+            }
+            _ => return Err(DecodeError("unsupported wire type")),
+        };
+        Ok((key >> 3, value))
+    }
+
+    fn to_string(bytes: &[u8]) -> Result<String, DecodeError> {
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError("string isn't UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // The markers below are derived without glob importing the crate, to
+    // check that the generated code doesn't depend on what's in scope.
+    use crate::{
+        add_lazy_marker, add_marker, current_label, is_active, label_stack, set_active, Format,
+        GraphColor, GraphType, JSONWriter, JsonTypeHint, Location, MarkerBuffer, MarkerEntry,
+        MarkerError, MarkerFieldValue, MarkerFields, MarkerOptions, MarkerSchema, MarkerStack,
+        MarkerTiming, ProfilerLabel, ProfilerMarker, ProfilerMarkerDyn, ProfilerMarker_v2,
+        PropertyValue, RecordingWriter, SchemaGraph, SchemaRow, Searchable, SerializeError,
+        UniqueStrings,
+    };
+    use profiler_macros::{gecko_profiler_fn_label, ProfilerMarker};
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart, MarkerTable, TimelineIPC)]
+    pub struct ExampleMarker {
+        #[searchable]
+        #[format(Integer)]
+        field1: u32,
+        #[format(String)]
+        field2: String,
+        #[format(Integer)]
+        field3: std::option::Option<f32>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    pub struct FeatureGatedMarker {
+        #[format(Integer)]
+        id: u32,
+        #[cfg(feature = "protobuf")]
+        #[format(Bytes)]
+        encoded_size: u64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    pub struct SharedStringMarker {
+        #[format(String)]
+        arc_str: std::sync::Arc<str>,
+        #[format(String)]
+        rc_str: std::rc::Rc<str>,
+        #[format(String)]
+        arc_string: std::sync::Arc<String>,
+        #[format(String)]
+        rc_string: std::rc::Rc<String>,
+        #[format(Url)]
+        url: Option<std::sync::Arc<str>>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart, TimelineFileIO)]
+    pub struct DiskIOMarker {
+        #[format(FilePath)]
+        path: String,
+        #[format(Bytes)]
+        size: u64,
+        #[format(UniqueString)]
+        operation: String,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerTable)]
+    pub struct RenamedMarker {
+        #[key = "innerWindowID"]
+        #[format(Integer)]
+        inner_window_id: u64,
+        #[key = "category"]
+        #[format(UniqueString)]
+        category: Option<String>,
+        #[format(String)]
+        count: u32,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(TimelineMemory)]
+    pub struct MemoryMarker {
+        #[format(Bytes)]
+        #[graph(Bar)]
+        allocated: u64,
+        #[format(Percentage)]
+        #[graph(FilledLine, color = Orange)]
+        usage: f64,
+        #[format(String)]
+        description: String,
+    }
+
+    /// Write a single float property, and parse it back.
+    fn float_round_trip(value: f64) -> (String, serde_json::Value) {
+        let mut json_writer = JSONWriter::new();
+        json_writer.float_property("value", value);
+        let mut payload = json_writer.take_string();
+        payload.push('}');
+        let json: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        (payload, json["value"].clone())
+    }
+
+    #[test]
+    fn float_property_round_trips() {
+        let values = [
+            0.1 + 0.2,
+            1.5,
+            -42.0,
+            1e21,
+            1e300,
+            f64::MAX,
+            f64::MIN,
+            1e-7,
+            f64::MIN_POSITIVE,
+            5e-324,
+        ];
+        for value in values {
+            let (payload, json) = float_round_trip(value);
+            assert_eq!(json.as_f64(), Some(value), "{}", payload);
+        }
+        assert_eq!(
+            float_round_trip(0.1 + 0.2).0,
+            r#"{"value":0.30000000000000004}"#
+        );
+        assert_eq!(float_round_trip(1e300).0, r#"{"value":1e300}"#);
+        assert_eq!(float_round_trip(5e-324).0, r#"{"value":5e-324}"#);
+
+        let (_, negative_zero) = float_round_trip(-0.0);
+        let negative_zero = negative_zero.as_f64().unwrap();
+        assert_eq!(negative_zero, 0.0);
+        assert!(negative_zero.is_sign_negative());
+
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert_eq!(float_round_trip(value).1, serde_json::Value::Null);
+        }
+    }
+
+    #[test]
+    fn double_property_with_precision() {
+        let mut json_writer = JSONWriter::new();
+        json_writer.double_property_with_precision("percentage", 12.3456, 2);
+        json_writer.double_property_with_precision("rounded", 0.999, 1);
+        json_writer.double_property_with_precision("nan", f64::NAN, 2);
+        let mut payload = json_writer.take_string();
+        payload.push('}');
+        assert_eq!(payload, r#"{"percentage":12.35,"rounded":1.0,"nan":null}"#);
+        let json: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(json["percentage"].as_f64(), Some(12.35));
+    }
+
+    #[derive(Debug, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(
+        MarkerChart,
+        TimelineOverview,
+        note = "This marker shows up in the overview"
+    )]
+    pub struct OverviewMarker {
+        #[searchable]
+        #[format(Integer)]
+        #[graph(Line, color = Blue)]
+        count: u32,
+        #[key = "label"]
+        #[format(UniqueString)]
+        name: String,
+    }
+
+    #[test]
+    fn derived_schema_json() {
+        let schema = OverviewMarker::marker_type_display();
+        assert_eq!(
+            schema.locations(),
+            &[Location::MarkerChart, Location::TimelineOverview]
+        );
+        assert_eq!(
+            schema.location_note(Location::TimelineOverview),
+            Some("This marker shows up in the overview")
+        );
+        assert_eq!(schema.location_note(Location::MarkerChart), None);
+
+        assert_eq!(
+            schema.to_json(OverviewMarker::marker_type_name()),
+            serde_json::json!({
+                "name": "OverviewMarker",
+                "display": ["marker-chart", "timeline-overview"],
+                "displayNotes": {
+                    "timeline-overview": "This marker shows up in the overview",
+                },
+                "chartLabel": "Name: {marker.name}",
+                "data": [
+                    { "key": "count", "label": "count", "format": "integer", "searchable": true },
+                    { "key": "label", "label": "name", "format": "unique-string" },
+                ],
+                "graphs": [{ "key": "count", "type": "line", "color": "blue" }],
+            })
+        );
+    }
+
+    #[derive(Debug, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    pub struct DocumentedMarker {
+        ///
+        ///   Number of bytes written to disk
+        ///
+        /// More detail, which isn't part of the label.
+        #[format(Bytes)]
+        written: u64,
+        #[format(Integer)]
+        undocumented: u32,
+        /// A doc comment that is overridden.
+        #[label = "Explicit label"]
+        overridden: String,
+    }
+
+    #[test]
+    fn doc_comment_labels() {
+        let schema = DocumentedMarker::marker_type_display();
+        let labels: Vec<_> = schema
+            .rows()
+            .iter()
+            .map(|row| match row {
+                SchemaRow::Dynamic { label, .. } => label.as_deref(),
+                SchemaRow::Static { .. } => None,
+            })
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                Some("Number of bytes written to disk"),
+                Some("undocumented"),
+                Some("Explicit label"),
+            ]
+        );
+    }
+
+    #[derive(Debug, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    pub struct PrettyMarker {
+        #[format(Duration)]
+        duration: f64,
+        /// Size
+        #[format(Bytes)]
+        size: u64,
+        #[format(Percentage)]
+        ratio: f32,
+        #[format(Url)]
+        url: String,
+        #[format(Integer)]
+        missing: Option<u32>,
+    }
+
+    #[test]
+    fn pretty_print() {
+        let marker = PrettyMarker {
+            duration: 42.3,
+            size: 1_234_567,
+            ratio: 0.425,
+            url: "https://example.com/".to_string(),
+            missing: None,
+        };
+        let url = if cfg!(feature = "terminal") {
+            "\x1b]8;;https://example.com/\x1b\\https://example.com/\x1b]8;;\x1b\\"
+        } else {
+            "https://example.com/"
+        };
+        assert_eq!(
+            marker.pretty_print(),
+            format!(
+                "PrettyMarker\n  duration: 42.3ms\n  Size: 1.23 MB\n  ratio: 42.5%\n  url: {}\n  missing: ",
+                url
+            )
+        );
+
+        assert_eq!(Format::Bytes.pretty_format_value("512"), "512 B");
+        assert_eq!(Format::Bytes.pretty_format_value("3000000000"), "3.00 GB");
+        assert_eq!(Format::Seconds.pretty_format_value("2"), "2s");
+        assert_eq!(Format::Decimal.pretty_format_value("0.12345"), "0.123");
+        assert_eq!(Format::Integer.pretty_format_value("-7"), "-7");
+        assert_eq!(Format::Duration.pretty_format_value("n/a"), "n/a");
+    }
+
+    #[test]
+    fn into_boxed() {
+        let markers: Vec<Box<dyn ProfilerMarkerDyn>> = vec![
+            MemoryMarker {
+                allocated: 1024,
+                usage: 0.5,
+                description: "heap".to_string(),
+            }
+            .into_boxed(),
+            PrettyMarker {
+                duration: 42.3,
+                size: 7,
+                ratio: 0.425,
+                url: "https://example.com/".to_string(),
+                missing: None,
+            }
+            .into_boxed(),
+        ];
+        let names: Vec<_> = markers.iter().map(|marker| marker.marker_name()).collect();
+        assert_eq!(names, ["MemoryMarker", "PrettyMarker"]);
+        assert_eq!(markers[0].schema(), MemoryMarker::marker_type_display_ref());
+        assert_eq!(markers[1].field_as_string("size").as_deref(), Some("7"));
+        assert_eq!(markers[1].field_as_string("unknown"), None);
+
+        let mut json_writer = JSONWriter::new();
+        markers[0].stream_json(&mut json_writer);
+        assert_eq!(
+            json_writer.seal(),
+            r#"{"allocated":1024,"usage":0.5,"description":"heap"}"#
+        );
+    }
+
+    mod renamed_runtime {
+        // Refer to the runtime crate by another name, as a crate that
+        // re-exports it would.
+        use ::fx_markers as runtime;
+        use profiler_macros::ProfilerMarker;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+        #[profiler_marker(crate = "runtime")]
+        #[marker_display(MarkerTable)]
+        pub struct RenamedRuntimeMarker {
+            #[format(Integer)]
+            pub count: u32,
+            #[format(UniqueString)]
+            pub name: String,
+        }
+    }
+
+    #[test]
+    fn renamed_runtime_crate() {
+        use renamed_runtime::RenamedRuntimeMarker;
+        let marker = RenamedRuntimeMarker {
+            count: 3,
+            name: "renamed".to_string(),
+        };
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(json, serde_json::json!({ "count": 3, "name": 0 }));
+        assert_eq!(
+            RenamedRuntimeMarker::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+        assert_eq!(
+            RenamedRuntimeMarker::marker_type_display().locations(),
+            &[Location::MarkerTable]
+        );
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub enum Compression {
+        Stored,
+        Gzip { level: u8 },
+        Brotli(u8, u8),
+        Custom(String),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    pub struct ConfigMarker {
+        #[serde_property]
+        buckets: HashMap<String, Vec<u32>>,
+        #[serde_property]
+        compression: Option<Compression>,
+        #[format(Integer)]
+        count: u32,
+    }
+
+    /// Write `value` with `serde_property`, and return the JSON written.
+    fn serde_property_json<T: Serialize>(value: &T) -> Result<String, SerializeError> {
+        let mut json_writer = JSONWriter::new();
+        json_writer.serde_property("v", value)?;
+        Ok(json_writer.take_string())
+    }
+
+    #[test]
+    fn serde_property() {
+        let marker = ConfigMarker {
+            buckets: HashMap::from([
+                ("a \"quoted\" key".to_string(), vec![1, 2, 3]),
+                ("empty".to_string(), vec![]),
+            ]),
+            compression: Some(Compression::Gzip { level: 9 }),
+            count: 2,
+        };
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "buckets": { "a \"quoted\" key": [1, 2, 3], "empty": [] },
+                "compression": { "Gzip": { "level": 9 } },
+                "count": 2,
+            })
+        );
+        assert_eq!(
+            ConfigMarker::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+
+        // The JSON written is the same as serde_json's.
+        fn assert_same_as_serde_json<T: Serialize>(value: T) {
+            assert_eq!(
+                serde_property_json(&value).unwrap(),
+                format!(r#"{{"v":{}"#, serde_json::to_string(&value).unwrap())
+            );
+        }
+        assert_same_as_serde_json(Compression::Stored);
+        assert_same_as_serde_json(Compression::Brotli(1, 2));
+        assert_same_as_serde_json(Compression::Custom("\u{1}\n".to_string()));
+        assert_same_as_serde_json((Some('c'), None::<u8>, ()));
+        assert_same_as_serde_json([0.1f32, 1.5]);
+        assert_same_as_serde_json(vec![f64::NAN, -0.25]);
+        assert_same_as_serde_json(HashMap::from([(-1i64, "negative")]));
+        assert_same_as_serde_json(std::collections::BTreeMap::from([('k', u128::MAX)]));
+
+        // Keys that aren't strings or numbers can't be written, and nothing
+        // is written in their place.
+        let mut json_writer = JSONWriter::new();
+        json_writer.int_property("a", 1);
+        assert!(json_writer
+            .serde_property("bad", &HashMap::from([((1, 2), 3)]))
+            .is_err());
+        json_writer.property_from_serialize("null", &HashMap::from([(vec![1], 3)]));
+        json_writer.int_property("b", 2);
+        assert_eq!(json_writer.take_string(), r#"{"a":1,"null":null,"b":2"#);
+    }
+
+    #[test]
+    fn json_writer_hash() {
+        let write = |size| {
+            let mut json_writer = JSONWriter::new();
+            json_writer.string_property("path", "/tmp/file");
+            json_writer.int_property("size", size);
+            json_writer
+        };
+        let mut payloads = std::collections::HashSet::new();
+        assert!(payloads.insert(write(1)));
+        assert!(!payloads.insert(write(1)));
+        assert!(payloads.insert(write(2)));
+        assert_eq!(payloads.len(), 2);
+        assert!(payloads.contains(&write(2)));
+    }
+
+    #[test]
+    fn json_writer_eq() {
+        let mut derived = JSONWriter::new();
+        StyleFlushMarker { elements: 3 }.stream_json_marker_data(&mut derived);
+        let mut by_hand = JSONWriter::new();
+        by_hand.int_property("elements", 3);
+        assert_eq!(derived, by_hand);
+
+        by_hand.null_property("extra");
+        assert_ne!(derived, by_hand);
+    }
+
+    #[test]
+    fn json_writer_display() {
+        let mut json_writer = JSONWriter::new();
+        assert_eq!(format!("{}", json_writer), "{");
+        json_writer.string_property("path", "/tmp/file");
+        json_writer.int_property("size", 4);
+        assert_eq!(format!("{json_writer}"), r#"{"path":"/tmp/file","size":4"#);
+        assert_eq!(json_writer.to_string(), json_writer.take_string());
+    }
+
+    #[test]
+    fn json_writer_clone() {
+        let mut json_writer = JSONWriter::new();
+        json_writer.unique_string_property("name", "first");
+        let mut checkpoint = json_writer.clone();
+        assert_eq!(checkpoint, json_writer);
+
+        json_writer.unique_string_property("other", "second");
+        checkpoint.int_property("size", 4);
+        assert_eq!(json_writer.as_str(), r#"{"name":0,"other":1"#);
+        assert_eq!(checkpoint.as_str(), r#"{"name":0,"size":4"#);
+        assert_eq!(json_writer.unique_strings().get(1), Some("second"));
+        assert_eq!(checkpoint.unique_strings().get(1), None);
+    }
+
+    #[test]
+    fn json_writer_transaction() {
+        let mut json_writer = JSONWriter::new();
+        json_writer.int_property("kept", 1);
+        let result = json_writer.transaction(|json_writer| {
+            json_writer.unique_string_property("name", "committed");
+            Some(2)
+        });
+        assert_eq!(result, Some(2));
+
+        let result = json_writer.transaction(|json_writer| {
+            json_writer.unique_string_property("other", "reverted");
+            json_writer.start_object_property("object");
+            json_writer.abort();
+            Some(3)
+        });
+        assert_eq!(result, None);
+        let result: Option<()> = json_writer.transaction(|json_writer| {
+            json_writer.int_property("reverted", 4);
+            None
+        });
+        assert_eq!(result, None);
+
+        // Aborting a nested transaction only reverts that transaction.
+        let result = json_writer.transaction(|json_writer| {
+            json_writer.int_property("outer", 5);
+            json_writer.transaction(|json_writer| {
+                json_writer.int_property("inner", 6);
+                json_writer.abort();
+                Some(())
+            });
+            Some(())
+        });
+        assert_eq!(result, Some(()));
+
+        // So are changes to the comma mode.
+        let result: Option<()> = json_writer.transaction(|json_writer| {
+            json_writer
+                .auto_comma_mode(false)
+                .int_property("reverted", 7);
+            None
+        });
+        assert_eq!(result, None);
+        json_writer.int_property("last", 8);
+
+        assert_eq!(json_writer.count_properties(), 4);
+        assert_eq!(json_writer.unique_strings().get(1), None);
+        assert_eq!(
+            json_writer.seal(),
+            r#"{"kept":1,"name":0,"outer":5,"last":8}"#
+        );
+
+        let mut recorder = RecordingWriter::new();
+        recorder.int_property("kept", 1);
+        recorder.transaction(|json_writer| {
+            json_writer.int_property("reverted", 2);
+            None::<()>
+        });
+        assert_eq!(recorder.get("reverted"), None);
+        assert_eq!(recorder.get("kept"), Some(&PropertyValue::Int(1)));
+    }
+
+    #[test]
+    fn json_writer_kv_from_schema() {
+        let marker = MemoryMarker {
+            allocated: 1024,
+            usage: 0.5,
+            description: "heap".to_string(),
+        };
+        let mut writer = JSONWriter::new();
+        writer.kv_from_schema(&marker);
+        let mut expected = JSONWriter::new();
+        marker.stream_json_marker_data(&mut expected);
+        assert_eq!(writer.seal(), expected.seal());
+
+        let marker = PrettyMarker {
+            duration: 42.5,
+            size: 7,
+            ratio: 0.5,
+            url: "https://example.com/".to_string(),
+            missing: None,
+        };
+        let mut writer = JSONWriter::new();
+        writer.kv_from_schema(&marker);
+        assert_eq!(
+            writer.seal(),
+            r#"{"duration":42.5,"size":7,"ratio":0.5,"url":"https://example.com/","missing":null}"#
+        );
+    }
+
+    #[test]
+    fn json_writer_auto_comma_mode() {
+        let mut writer = JSONWriter::new();
+        writer.int_property("a", 1);
+        writer.auto_comma_mode(false).int_property("b", 2);
+        writer.comma();
+        writer.int_property("c", 3);
+        writer.auto_comma_mode(true).int_property("d", 4);
+        assert_eq!(writer.seal(), r#"{"a":1"b":2,"c":3,"d":4}"#);
+
+        let mut writer = JSONWriter::new();
+        writer.auto_comma_mode(false);
+        writer.repeat_property("items", 2, |item, index| {
+            item.int_property("x", index as i64);
+            item.comma();
+            item.int_property("y", index as i64);
+        });
+        assert_eq!(writer.seal(), r#"{"items":[{"x":0,"y":0},{"x":1,"y":1}]}"#);
+    }
+
+    #[test]
+    fn json_writer_max_depth() {
+        let mut json_writer = JSONWriter::new();
+        json_writer.int_property("flat", 1);
+        assert_eq!(json_writer.max_depth(), 1);
+        json_writer.array_of_strings("tags", &["a"]);
+        assert_eq!(json_writer.max_depth(), 2);
+
+        json_writer.start_object_property("outer");
+        json_writer.start_object_property("inner");
+        json_writer.end_object();
+        json_writer.end_object();
+        assert_eq!(json_writer.max_depth(), 3);
+
+        json_writer.repeat_property("samples", 2, |json_writer, _| {
+            json_writer.array_of_strings("frames", &["main"]);
+        });
+        assert_eq!(json_writer.max_depth(), 4);
+
+        json_writer
+            .serde_property("nested", &serde_json::json!([[["{["], {}]]))
+            .unwrap();
+        assert_eq!(json_writer.max_depth(), 4);
+        json_writer
+            .serde_property("nested", &serde_json::json!([[[[]]]]))
+            .unwrap();
+        assert_eq!(json_writer.max_depth(), 5);
+
+        // Reverted writes don't count.
+        json_writer.transaction(|json_writer| {
+            json_writer.object_from_serialize("deep", &serde_json::json!({"a": [[[[[]]]]]}));
+            None::<()>
+        });
+        assert_eq!(json_writer.max_depth(), 5);
+    }
+
+    #[test]
+    fn json_writer_count_properties() {
+        let mut json_writer = JSONWriter::new();
+        assert_eq!(json_writer.count_properties(), 0);
+        json_writer.int_property("a", 1);
+        json_writer.string_property("b", "2");
+        json_writer.start_object_property("c");
+        json_writer.null_property("d");
+        json_writer.end_object();
+        assert_eq!(json_writer.count_properties(), 4);
+        json_writer.repeat_property("e", 2, |item, index| {
+            item.int_property("index", index as i64)
+        });
+        assert_eq!(json_writer.count_properties(), 7);
+        // Properties that fail to serialize aren't written, nor counted.
+        let map = HashMap::from([((1, 2), 3)]);
+        assert!(json_writer.serde_property("f", &map).is_err());
+        assert_eq!(json_writer.count_properties(), 7);
+
+        let mut recorder = RecordingWriter::new();
+        ExampleMarker {
+            field1: 1,
+            field2: "two".to_string(),
+            field3: None,
+        }
+        .stream_json_marker_data(&mut recorder);
+        assert_eq!(recorder.count_properties(), 3);
+    }
+
+    #[test]
+    fn json_writer_seal() {
+        assert_eq!(JSONWriter::new().seal(), "{}");
+
+        let mut json_writer = JSONWriter::new();
+        json_writer.start_object_property("outer");
+        json_writer.start_object_property("inner");
+        json_writer.int_property("size", 4);
+        json_writer.end_object();
+        json_writer.start_object_property("open");
+        let json = json_writer.seal();
+        assert_eq!(json, r#"{"outer":{"inner":{"size":4},"open":{}}}"#);
+        assert!(serde_json::from_str::<serde_json::Value>(&json).is_ok());
+    }
+
+    #[test]
+    fn json_writer_len() {
+        let mut json_writer = JSONWriter::new();
+        assert!(json_writer.is_empty());
+        assert_eq!(json_writer.len(), 1);
+        json_writer.int_property("size", 4);
+        assert!(!json_writer.is_empty());
+        assert_eq!(json_writer.len(), json_writer.as_str().len());
+
+        let mut json_writer = JSONWriter::new();
+        json_writer.start_object_property("nested");
+        assert!(!json_writer.is_empty());
+    }
+
+    #[test]
+    fn json_writer_debug() {
+        let mut json_writer = JSONWriter::new();
+        json_writer.int_property("size", 4);
+        assert_eq!(
+            format!("{:?}", json_writer),
+            r#"JSONWriter { buffer: "{\"size\":4", .. }"#
+        );
+    }
+
+    #[test]
+    fn json_writer_pointer() {
+        let json_writer = JSONWriter::new();
+        let other = JSONWriter::new();
+        let address = format!("{:p}", json_writer);
+        assert_eq!(address, format!("{:p}", &json_writer as *const JSONWriter));
+        assert_ne!(address, format!("{:p}", other));
+    }
+
+    #[test]
+    fn json_writer_as_str() {
+        let mut json_writer = JSONWriter::new();
+        json_writer.string_property("path", "/tmp/file");
+        assert_eq!(json_writer.as_str(), r#"{"path":"/tmp/file""#);
+        json_writer.int_property("size", 4);
+        assert_eq!(json_writer.as_str(), r#"{"path":"/tmp/file","size":4"#);
+        assert_eq!(json_writer.as_str().to_string(), json_writer.take_string());
+    }
+
+    #[test]
+    fn json_writer_repeat_property() {
+        let samples = [(1, "idle"), (2, "busy"), (3, "idle")];
+        let mut recorder = RecordingWriter::new();
+        recorder.int_property("count", 3);
+        recorder.repeat_property("samples", samples.len(), |item, index| {
+            item.int_property("time", samples[index].0);
+            item.unique_string_property("state", samples[index].1);
+        });
+        recorder.repeat_property("empty", 0, |_, _| unreachable!());
+        assert_eq!(
+            recorder.as_str(),
+            concat!(
+                r#"{"count":3,"samples":[{"time":1,"state":0},"#,
+                r#"{"time":2,"state":1},{"time":3,"state":0}],"empty":[]"#
+            )
+        );
+        assert_eq!(recorder.unique_strings().get(1), Some("busy"));
+
+        let sample = |time, state: &str| {
+            PropertyValue::Object(vec![
+                ("time".to_string(), PropertyValue::Int(time)),
+                (
+                    "state".to_string(),
+                    PropertyValue::UniqueStr(state.to_string()),
+                ),
+            ])
+        };
+        assert_eq!(
+            recorder.get("samples"),
+            Some(&PropertyValue::Array(vec![
+                sample(1, "idle"),
+                sample(2, "busy"),
+                sample(3, "idle"),
+            ]))
+        );
+        assert_eq!(recorder.get("empty"), Some(&PropertyValue::Array(vec![])));
+    }
+
+    #[test]
+    fn json_writer_object_from_serialize() {
+        #[derive(Serialize)]
+        struct Request<'a> {
+            method: &'a str,
+            status: u16,
+        }
+
+        let mut recorder = RecordingWriter::new();
+        recorder.object_from_serialize(
+            "request",
+            &Request {
+                method: "GET",
+                status: 200,
+            },
+        );
+        recorder.object_from_serialize("headers", &HashMap::from([("Accept", "*/*")]));
+        // Values that aren't objects are written as empty objects.
+        recorder.object_from_serialize("count", &3);
+        recorder.object_from_serialize("invalid", &HashMap::from([((1, 2), 3)]));
+        recorder.int_property("after", 1);
+        assert_eq!(
+            recorder.as_str(),
+            concat!(
+                r#"{"request":{"method":"GET","status":200},"headers":{"Accept":"*/*"},"#,
+                r#""count":{},"invalid":{},"after":1"#
+            )
+        );
+        assert_eq!(
+            recorder.get("request"),
+            Some(&PropertyValue::Object(vec![
+                ("method".to_string(), PropertyValue::Str("GET".to_string())),
+                ("status".to_string(), PropertyValue::Int(200)),
+            ]))
+        );
+        assert_eq!(recorder.get("count"), Some(&PropertyValue::Object(vec![])));
+    }
+
+    #[test]
+    fn json_writer_property_group() {
+        let mut recorder = RecordingWriter::new();
+        recorder.property_group("timing_", |w| {
+            w.float_property("start", 1.5);
+            w.property_group("io_", |w| w.int_property("wait", 2));
+            w.start_object_property("phases");
+            w.end_object();
+            // The properties of nested objects aren't prefixed, except by
+            // groups started in them.
+            w.start_object_property("steps");
+            w.int_property("parse", 3);
+            w.property_group("gc_", |w| w.int_property("minor", 1));
+            w.end_object();
+            w.repeat_property("runs", 1, |w, _| w.int_property("index", 0));
+            w.float_property("end", 4.0);
+        });
+        recorder.int_property("count", 1);
+        assert_eq!(
+            recorder.as_str(),
+            concat!(
+                r#"{"timing_start":1.5,"timing_io_wait":2,"timing_phases":{},"#,
+                r#""timing_steps":{"parse":3,"gc_minor":1},"timing_runs":[{"index":0}],"#,
+                r#""timing_end":4,"count":1"#
+            )
+        );
+        recorder.assert_float("timing_start", 1.5);
+        recorder.assert_int("timing_io_wait", 2);
+        assert_eq!(
+            recorder.get("timing_phases"),
+            Some(&PropertyValue::Object(vec![]))
+        );
+        assert_eq!(
+            recorder.get("timing_steps"),
+            Some(&PropertyValue::Object(vec![
+                ("parse".to_string(), PropertyValue::Int(3)),
+                ("gc_minor".to_string(), PropertyValue::Int(1)),
+            ]))
+        );
+        recorder.assert_int("count", 1);
+    }
+
+    #[test]
+    fn json_writer_conditional_null_property() {
+        let mut json_writer = JSONWriter::new();
+        for (name, value) in [("present", Some(3)), ("absent", None)] {
+            json_writer.conditional_null_property(name, value.is_some());
+            if let Some(value) = value {
+                json_writer.int_property(name, value);
+            }
+        }
+        assert_eq!(json_writer.as_str(), r#"{"present":3,"absent":null"#);
+    }
+
+    #[test]
+    fn json_writer_property_or_null() {
+        let mut json_writer = JSONWriter::new();
+        for (name, value) in [("present", Some(3)), ("absent", None)] {
+            json_writer.property_or_null(name, value, |json_writer, value| {
+                json_writer.int_property(name, value)
+            });
+        }
+        json_writer.property_or_null("url", Some("https://example.com"), |json_writer, url| {
+            json_writer.string_property("url", url)
+        });
+        assert_eq!(
+            json_writer.as_str(),
+            r#"{"present":3,"absent":null,"url":"https://example.com""#
+        );
+    }
+
+    #[test]
+    fn json_writer_array_of_strings() {
+        let mut recorder = RecordingWriter::new();
+        recorder.array_of_strings("tags", &["a", "b\"", "\n"]);
+        recorder.array_of_strings("empty", &[]);
+        assert_eq!(recorder.as_str(), r#"{"tags":["a","b\"","\n"],"empty":[]"#);
+        assert_eq!(
+            recorder.get("tags"),
+            Some(&PropertyValue::Array(vec![
+                PropertyValue::Str("a".to_string()),
+                PropertyValue::Str("b\"".to_string()),
+                PropertyValue::Str("\n".to_string()),
+            ]))
+        );
+
+        // The derive uses it for `Vec<String>` fields with a string format.
+        let marker = EndpointMarker {
+            endpoint: Endpoint {
+                host: "example.com".to_string(),
+                port: 80,
+            },
+            fallback: None,
+            tags: vec!["a".to_string(), "b".to_string()],
+        };
+        let mut json_writer = JSONWriter::new();
+        marker.stream_json_marker_data(&mut json_writer);
+        assert!(json_writer.as_str().ends_with(r#""tags":["a","b"]"#));
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    pub struct HeadersMarker {
+        headers: HashMap<String, String>,
+        counts: std::collections::BTreeMap<String, u64>,
+        #[format(Percentage)]
+        ratios: Option<HashMap<String, f64>>,
+        timings: std::collections::BTreeMap<String, f32>,
+    }
+
+    #[test]
+    fn map_fields() {
+        let marker = HeadersMarker {
+            headers: HashMap::from([
+                ("Content-Type".to_string(), "text/html".to_string()),
+                ("Accept".to_string(), "*/*".to_string()),
+                ("X-\"Quoted\"\n".to_string(), "a\\b".to_string()),
+            ]),
+            counts: [("b".to_string(), 2), ("a".to_string(), 1)].into(),
+            ratios: Some(HashMap::new()),
+            timings: Default::default(),
+        };
+        let mut json_writer = JSONWriter::new();
+        marker.stream_json_marker_data(&mut json_writer);
+        assert_eq!(
+            json_writer.take_string(),
+            concat!(
+                r#"{"headers":{"Accept":"*/*","Content-Type":"text/html","X-\"Quoted\"\n":"a\\b"},"#,
+                r#""counts":{"a":1,"b":2},"ratios":{},"timings":{}"#
+            )
+        );
+
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(
+            HeadersMarker::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+
+        let formats: Vec<_> = HeadersMarker::marker_type_display()
+            .rows()
+            .iter()
+            .map(|row| match row {
+                SchemaRow::Dynamic { format, .. } => Some(*format),
+                SchemaRow::Static { .. } => None,
+            })
+            .collect();
+        assert_eq!(
+            formats,
+            vec![
+                Some(Format::String),
+                Some(Format::Integer),
+                Some(Format::Percentage),
+                Some(Format::Decimal),
+            ]
+        );
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    pub struct EventMarker {
+        #[format(UniqueString)]
+        r#type: String,
+    }
+
+    #[test]
+    fn field_key_constants() {
+        assert_eq!(RenamedMarker::FIELD_KEY_INNER_WINDOW_ID, "innerWindowID");
+        assert_eq!(RenamedMarker::FIELD_KEY_CATEGORY, "category");
+        assert_eq!(RenamedMarker::FIELD_KEY_COUNT, "count");
+        assert_eq!(OverviewMarker::FIELD_KEY_NAME, "label");
+        assert_eq!(EventMarker::FIELD_KEY_TYPE, "type");
+
+        // Flattened fields use the constants of the flattened type.
+        assert_eq!(ConnectionMarker::FIELD_KEY_PROTOCOL, "protocol");
+        assert_eq!(ConnectionInfo::FIELD_KEY_LATENCY, "latency");
+        assert_eq!(NetworkInfo::FIELD_KEY_HOST, "host");
+
+        // The constants match the keys of the streamed JSON.
+        let mut json_writer = JSONWriter::new();
+        json_writer.int_property(RenamedMarker::FIELD_KEY_INNER_WINDOW_ID, 7);
+        json_writer.null_property(RenamedMarker::FIELD_KEY_CATEGORY);
+        json_writer.string_property(RenamedMarker::FIELD_KEY_COUNT, "2");
+        let marker = RenamedMarker {
+            inner_window_id: 7,
+            category: None,
+            count: 2,
+        };
+        let mut expected = JSONWriter::new();
+        marker.stream_json_marker_data(&mut expected);
+        assert_eq!(json_writer.take_string(), expected.take_string());
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+    pub struct Pid(u32);
+
+    impl MarkerFieldValue for Pid {
+        const FORMAT: Format = Format::Integer;
+        fn write(&self, key: &str, json_writer: &mut JSONWriter) {
+            self.0.write(key, json_writer);
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    pub struct SpawnMarker {
+        parent: Pid,
+        child: Option<Pid>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart, TimelineOverview)]
+    pub struct ProcessCountMarker {
+        #[graph(Line)]
+        pid: Pid,
+        #[format(Integer)]
+        threads: u32,
+    }
+
+    #[test]
+    fn marker_field_value() {
+        let marker = SpawnMarker {
+            parent: Pid(1),
+            child: Some(Pid(42)),
+        };
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(json, serde_json::json!({ "parent": 1, "child": 42 }));
+        assert_eq!(
+            SpawnMarker::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+
+        let marker = SpawnMarker {
+            parent: Pid(1),
+            child: None,
+        };
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(json, serde_json::json!({ "parent": 1, "child": null }));
+        assert_eq!(
+            SpawnMarker::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+
+        let marker = ProcessCountMarker {
+            pid: Pid(7),
+            threads: 3,
+        };
+        let (json, _) = stream_marker(&marker);
+        assert_eq!(json, serde_json::json!({ "pid": 7, "threads": 3 }));
+        assert_eq!(marker.field_value_as_string("pid").as_deref(), Some("7"));
+
+        for schema in [
+            SpawnMarker::marker_type_display(),
+            ProcessCountMarker::marker_type_display(),
+        ] {
+            assert!(schema.rows().iter().any(|row| matches!(
+                row,
+                SchemaRow::Dynamic {
+                    format: Format::Integer,
+                    ..
+                }
+            )));
+        }
+        assert_eq!(
+            ProcessCountMarker::marker_type_display().graphs(),
+            &[SchemaGraph {
+                key: "pid".to_string(),
+                graph_type: GraphType::Line,
+                color: None,
+            }]
+        );
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerTable)]
+    pub struct MemoizedMarker {
+        #[format(Integer)]
+        count: u32,
+    }
+
+    /// A generic marker whose schemas are only built by `memoized_schema`,
+    /// which counts them.
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    #[serde(bound = "")]
+    pub struct MemoizedSampleMarker<T: MarkerFieldValue + Serialize + DeserializeOwned> {
+        #[format(UniqueString)]
+        name: String,
+        value: T,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    // The bounds of the parameters are declared on the struct, so that the
+    // `ProfilerMarker` implementation has them too.
+    #[serde(bound = "")]
+    #[marker_v2]
+    pub struct SampleMarker<T: MarkerFieldValue + Serialize + DeserializeOwned> {
+        #[format(UniqueString)]
+        name: String,
+        value: T,
+    }
+
+    /// Run `f`, and return the number of schemas created while it ran. Schemas
+    /// are memoized for all threads, so `f` should only use marker types that
+    /// no other test uses.
+    fn schemas_created(f: impl FnOnce()) -> usize {
+        let before = crate::SCHEMAS_CREATED.with(|created| created.get());
+        f();
+        crate::SCHEMAS_CREATED.with(|created| created.get()) - before
+    }
+
+    #[test]
+    fn memoized_schema() {
+        let created = schemas_created(|| {
+            let first = MemoizedMarker::marker_type_display();
+            let second = MemoizedMarker::marker_type_display();
+            assert_eq!(first, second);
+            assert!(std::ptr::eq(
+                MemoizedMarker::marker_type_display_ref(),
+                MemoizedMarker::marker_type_display_ref()
+            ));
+            assert_eq!(MemoizedMarker::marker_type_display_ref(), &first);
+        });
+        assert_eq!(created, 1);
+
+        // Each instantiation of a generic marker has a schema of its own.
+        let created = schemas_created(|| {
+            for _ in 0..2 {
+                let integer = MemoizedSampleMarker::<u64>::marker_type_display_ref();
+                let decimal = MemoizedSampleMarker::<f64>::marker_type_display_ref();
+                assert_eq!(
+                    integer.rows()[1],
+                    SchemaRow::Dynamic {
+                        key: "value".to_string(),
+                        label: Some("value".to_string()),
+                        format: Format::Integer,
+                        searchable: Searchable::NotSearchable,
+                    }
+                );
+                assert_eq!(
+                    decimal.rows()[1],
+                    SchemaRow::Dynamic {
+                        key: "value".to_string(),
+                        label: Some("value".to_string()),
+                        format: Format::Decimal,
+                        searchable: Searchable::NotSearchable,
+                    }
+                );
+            }
+        });
+        assert_eq!(created, 2);
+
+        let marker = SampleMarker {
+            name: "sample".to_string(),
+            value: 2.5,
+        };
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(
+            SampleMarker::<f64>::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+    }
+
+    #[test]
+    fn schema_json() {
+        let json = ExampleMarker::schema_json();
+        assert!(std::ptr::eq(json, ExampleMarker::schema_json()));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(json).unwrap(),
+            ExampleMarker::marker_type_display().to_json("ExampleMarker")
+        );
+
+        let integer = SampleMarker::<u64>::schema_json();
+        let decimal = SampleMarker::<f64>::schema_json();
+        assert!(std::ptr::eq(integer, SampleMarker::<u64>::schema_json()));
+        assert!(integer.contains(r#""format":"integer""#));
+        assert!(decimal.contains(r#""format":"decimal""#));
+    }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    fn schema_checksum() {
+        use sha2::Digest;
+        let checksum = ExampleMarker::schema_checksum();
+        assert_eq!(
+            checksum,
+            <[u8; 32]>::from(sha2::Sha256::digest(ExampleMarker::schema_json()))
+        );
+        assert_eq!(checksum, ExampleMarker::schema_checksum());
+        assert_ne!(checksum, OverviewMarker::schema_checksum());
+        assert_ne!(
+            SampleMarker::<u64>::schema_checksum(),
+            SampleMarker::<f64>::schema_checksum()
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn schema_as_toml() {
+        let toml = OverviewMarker::schema_as_toml();
+        assert!(toml.contains("name = \"OverviewMarker\""), "{}", toml);
+        assert!(toml.contains("[[data]]"), "{}", toml);
+        assert_eq!(
+            MarkerSchema::from_toml(&toml).unwrap(),
+            OverviewMarker::marker_type_display()
+        );
+        assert_eq!(
+            MarkerSchema::from_toml(&MemoryMarker::schema_as_toml()).unwrap(),
+            MemoryMarker::marker_type_display()
+        );
+        assert!(MarkerSchema::from_toml("display = [\"nowhere\"]\ndata = []").is_err());
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn schema_proto() {
+        use crate::proto::{SchemaProto, SchemaRowProto};
+        let marker = ExampleMarker {
+            field1: 12,
+            field2: "Hello, world!".to_string(),
+            field3: None,
+        };
+        let message = marker.to_schema_proto();
+        assert_eq!(message.name, "ExampleMarker");
+        assert_eq!(
+            message.locations,
+            vec!["marker-chart", "marker-table", "timeline-ipc"]
+        );
+        assert_eq!(message.rows.len(), 3);
+        assert_eq!(message.rows[0].key, "field1");
+        assert_eq!(message.rows[0].format, "integer");
+        assert!(message.rows[0].searchable);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&message.data).unwrap(),
+            stream_marker(&marker).0
+        );
+        assert_eq!(message, SchemaProto::from_marker(&marker));
+        assert_eq!(
+            SchemaProto::decode(&message.encode_to_vec()).unwrap(),
+            message
+        );
+
+        // Generic markers use the provided method.
+        let marker = SampleMarker {
+            name: "cpu".to_string(),
+            value: 3u64,
+        };
+        let message = marker.to_schema_proto();
+        assert_eq!(message.unique_strings, vec!["cpu"]);
+        assert_eq!(
+            SchemaProto::decode(&message.encode_to_vec()).unwrap(),
+            message
+        );
+
+        let row = SchemaRowProto {
+            key: "a".to_string(),
+            searchable: true,
+            ..Default::default()
+        };
+        assert_eq!(row.encode_to_vec(), [0x0a, 1, b'a', 0x20, 1]);
+        // Unknown fields are skipped, truncated ones are errors.
+        assert_eq!(
+            SchemaRowProto::decode(&[0x30, 5, 0x0a, 1, b'a', 0x20, 1]).unwrap(),
+            row
+        );
+        assert!(SchemaRowProto::decode(&[0x0a, 2, b'a']).is_err());
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    #[marker_scalable]
+    pub struct TransferMarker {
+        #[format(Pid)]
+        pid: u32,
+        #[format(Bytes)]
+        size: u64,
+        #[format(Milliseconds)]
+        duration: f64,
+        #[format(Percentage)]
+        ratio: Option<f32>,
+        #[format(Integer)]
+        retries: Option<u8>,
+        #[format(String)]
+        url: String,
+        cached: bool,
+    }
+
+    #[test]
+    fn scalable_marker() {
+        let marker = TransferMarker {
+            pid: 1234,
+            size: 4096,
+            duration: 1.5,
+            ratio: Some(0.25),
+            retries: Some(100),
+            url: "https://example.com".to_string(),
+            cached: true,
+        };
+        assert_eq!(
+            marker * 3.0,
+            TransferMarker {
+                // Identifiers are kept as they are.
+                pid: 1234,
+                size: 12288,
+                duration: 4.5,
+                ratio: Some(0.75),
+                // Integers saturate at the bounds of their type.
+                retries: Some(255),
+                url: "https://example.com".to_string(),
+                cached: true,
+            }
+        );
+
+        let marker = TransferMarker {
+            pid: 1,
+            size: 1000,
+            duration: 2.0,
+            ratio: None,
+            retries: None,
+            url: String::new(),
+            cached: false,
+        };
+        let scaled = marker * 0.001;
+        assert_eq!((scaled.size, scaled.duration), (1, 0.002));
+        assert_eq!((scaled.ratio, scaled.retries), (None, None));
+
+        // Whole factors keep the precision of integers above 2^53.
+        let marker = TransferMarker {
+            pid: 1,
+            size: (1 << 60) + 1,
+            duration: 0.0,
+            ratio: None,
+            retries: Some(10),
+            url: String::new(),
+            cached: false,
+        };
+        let scaled = marker * 4.0;
+        assert_eq!(scaled.size, (1 << 62) + 4);
+        assert_eq!((scaled * 16.0).size, u64::MAX);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    #[marker_diffable]
+    pub struct CounterMarker {
+        #[format(Pid)]
+        pid: u32,
+        #[format(Integer)]
+        allocations: u32,
+        #[format(Bytes)]
+        bytes: Option<i64>,
+        #[format(Milliseconds)]
+        gc_time: f64,
+    }
+
+    #[test]
+    fn diffable_marker() {
+        let previous = CounterMarker {
+            pid: 1,
+            allocations: 10,
+            bytes: Some(4096),
+            gc_time: 1.5,
+        };
+        let current = CounterMarker {
+            pid: 1,
+            allocations: 25,
+            bytes: Some(1024),
+            gc_time: 4.0,
+        };
+        assert_eq!(
+            current - previous,
+            CounterMarker {
+                pid: 1,
+                allocations: 15,
+                bytes: Some(-3072),
+                gc_time: 2.5,
+            }
+        );
+
+        let earlier = CounterMarker {
+            pid: 2,
+            allocations: 30,
+            bytes: Some(8),
+            gc_time: 0.0,
+        };
+        let later = CounterMarker {
+            pid: 2,
+            allocations: 20,
+            bytes: None,
+            gc_time: 0.0,
+        };
+        // Integers saturate, and `None` on either side keeps the left-hand side.
+        let delta = later - earlier;
+        assert_eq!((delta.allocations, delta.bytes), (0, None));
+    }
+
+    mod downloads {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        pub struct Download {
+            pub url: String,
+            pub size: u64,
+            pub resumed: Option<bool>,
+        }
+    }
+
+    /// Describes the fields of `downloads::Download`, which can't be annotated.
+    #[derive(ProfilerMarker)]
+    #[profiler_impl_for = "downloads::Download"]
+    #[marker_display(MarkerTable)]
+    struct DownloadMarkerConfig {
+        #[format(Url)]
+        #[searchable]
+        url: String,
+        #[format(Bytes)]
+        size: u64,
+        resumed: Option<bool>,
+    }
+
+    #[test]
+    fn profiler_impl_for() {
+        assert_eq!(downloads::Download::marker_type_name(), "Download");
+        assert_eq!(DownloadMarkerConfig::FIELD_KEY_SIZE, "size");
+
+        let schema = downloads::Download::marker_type_display();
+        assert_eq!(schema.locations(), &[Location::MarkerTable]);
+        assert_eq!(
+            schema.rows()[0],
+            SchemaRow::Dynamic {
+                key: "url".to_string(),
+                label: Some("url".to_string()),
+                format: Format::Url,
+                searchable: Searchable::Searchable,
+            }
+        );
+
+        let marker = downloads::Download {
+            url: "https://example.com/file".to_string(),
+            size: 2048,
+            resumed: Some(true),
+        };
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "url": "https://example.com/file",
+                "size": 2048,
+                "resumed": "true",
+            })
+        );
+        assert_eq!(
+            marker.field_value_as_string("size").as_deref(),
+            Some("2048")
+        );
+        assert_eq!(
+            downloads::Download::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    pub struct TaskMarker {
+        #[format(Flow)]
+        flow: u64,
+        #[format(TerminatingFlow)]
+        terminating_flow: Option<u64>,
+        #[format(Pid)]
+        pid: u32,
+        #[format(Tid)]
+        tid: u64,
+        #[format(List)]
+        stages: Vec<String>,
+    }
+
+    #[test]
+    fn flow_pid_tid_list_formats() {
+        let schema = TaskMarker::marker_type_display();
+        let formats: Vec<_> = schema
+            .rows()
+            .iter()
+            .map(|row| match row {
+                SchemaRow::Dynamic { format, .. } => Some(*format),
+                SchemaRow::Static { .. } => None,
+            })
+            .collect();
+        assert_eq!(
+            formats,
+            vec![
+                Some(Format::Flow),
+                Some(Format::TerminatingFlow),
+                Some(Format::Pid),
+                Some(Format::Tid),
+                Some(Format::List),
+            ]
+        );
+        let json_formats: Vec<_> = schema.to_json("Task")["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["format"].clone())
+            .collect();
+        assert_eq!(
+            json_formats,
+            vec!["flow-id", "terminating-flow-id", "pid", "tid", "list"]
+        );
+
+        let marker = TaskMarker {
+            flow: u64::MAX,
+            terminating_flow: None,
+            pid: 1234,
+            tid: 5678,
+            stages: vec!["parse".to_string(), "layout \"1\"".to_string()],
+        };
+        let mut json_writer = JSONWriter::new();
+        marker.stream_json_marker_data(&mut json_writer);
+        assert_eq!(
+            json_writer.take_string(),
+            concat!(
+                r#"{"flow":18446744073709551615,"terminating_flow":null,"pid":1234,"#,
+                r#""tid":5678,"stages":["parse","layout \"1\""]"#
+            )
+        );
+        assert_eq!(
+            marker.field_value_as_string("flow").as_deref(),
+            Some("18446744073709551615")
+        );
+        assert_eq!(
+            marker.field_value_as_string("stages").as_deref(),
+            Some(r#"["parse","layout \"1\""]"#)
+        );
+
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(
+            TaskMarker::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+    }
+
+    #[test]
+    fn estimated_payload_bytes() {
+        let marker = ExampleMarker {
+            field1: 12,
+            field2: "Hello, world!".to_string(),
+            field3: None,
+        };
+        // `field1` and `field3` are numeric, and `field2` is a 13 byte string.
+        assert_eq!(
+            marker.estimated_payload_bytes(),
+            2 + (10 + 20) + (10 + 13 + 4) + (10 + 20)
+        );
+        let (payload, _) = stream_marker(&marker);
+        assert!(marker.estimated_payload_bytes() >= payload.to_string().len());
+    }
+
+    #[test]
+    fn schema_row_lookup() {
+        let mut schema = MarkerSchema::new(&[Location::MarkerChart]);
+        schema
+            .add_static_label_value("Note", "value")
+            .add_key_format("a", Format::Integer)
+            .add_key_format("b", Format::String);
+
+        assert_eq!(schema.row_index_of("b"), Some(2));
+        assert_eq!(schema.row_index_of("Note"), None);
+        assert_eq!(schema.row_index_of("c"), None);
+        let index = schema.row_index_of("a").unwrap();
+        assert_eq!(schema.row_at(index).and_then(SchemaRow::key), Some("a"));
+        assert_eq!(schema.row_at(3), None);
+
+        schema.add_key_label_format("c", "C Value", Format::Integer);
+        assert_eq!(
+            schema.key_label_pairs().collect::<Vec<_>>(),
+            vec![("c", "C Value")]
+        );
+        assert_eq!(
+            DocumentedMarker::marker_type_display()
+                .key_label_pairs()
+                .collect::<Vec<_>>(),
+            vec![
+                ("written", "Number of bytes written to disk"),
+                ("undocumented", "undocumented"),
+                ("overridden", "Explicit label"),
+            ]
+        );
+    }
+
+    #[test]
+    fn schema_capacity() {
+        let mut schema = MarkerSchema::new(&[Location::MarkerChart]);
+        assert_eq!(schema.capacity(), 0);
+        schema.reserve(3);
+        let capacity = schema.capacity();
+        assert!(capacity >= 3);
+        schema
+            .add_key_format("a", Format::Integer)
+            .add_key_format("b", Format::Integer)
+            .add_static_label_value("Note", "value");
+        assert_eq!(schema.capacity(), capacity);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    pub struct TextMarker {
+        text: String,
+    }
+
+    #[profiler_macros::marker_display(
+        MarkerTable,
+        TimelineOverview,
+        note = "Text markers are shown in the overview"
+    )]
+    impl ProfilerMarker for TextMarker {
+        fn marker_type_name() -> &'static str {
+            "Text"
+        }
+
+        fn marker_type_display() -> MarkerSchema {
+            let mut schema = MarkerSchema::new(&[]);
+            schema.add_key_format("text", Format::String);
+            schema
+        }
+
+        fn stream_json_marker_data(&self, json_writer: &mut JSONWriter) {
+            json_writer.string_property("text", &self.text);
+        }
+
+        fn from_json_marker_data(
+            json: &serde_json::Value,
+            unique_strings: &UniqueStrings,
+        ) -> Result<Self, MarkerError> {
+            let reader = crate::JSONReader::new(json, unique_strings)?;
+            Ok(TextMarker {
+                text: reader.property("text")?,
+            })
+        }
+
+        fn field_value_as_string(&self, key: &str) -> Option<String> {
+            (key == "text").then(|| self.text.clone())
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    pub struct EmptyMarker {}
+
+    #[profiler_macros::marker_display]
+    impl ProfilerMarker for EmptyMarker {
+        fn marker_type_name() -> &'static str {
+            "Empty"
+        }
+
+        fn stream_json_marker_data(&self, _json_writer: &mut JSONWriter) {}
+
+        fn from_json_marker_data(
+            _json: &serde_json::Value,
+            _unique_strings: &UniqueStrings,
+        ) -> Result<Self, MarkerError> {
+            Ok(EmptyMarker {})
+        }
+
+        fn field_value_as_string(&self, _key: &str) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn marker_display_on_impl() {
+        let schema = TextMarker::marker_type_display();
+        assert_eq!(
+            schema.locations(),
+            &[Location::MarkerTable, Location::TimelineOverview]
+        );
+        assert_eq!(
+            schema.location_note(Location::TimelineOverview),
+            Some("Text markers are shown in the overview")
+        );
+        assert_eq!(schema.rows().len(), 1);
+
+        let schema = EmptyMarker::marker_type_display();
+        assert_eq!(schema.locations(), &[Location::MarkerChart]);
+        assert_eq!(schema.rows(), &[]);
+        assert_eq!(schema.to_json("Empty")["chartLabel"], "Name: {marker.name}");
+    }
+
+    #[test]
+    fn schema_merge() {
+        let mut schema = MarkerSchema::new(&[Location::MarkerChart]);
+        schema
+            .set_chart_label("{marker.data.a}")
+            .add_key_format("a", Format::Integer)
+            .add_graph_line("a", GraphType::Line);
+        let mut other = MarkerSchema::new(&[Location::MarkerChart, Location::TimelineMemory]);
+        other
+            .set_location_note(Location::TimelineMemory, "Memory")
+            .set_chart_label("{marker.data.b}")
+            .set_tooltip_label("{marker.data.b}")
+            .add_key_format("a", Format::String)
+            .add_key_format("b", Format::Bytes)
+            .add_static_label_value("Note", "value")
+            .add_graph_line("a", GraphType::Bar)
+            .add_graph_line("b", GraphType::Bar);
+
+        let mut merged = schema.clone();
+        merged.merge(&other);
+        assert_eq!(MarkerSchema::union(&schema, &other), merged);
+        schema += &other;
+        assert_eq!(schema, merged);
+
+        assert_eq!(
+            schema.locations(),
+            &[Location::MarkerChart, Location::TimelineMemory]
+        );
+        assert_eq!(
+            schema.location_note(Location::TimelineMemory),
+            Some("Memory")
+        );
+        let json = schema.to_json("Merged");
+        assert_eq!(json["chartLabel"], "{marker.data.a}");
+        assert_eq!(json["tooltipLabel"], "{marker.data.b}");
+        assert_eq!(
+            json["data"],
+            serde_json::json!([
+                { "key": "a", "format": "integer" },
+                { "key": "b", "format": "bytes" },
+                { "label": "Note", "value": "value" },
+            ])
+        );
+        assert_eq!(
+            json["graphs"],
+            serde_json::json!([
+                { "key": "a", "type": "line" },
+                { "key": "b", "type": "bar" },
+            ])
+        );
+    }
+
+    #[test]
+    fn schema_diff_against_json() {
+        assert_eq!(
+            ExampleMarker::schema_diff_against_json(ExampleMarker::schema_json()),
+            Vec::<String>::new()
+        );
+
+        let mut baseline = ExampleMarker::marker_type_display();
+        baseline
+            .set_locations(&[Location::MarkerChart, Location::MarkerTable])
+            .set_tooltip_label("{marker.data.field1}");
+        let mut baseline = baseline.to_json(ExampleMarker::marker_type_name());
+        let rows = baseline["data"].as_array_mut().unwrap();
+        rows.reverse();
+        rows[0]["format"] = "url".into();
+        rows.push(serde_json::json!({ "key": "removed", "format": "integer" }));
+        rows.retain(|row| row["key"] != "field1");
+
+        assert_eq!(
+            ExampleMarker::schema_diff_against_json(&baseline.to_string()),
+            vec![
+                r#"display changed from ["marker-chart","marker-table"] to ["marker-chart","marker-table","timeline-ipc"]"#,
+                r#"tooltipLabel "{marker.data.field1}" was removed"#,
+                r#"row "field3": format changed from "url" to "integer""#,
+                r#"row "removed" was removed"#,
+                r#"row "field1" was added"#,
+            ]
+        );
+        assert_eq!(ExampleMarker::schema_diff_against_json("{").len(), 1);
+    }
+
+    #[test]
+    fn type_url() {
+        assert_eq!(ExampleMarker::type_url(), "example-marker");
+        assert_eq!(DiskIOMarker::type_url(), "disk-io-marker");
+        assert_eq!(SampleMarker::<u64>::type_url(), "sample-marker");
+        // Hand-written implementations use the runtime conversion.
+        assert_eq!(TextMarker::type_url(), "text");
+        assert!(std::ptr::eq(TextMarker::type_url(), TextMarker::type_url()));
+
+        let names = [
+            ("HTTP2RequestMarker", "http2-request-marker"),
+            ("IPC_Message", "ipc-message"),
+            ("snake_case__name_", "snake-case-name"),
+            ("Ünicode Marker", "%C3%BCnicode-marker"),
+            ("straßeMARKER", "stra%C3%9Fe-marker"),
+            ("ÉtéMarker", "%C3%A9t%C3%A9-marker"),
+            ("Marker\u{1F600}", "marker"),
+        ];
+        for (name, url) in names {
+            assert_eq!(crate::type_url_from_name(name), url);
+        }
+        // Derived markers convert their name at runtime, the same way.
+        assert_eq!(ÜberMarker::type_url(), "%C3%BCber-marker");
+    }
+
+    #[derive(Debug, Serialize, Deserialize, ProfilerMarker)]
+    pub struct ÜberMarker {
+        #[format(Integer)]
+        count: u32,
+    }
+
+    #[test]
+    fn schema_from_str() {
+        let schemas = [
+            ExampleMarker::marker_type_display(),
+            OverviewMarker::marker_type_display(),
+            MemoryMarker::marker_type_display(),
+            TextMarker::marker_type_display(),
+            MarkerSchema::new_with_special_frontend_location(),
+        ];
+        for schema in schemas {
+            let json = schema.to_json("Marker").to_string();
+            assert_eq!(json.parse::<MarkerSchema>().unwrap(), schema);
+        }
+
+        let mut schema = MarkerSchema::new(&[Location::TimelineMemory, Location::MarkerChart]);
+        schema
+            .set_location_note(Location::TimelineMemory, "Memory")
+            .set_location_note(Location::MarkerChart, "Chart")
+            .set_all_labels("{marker.name}")
+            .add_static_label_value("Note", "value")
+            .add_key_label_format_searchable("a", "A", Format::Flow, Searchable::Searchable)
+            .add_graph_line_with_color("a", GraphType::FilledLine, GraphColor::Teal);
+        let json = schema.to_json("Marker").to_string();
+        assert_eq!(json.parse::<MarkerSchema>().unwrap(), schema);
+
+        let errors = [
+            ("[]", "expected a schema object, found []"),
+            (
+                r#"{"display":["nowhere"],"data":[]}"#,
+                r#"unknown location "nowhere""#,
+            ),
+            (
+                r#"{"display":[],"data":[{"key":"a","format":"hex"}]}"#,
+                r#"unknown format "hex""#,
+            ),
+            (
+                r#"{"display":[],"data":[{"key":"a"}]}"#,
+                r#"missing "format" in {"key":"a"}"#,
+            ),
+            (
+                r#"{"display":[],"data":[],"graphs":[{"key":"a","type":"pie"}]}"#,
+                r#"unknown graph type "pie""#,
+            ),
+        ];
+        for (json, message) in errors {
+            let error = json.parse::<MarkerSchema>().unwrap_err();
+            assert_eq!(error.to_string(), message);
+        }
+        assert!("{".parse::<MarkerSchema>().unwrap_err().is_eof());
+    }
+
+    #[test]
+    fn field_count() {
+        const EXAMPLE_FIELD_COUNT: usize = ExampleMarker::FIELD_COUNT;
+        assert_eq!(EXAMPLE_FIELD_COUNT, 3);
+        assert_eq!(ExampleMarker::field_count(), 3);
+        assert_eq!(
+            ExampleMarker::field_count(),
+            ExampleMarker::marker_type_display().rows().len()
+        );
+        assert_eq!(SampleMarker::<u64>::field_count(), 2);
+        assert_eq!(DownloadMarkerConfig::field_count(), 3);
+        // The key of a `count` field has a constant of its own.
+        assert_eq!(OverviewMarker::FIELD_KEY_COUNT, "count");
+        assert_eq!(OverviewMarker::FIELD_COUNT, 2);
+        assert_eq!(OverviewMarker::field_count(), 2);
+    }
+
+    #[test]
+    fn locations_const() {
+        const EXAMPLE_LOCATIONS: &[Location] = ExampleMarker::LOCATIONS;
+        assert_eq!(
+            EXAMPLE_LOCATIONS,
+            &[
+                Location::MarkerChart,
+                Location::MarkerTable,
+                Location::TimelineIPC
+            ]
+        );
+        assert_eq!(
+            ExampleMarker::LOCATIONS,
+            ExampleMarker::marker_type_display().locations()
+        );
+        assert_eq!(EventMarker::LOCATIONS, &[Location::MarkerChart]);
+        assert_eq!(SampleMarker::<u64>::LOCATIONS, &[Location::MarkerChart]);
+    }
+
+    #[test]
+    fn schema_with_builders() {
+        let built = MarkerSchema::new(&[Location::MarkerChart, Location::MarkerTable])
+            .with_chart_label("{marker.data.a}")
+            .with_tooltip_label("{marker.data.b}")
+            .with_table_label("{marker.name}")
+            .with_location_note(Location::MarkerTable, "Table")
+            .with_key_format("a", Format::Integer)
+            .with_key_label_format("b", "B", Format::String)
+            .with_key_format_searchable("c", Format::Url, Searchable::Searchable)
+            .with_key_label_format_searchable("d", "D", Format::Bytes, Searchable::Searchable)
+            .with_static_label_value("Note", "value");
+
+        let mut schema = MarkerSchema::new(&[Location::MarkerChart, Location::MarkerTable]);
+        schema
+            .set_chart_label("{marker.data.a}")
+            .set_tooltip_label("{marker.data.b}")
+            .set_table_label("{marker.name}")
+            .set_location_note(Location::MarkerTable, "Table")
+            .add_key_format("a", Format::Integer)
+            .add_key_label_format("b", "B", Format::String)
+            .add_key_format_searchable("c", Format::Url, Searchable::Searchable)
+            .add_key_label_format_searchable("d", "D", Format::Bytes, Searchable::Searchable)
+            .add_static_label_value("Note", "value");
+        assert_eq!(built, schema);
+
+        let all = MarkerSchema::new(&[]).with_all_labels("{marker.name}");
+        assert_eq!(all.to_json("All")["tableLabel"], "{marker.name}");
+    }
+
+    #[test]
+    fn schema_matches() {
+        let schema = ExampleMarker::marker_type_display();
+        assert!(ExampleMarker::schema_matches(&schema));
+        let from_file: MarkerSchema = ExampleMarker::schema_json().parse().unwrap();
+        assert!(ExampleMarker::schema_matches(&from_file));
+
+        // Extra rows, other labels and locations don't matter.
+        let extended = MarkerSchema::new(&[Location::StackChart])
+            .with_key_label_format("field3", "Third", Format::Integer)
+            .with_key_format("field1", Format::Integer)
+            .with_key_format("field2", Format::String)
+            .with_key_format("extra", Format::Bytes);
+        assert!(ExampleMarker::schema_matches(&extended));
+
+        let missing = MarkerSchema::new(&[Location::MarkerChart])
+            .with_key_format("field1", Format::Integer)
+            .with_key_format("field2", Format::String);
+        assert!(!ExampleMarker::schema_matches(&missing));
+        let other_format = missing.clone().with_key_format("field3", Format::Decimal);
+        assert!(!ExampleMarker::schema_matches(&other_format));
+        assert!(!ExampleMarker::schema_matches(
+            &MarkerSchema::new_with_special_frontend_location()
+        ));
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    #[marker_doc = "A style flush, recorded each time styles are recomputed."]
+    #[marker_v2(version = 2, category = "Layout")]
+    pub struct StyleFlushMarker {
+        #[format(Integer)]
+        elements: u32,
+    }
+
+    #[test]
+    fn schema_description() {
+        let schema = StyleFlushMarker::marker_type_display();
+        let description = "A style flush, recorded each time styles are recomputed.";
+        assert_eq!(schema.description(), Some(description));
+        assert_eq!(schema.to_json("StyleFlush")["description"], description);
+        assert_eq!(
+            StyleFlushMarker::schema_json()
+                .parse::<MarkerSchema>()
+                .unwrap(),
+            schema
+        );
+        assert!(schema
+            .to_rust_literal()
+            .contains(&format!("schema.set_description({:?});", description)));
+        assert_eq!(ExampleMarker::marker_type_display().description(), None);
+
+        let mut merged = MarkerSchema::new(&[]).with_description("Kept");
+        merged.merge(&schema);
+        assert_eq!(merged.description(), Some("Kept"));
+    }
+
+    #[test]
+    fn marker_v2() {
+        assert_eq!(StyleFlushMarker::schema_version(), 2);
+        assert_eq!(StyleFlushMarker::category(), Some("Layout"));
+        assert_eq!(<StyleFlushMarker as ProfilerMarker_v2>::field_count(), 1);
+
+        // The defaults of the trait.
+        assert_eq!(SampleMarker::<u64>::schema_version(), 1);
+        assert_eq!(SampleMarker::<u64>::category(), None);
+        assert_eq!(
+            <SampleMarker<u64> as ProfilerMarker_v2>::field_count(),
+            SampleMarker::<u64>::field_count()
+        );
+    }
+
+    #[test]
+    fn schema_json_const() {
+        const JSON: &str = ExampleMarker::SCHEMA_JSON;
+        assert_eq!(JSON, ExampleMarker::schema_json());
+        assert_eq!(DiskIOMarker::SCHEMA_JSON, DiskIOMarker::schema_json());
+        assert_eq!(RenamedMarker::SCHEMA_JSON, RenamedMarker::schema_json());
+        // With graphs, and a note.
+        assert_eq!(MemoryMarker::SCHEMA_JSON, MemoryMarker::schema_json());
+        assert_eq!(OverviewMarker::SCHEMA_JSON, OverviewMarker::schema_json());
+        // With a description.
+        assert_eq!(
+            StyleFlushMarker::SCHEMA_JSON,
+            StyleFlushMarker::schema_json()
+        );
+    }
+
+    #[derive(Debug, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(
+        TimelineOverview,
+        note = "overview",
+        MarkerChart,
+        note = "chart",
+        TimelineFileIO,
+        note = "file \"io\"",
+        MarkerTable,
+        note = "table",
+        TimelineIPC,
+        note = "ipc",
+        StackChart,
+        note = "stack",
+        TimelineMemory,
+        note = "memory"
+    )]
+    pub struct EveryVariantMarker {
+        #[format(Url)]
+        url: String,
+        #[format(FilePath)]
+        path: String,
+        #[format(SanitizedString)]
+        sanitized: String,
+        #[searchable]
+        #[format(String)]
+        string: String,
+        #[format(UniqueString)]
+        unique: String,
+        #[format(Duration)]
+        #[graph(Line, color = Blue)]
+        duration: f64,
+        #[format(Time)]
+        #[graph(Bar, color = Green)]
+        time: f64,
+        #[format(Seconds)]
+        #[graph(FilledLine, color = Grey)]
+        seconds: f64,
+        #[format(Milliseconds)]
+        #[graph(Line, color = Ink)]
+        milliseconds: f64,
+        #[format(Microseconds)]
+        #[graph(Line, color = Magenta)]
+        microseconds: f64,
+        #[format(Nanoseconds)]
+        #[graph(Line, color = Orange)]
+        nanoseconds: u64,
+        #[format(Bytes)]
+        #[graph(Line, color = Purple)]
+        bytes: u64,
+        #[format(Percentage)]
+        #[graph(Line, color = Red)]
+        percentage: f64,
+        #[format(Integer)]
+        #[graph(Line, color = Teal)]
+        integer: i64,
+        #[format(Decimal)]
+        #[graph(Line, color = Yellow)]
+        decimal: f64,
+        #[format(Flow)]
+        #[graph(Line)]
+        flow: u64,
+        #[format(TerminatingFlow)]
+        terminating_flow: u64,
+        #[format(Pid)]
+        pid: u32,
+        #[format(Tid)]
+        tid: u32,
+        #[format(List)]
+        list: Vec<String>,
+    }
+
+    #[test]
+    fn schema_json_const_every_variant() {
+        // Every format, location (with a note), graph type and color.
+        assert_eq!(
+            EveryVariantMarker::SCHEMA_JSON,
+            EveryVariantMarker::schema_json()
+        );
+    }
+
+    #[test]
+    fn type_name_bytes() {
+        const NAME: &[u8] = ExampleMarker::type_name_bytes();
+        assert_eq!(NAME, b"ExampleMarker");
+        assert_eq!(SampleMarker::<u64>::type_name_bytes(), b"SampleMarker");
+        // The name of the type the traits are implemented for.
+        assert_eq!(DownloadMarkerConfig::type_name_bytes(), b"Download");
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_version(3)]
+    pub struct VersionedMarker {
+        #[format(Integer)]
+        count: u32,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_version(4)]
+    #[marker_v2(category = "Graphics")]
+    pub struct VersionedV2Marker {
+        #[format(Integer)]
+        count: u32,
+    }
+
+    #[test]
+    fn schema_version_const() {
+        const VERSIONS: [u32; 4] = [
+            StyleFlushMarker::schema_version_const(),
+            SampleMarker::<u64>::schema_version_const(),
+            VersionedMarker::schema_version_const(),
+            VersionedV2Marker::schema_version_const(),
+        ];
+        assert_eq!(VERSIONS, [2, 1, 3, 4]);
+        let _: [(); StyleFlushMarker::schema_version_const() as usize] = [(); 2];
+        // `#[marker_version(..)]` is the version of `#[marker_v2]` too.
+        assert_eq!(VersionedV2Marker::schema_version(), 4);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerTable)]
+    pub struct PaintMarker {
+        #[format(Time)]
+        #[serde(with = "crate::serde_process_time")]
+        start: std::time::Instant,
+        #[format(Time)]
+        #[serde(skip)]
+        end: Option<std::time::Instant>,
+    }
+
+    #[test]
+    fn instant_time_fields() {
+        let start = *crate::PROCESS_START + std::time::Duration::from_millis(1500);
+        assert_eq!(crate::process_time(start), 1500.0);
+        assert_eq!(
+            crate::process_time(*crate::PROCESS_START - std::time::Duration::from_millis(2)),
+            -2.0
+        );
+        assert_eq!(
+            crate::instant_from_process_time(-2.0),
+            Some(*crate::PROCESS_START - std::time::Duration::from_millis(2))
+        );
+        assert_eq!(crate::instant_from_process_time(f64::NAN), None);
+        assert_eq!(crate::instant_from_process_time(f64::INFINITY), None);
+        assert_eq!(crate::instant_from_process_time(-1e300), None);
+
+        let marker = PaintMarker { start, end: None };
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(json, serde_json::json!({ "start": 1500, "end": null }));
+        assert_eq!(
+            PaintMarker::from_json_marker_data(&json, &unique_strings).unwrap(),
+            marker
+        );
+        assert_eq!(
+            marker.field_value_as_string("start").as_deref(),
+            Some("1500")
+        );
+        let map: std::collections::BTreeMap<String, serde_json::Value> = marker.try_into().unwrap();
+        assert_eq!(map["start"], 1500.0);
+        assert_eq!(map["end"], serde_json::Value::Null);
+
+        let marker = PaintMarker {
+            start,
+            end: Some(start + std::time::Duration::from_millis(250)),
+        };
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(json["end"], 1750.0);
+        assert_eq!(
+            PaintMarker::from_json_marker_data(&json, &unique_strings).unwrap(),
+            marker
+        );
+        assert_eq!(
+            serde_json::to_value(&marker).unwrap(),
+            serde_json::json!({ "start": 1500.0 })
+        );
+
+        // Times that can't be represented are errors rather than panics.
+        let json = serde_json::json!({ "start": 1e300, "end": null });
+        assert!(matches!(
+            PaintMarker::from_json_marker_data(&json, &unique_strings),
+            Err(MarkerError::TypeMismatch { key, .. }) if key == "start"
+        ));
+        assert!(
+            serde_json::from_value::<PaintMarker>(serde_json::json!({ "start": -1e300 })).is_err()
+        );
+    }
+
+    #[test]
+    fn schema_to_hashmap() {
+        let mut schema = ExampleMarker::marker_type_display();
+        schema.add_key_format("field2", Format::Url);
+        schema.add_static_label_value("Static", "value");
+        let map = schema.to_hashmap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(
+            map["field1"],
+            (Format::Integer, "field1", Searchable::Searchable)
+        );
+        // The first row with a key is used.
+        assert_eq!(map["field2"].0, Format::String);
+        assert!(!map.contains_key("Static"));
+
+        let mut schema = MarkerSchema::new(&[]);
+        schema.add_key_label_format("size", "Size", Format::Bytes);
+        assert_eq!(
+            schema.to_hashmap()["size"],
+            (Format::Bytes, "Size", Searchable::NotSearchable)
+        );
+    }
+
+    type FlowId = u64;
+    type Path = String;
+    type Count = u32;
+    use std::string::String as Text;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    pub struct AliasedMarker {
+        #[format(Flow)]
+        flow: FlowId,
+        #[format(FilePath)]
+        path: Path,
+        #[format(UniqueString)]
+        name: Text,
+        count: Count,
+        label: Option<Text>,
+    }
+
+    #[test]
+    fn type_alias_fields() {
+        let schema = AliasedMarker::marker_type_display();
+        let formats: Vec<_> = schema
+            .rows()
+            .iter()
+            .filter_map(|row| match row {
+                SchemaRow::Dynamic { format, .. } => Some(*format),
+                SchemaRow::Static { .. } => None,
+            })
+            .collect();
+        assert_eq!(
+            formats,
+            vec![
+                Format::Flow,
+                Format::FilePath,
+                Format::UniqueString,
+                Format::Integer,
+                Format::String
+            ]
+        );
+
+        let marker = AliasedMarker {
+            flow: u64::MAX,
+            path: "/tmp/file".to_string(),
+            name: "Paint".to_string(),
+            count: 3,
+            label: Some("first".to_string()),
+        };
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "flow": u64::MAX,
+                "path": "/tmp/file",
+                "name": 0,
+                "count": 3,
+                "label": "first",
+            })
+        );
+        assert_eq!(
+            AliasedMarker::from_json_marker_data(&json, &unique_strings).unwrap(),
+            marker
+        );
+        assert_eq!(
+            marker.field_value_as_string("path").as_deref(),
+            Some("/tmp/file")
+        );
+        assert_eq!(
+            marker.field_value_as_string("name").as_deref(),
+            Some("Paint")
+        );
+    }
+
+    #[test]
+    fn json_type_hints() {
+        assert_eq!(JsonTypeHint::from(Format::Integer), JsonTypeHint::Number);
+        assert_eq!(JsonTypeHint::from(Format::Url), JsonTypeHint::String);
+        assert_eq!(JsonTypeHint::from(Format::List), JsonTypeHint::Array);
+        assert_eq!(
+            JsonTypeHint::from(Format::UniqueString).json_name(),
+            "number"
+        );
+
+        // The hints match the properties streamed by the derive.
+        let marker = TaskMarker {
+            flow: 1,
+            terminating_flow: Some(2),
+            pid: 3,
+            tid: 4,
+            stages: vec!["parse".to_string()],
+        };
+        let json = stream_marker(&marker).0;
+        for row in TaskMarker::marker_type_display().rows() {
+            if let SchemaRow::Dynamic { key, format, .. } = row {
+                let hint = match &json[key] {
+                    serde_json::Value::Number(_) => JsonTypeHint::Number,
+                    serde_json::Value::String(_) => JsonTypeHint::String,
+                    serde_json::Value::Array(_) => JsonTypeHint::Array,
+                    value => panic!("unexpected value {}", value),
+                };
+                assert_eq!(JsonTypeHint::from(*format), hint, "{}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn schema_approximate_json_size() {
+        let json_size = |schema: &MarkerSchema| schema.to_json("").to_string().len();
+        let schema = ExampleMarker::marker_type_display();
+        assert_eq!(schema.approximate_json_size(), json_size(&schema));
+
+        let mut schema = MarkerSchema::new(&[Location::MarkerChart, Location::TimelineMemory])
+            .with_chart_label("{marker.data.size}")
+            .with_description("Transfers")
+            .with_static_label_value("Static", "value")
+            .with_location_note(Location::TimelineMemory, "The transferred bytes");
+        schema.add_key_label_format("size", "Size", Format::Bytes);
+        schema.add_graph_line_with_color("size", GraphType::Line, GraphColor::Blue);
+        schema.add_graph_line("size", GraphType::Bar);
+        assert_eq!(schema.approximate_json_size(), json_size(&schema));
+
+        let schema = MarkerSchema::new_with_special_frontend_location();
+        assert_eq!(schema.approximate_json_size(), json_size(&schema));
+        assert_eq!(
+            MarkerSchema::new(&[]).approximate_json_size(),
+            json_size(&MarkerSchema::new(&[]))
+        );
+    }
+
+    #[test]
+    fn schema_with_all_locations() {
+        let schema = MarkerSchema::new_with_all_locations();
+        assert_eq!(schema.locations(), Location::ALL);
+        assert_eq!(schema.locations().len(), 7);
+        for location in Location::ALL {
+            assert_eq!(
+                Location::from_json_name(location.json_name()),
+                Some(*location)
+            );
+        }
+    }
+
+    #[test]
+    fn schema_retain_rows() {
+        let mut schema = ExampleMarker::marker_type_display();
+        schema.add_static_label_value("Static", "value");
+        schema.retain_rows(|row| {
+            matches!(
+                row,
+                SchemaRow::Dynamic {
+                    searchable: Searchable::Searchable,
+                    ..
+                }
+            )
+        });
+        let keys: Vec<_> = schema.rows().iter().map(SchemaRow::key).collect();
+        assert_eq!(keys, vec![Some("field1")]);
+
+        let mut calls = 0;
+        schema.retain_rows(|_| {
+            calls += 1;
+            false
+        });
+        assert_eq!(calls, 1);
+        assert!(schema.rows().is_empty());
+    }
+
+    #[test]
+    fn feature_gated_fields() {
+        let marker = FeatureGatedMarker {
+            id: 1,
+            #[cfg(feature = "protobuf")]
+            encoded_size: 64,
+        };
+        let keys = <FeatureGatedMarker as MarkerFields>::KEYS;
+        let (json, unique_strings) = stream_marker(&marker);
+        if cfg!(feature = "protobuf") {
+            assert_eq!(keys, ["id", "encoded_size"]);
+            assert_eq!(json["encoded_size"], 64);
+        } else {
+            assert_eq!(keys, ["id"]);
+            assert!(json.get("encoded_size").is_none());
+        }
+        assert_eq!(
+            FeatureGatedMarker::from_json_marker_data(&json, &unique_strings).unwrap(),
+            marker
+        );
+    }
+
+    #[test]
+    fn with_field_override() {
+        let marker = DiskIOMarker {
+            path: "/tmp/file".to_string(),
+            size: 4,
+            operation: "read".to_string(),
+        };
+        let overridden = marker
+            .with_field_override("size", serde_json::json!(16))
+            .unwrap()
+            .with_field_override("operation", serde_json::json!("write"))
+            .unwrap();
+        assert_eq!(
+            overridden,
+            DiskIOMarker {
+                path: "/tmp/file".to_string(),
+                size: 16,
+                operation: "write".to_string(),
+            }
+        );
+
+        assert_eq!(
+            marker.with_field_override("missing", serde_json::json!(1)),
+            Err(r#"unknown field "missing""#.to_string())
+        );
+        let error = marker
+            .with_field_override("size", serde_json::json!("big"))
+            .unwrap_err();
+        assert!(
+            error.starts_with(r#"invalid value "big" for field "size": "#),
+            "{}",
+            error
+        );
+
+        // The keys of flattened fields can be overridden too.
+        let marker = ConnectionMarker {
+            protocol: "h2".to_string(),
+            connection: ConnectionInfo {
+                network: NetworkInfo {
+                    host: "example.com".to_string(),
+                    port: 443,
+                },
+                latency: 12.5,
+            },
+        };
+        let overridden = marker
+            .with_field_override("port", serde_json::json!(8080))
+            .unwrap();
+        assert_eq!(overridden.connection.network.port, 8080);
+        assert_eq!(overridden.protocol, "h2");
+    }
+
+    #[test]
+    fn fields_present() {
+        let mut marker = ExampleMarker {
+            field1: 0,
+            field2: String::new(),
+            field3: None,
+        };
+        assert_eq!(marker.fields_present(), 0);
+        marker.field1 = 3;
+        assert_eq!(marker.fields_present(), 0b001);
+        marker.field3 = Some(0.0);
+        assert_eq!(marker.fields_present(), 0b101);
+        marker.field2 = "text".to_string();
+        assert_eq!(marker.fields_present(), 0b111);
+
+        let marker = SharedStringMarker {
+            arc_str: "a".into(),
+            rc_str: "".into(),
+            arc_string: std::sync::Arc::new(String::new()),
+            rc_string: std::rc::Rc::new("b".to_string()),
+            url: None,
+        };
+        assert_eq!(marker.fields_present(), 0b01001);
+    }
+
+    #[test]
+    fn schema_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MarkerSchema>();
+        assert_send_sync::<SchemaRow>();
+        assert_send_sync::<SchemaGraph>();
+    }
+
+    #[derive(Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerTable)]
+    #[marker_metrics]
+    pub struct RequestMarker {
+        #[format(Integer)]
+        status: u16,
+        #[searchable]
+        #[format(Url)]
+        route: String,
+    }
+
+    /// The `metrics` crate, re-exported under another path.
+    mod reexported {
+        pub use metrics;
+    }
+
+    #[derive(Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerTable)]
+    #[marker_metrics(crate = "reexported::metrics")]
+    pub struct QueueMarker {
+        #[searchable]
+        #[format(String)]
+        queue: Option<String>,
+    }
+
+    #[test]
+    fn metrics_label() {
+        let label = metrics::Label::from(RequestMarker {
+            status: 200,
+            route: "/index.html".to_string(),
+        });
+        assert_eq!((label.key(), label.value()), ("route", "/index.html"));
+
+        let label = metrics::Label::from(QueueMarker {
+            queue: Some("io".to_string()),
+        });
+        assert_eq!((label.key(), label.value()), ("queue", "io"));
+        let label = metrics::Label::from(QueueMarker { queue: None });
+        assert_eq!((label.key(), label.value()), ("queue", ""));
+    }
+
+    #[test]
+    fn searchable_not() {
+        assert_eq!(!Searchable::Searchable, Searchable::NotSearchable);
+        assert_eq!(!Searchable::NotSearchable, Searchable::Searchable);
+
+        let base = Searchable::Searchable;
+        let mut schema = MarkerSchema::new(&[]);
+        schema.add_key_format_searchable("key", Format::String, !base);
+        assert_eq!(
+            schema.to_hashmap()["key"],
+            (Format::String, "key", Searchable::NotSearchable)
+        );
+    }
+
+    #[test]
+    fn exp_formatting() {
+        #[derive(Serialize, Deserialize, ProfilerMarker)]
+        #[marker_display(MarkerTable)]
+        struct RateMarker {
+            rate: f64,
+            #[format(Percentage)]
+            hit_ratio: Option<f32>,
+            #[format(Integer)]
+            count: u64,
+        }
+
+        let marker = MemoryMarker {
+            allocated: 1024,
+            usage: 0.00125,
+            description: "heap".to_string(),
+        };
+        assert_eq!(
+            format!("{:e}", marker),
+            "MemoryMarker { allocated: 1024, usage: 1.25e-3, description: \"heap\" }"
+        );
+        assert_eq!(
+            format!("{:E}", marker),
+            "MemoryMarker { allocated: 1024, usage: 1.25E-3, description: \"heap\" }"
+        );
+
+        let marker = RateMarker {
+            rate: 12345.0,
+            hit_ratio: None,
+            count: 3,
+        };
+        assert_eq!(
+            format!("{:.1e}", marker),
+            "RateMarker { rate: 1.2e4, hit_ratio: None, count: 3 }"
+        );
+        let marker = RateMarker {
+            hit_ratio: Some(0.5),
+            ..marker
+        };
+        assert_eq!(
+            format!("{:e}", marker),
+            "RateMarker { rate: 1.2345e4, hit_ratio: Some(5e-1), count: 3 }"
+        );
+    }
+
+    #[test]
+    fn schema_iter_rows_with_format() {
+        let mut schema = MarkerSchema::new(&[]);
+        schema
+            .add_key_format("a", Format::Integer)
+            .add_static_label_value("Note", "value")
+            .add_key_format("b", Format::String)
+            .add_key_format("c", Format::Integer);
+        let keys: Vec<_> = schema
+            .iter_rows_with_format(Format::Integer)
+            .filter_map(SchemaRow::key)
+            .collect();
+        assert_eq!(keys, ["a", "c"]);
+        assert_eq!(schema.iter_rows_with_format(Format::Bytes).count(), 0);
+    }
+
+    #[test]
+    fn schema_satisfies_requirements() {
+        let schema = MemoryMarker::marker_type_display();
+        assert_eq!(schema.satisfies_requirements(&[]), Ok(()));
+        assert_eq!(
+            schema.satisfies_requirements(&["usage", "allocated"]),
+            Ok(())
+        );
+        assert_eq!(
+            schema.satisfies_requirements(&["freed", "usage", "Note"]),
+            Err(vec!["freed".to_string(), "Note".to_string()])
+        );
+    }
+
+    #[test]
+    fn schema_has_key() {
+        let schema = DiskIOMarker::marker_type_display();
+        assert!(schema.has_key("path"));
+        assert!(schema.has_key("operation"));
+        assert!(!schema.has_key("Path"));
+        assert!(!schema.has_key("missing"));
+
+        let mut schema = MarkerSchema::new(&[]);
+        schema.add_static_label_value("Note", "value");
+        assert!(!schema.has_key("Note"));
+        schema.add_key_format("b", Format::Integer);
+        schema.add_key_format("a", Format::Integer);
+        assert_eq!(schema.field_keys(), ["b", "a"]);
+    }
+
+    #[test]
+    fn schema_sort_rows_by_key() {
+        let mut schema = MarkerSchema::new(&[Location::MarkerChart]);
+        schema
+            .add_static_label_value("Note", "first")
+            .add_key_format("c", Format::Integer)
+            .add_key_label_format("a", "First", Format::Integer)
+            .add_static_label_value("Note", "second")
+            .add_key_format("b", Format::String)
+            .add_key_label_format("a", "Second", Format::String);
+        schema.sort_rows_by_key();
+
+        let keys: Vec<_> = schema.rows().iter().map(SchemaRow::key).collect();
+        assert_eq!(
+            keys,
+            [Some("a"), Some("a"), Some("b"), Some("c"), None, None]
+        );
+        assert!(matches!(
+            &schema.rows()[0],
+            SchemaRow::Dynamic { label: Some(label), .. } if label == "First"
+        ));
+        assert!(matches!(
+            &schema.rows()[4],
+            SchemaRow::Static { value, .. } if value == "first"
+        ));
+    }
+
+    #[test]
+    fn schema_from_rows() {
+        let mut expected = MarkerSchema::new(&[]);
+        expected
+            .add_key_format("a", Format::Integer)
+            .add_static_label_value("Note", "value");
+        let rows = expected.rows().to_vec();
+
+        let schema: MarkerSchema = rows.iter().cloned().collect();
+        assert_eq!(schema, expected);
+
+        let schema = MarkerSchema::from_rows_with_locations(&[Location::MarkerTable], rows);
+        assert_eq!(schema.rows(), expected.rows());
+        assert_eq!(schema.locations(), &[Location::MarkerTable]);
+    }
+
+    #[test]
+    fn schema_row_ord() {
+        let mut schema = MarkerSchema::new(&[]);
+        schema
+            .add_static_label_value("Note", "value")
+            .add_key_format("b", Format::Integer)
+            .add_key_label_format("a", "Second", Format::String)
+            .add_key_label_format("a", "First", Format::String)
+            .add_key_format("c", Format::Bytes);
+        let mut rows = schema.rows().to_vec();
+        rows.sort();
+        assert_eq!(
+            rows.iter().map(SchemaRow::key).collect::<Vec<_>>(),
+            [Some("a"), Some("a"), Some("b"), Some("c"), None]
+        );
+        assert!(matches!(
+            &rows[0],
+            SchemaRow::Dynamic { label: Some(label), .. } if label == "First"
+        ));
+
+        let set: std::collections::BTreeSet<SchemaRow> =
+            schema.rows().iter().chain(schema.rows()).cloned().collect();
+        assert_eq!(set.into_iter().collect::<Vec<_>>(), rows);
+    }
+
+    #[test]
+    fn schema_dedup_rows() {
+        let mut schema = MarkerSchema::new(&[Location::MarkerChart]);
+        schema
+            .add_key_label_format("a", "First", Format::Integer)
+            .add_static_label_value("Note", "value")
+            .add_key_format("b", Format::Integer)
+            .add_key_label_format("a", "Second", Format::String)
+            .add_static_label_value("Note", "value");
+
+        let deduped = schema.deduped();
+        assert_eq!(schema.rows().len(), 5);
+        schema.dedup_rows();
+        assert_eq!(schema, deduped);
+        assert_eq!(
+            schema.rows(),
+            &[
+                SchemaRow::Dynamic {
+                    key: "a".to_string(),
+                    label: Some("First".to_string()),
+                    format: Format::Integer,
+                    searchable: Searchable::NotSearchable,
+                },
+                SchemaRow::Static {
+                    label: "Note".to_string(),
+                    value: "value".to_string(),
+                },
+                SchemaRow::Dynamic {
+                    key: "b".to_string(),
+                    label: None,
+                    format: Format::Integer,
+                    searchable: Searchable::NotSearchable,
+                },
+                SchemaRow::Static {
+                    label: "Note".to_string(),
+                    value: "value".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn derived_schema_graphs() {
+        let schema = MemoryMarker::marker_type_display();
+        assert_eq!(
+            schema.graphs(),
+            &[
+                SchemaGraph {
+                    key: "allocated".to_string(),
+                    graph_type: GraphType::Bar,
+                    color: None,
+                },
+                SchemaGraph {
+                    key: "usage".to_string(),
+                    graph_type: GraphType::FilledLine,
+                    color: Some(GraphColor::Orange),
+                },
+            ]
+        );
+        assert_eq!(schema.rows().len(), 3);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    pub struct NetworkInfo {
+        #[format(Url)]
+        host: String,
+        #[format(Integer)]
+        port: u16,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    pub struct ConnectionInfo {
+        #[flatten]
+        network: NetworkInfo,
+        #[format(Milliseconds)]
+        latency: f64,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    pub struct ConnectionMarker {
+        #[format(UniqueString)]
+        #[searchable]
+        protocol: String,
+        #[flatten]
+        connection: ConnectionInfo,
+    }
+
+    #[test]
+    fn flattened_marker_fields() {
+        assert_eq!(
+            ConnectionMarker::KEYS,
+            &["protocol", "host", "port", "latency"]
+        );
+
+        let schema = ConnectionMarker::marker_type_display();
+        assert_eq!(schema.field_keys(), ConnectionMarker::KEYS);
+
+        let marker = ConnectionMarker {
+            protocol: "h2".to_string(),
+            connection: ConnectionInfo {
+                network: NetworkInfo {
+                    host: "example.com".to_string(),
+                    port: 443,
+                },
+                latency: 12.5,
+            },
+        };
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(
+            json,
+            serde_json::json!({ "protocol": 0, "host": "example.com", "port": 443, "latency": 12.5 })
+        );
+        assert_eq!(marker.field_value_as_string("port").as_deref(), Some("443"));
+        assert_eq!(
+            ConnectionMarker::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+
+        let map: std::collections::BTreeMap<String, serde_json::Value> = ConnectionMarker {
+            protocol: "h2".to_string(),
+            connection: ConnectionInfo {
+                network: NetworkInfo {
+                    host: "example.com".to_string(),
+                    port: 443,
+                },
+                latency: 12.5,
+            },
+        }
+        .try_into()
+        .unwrap();
+        assert_eq!(map.len(), 4);
+        assert_eq!(map["host"], "example.com");
+    }
+
+    #[test]
+    fn key_at() {
+        let keys: Vec<_> = (0..5).map(ConnectionMarker::key_at).collect();
+        assert_eq!(
+            keys,
+            [
+                Some("protocol"),
+                Some("host"),
+                Some("port"),
+                Some("latency"),
+                None
+            ]
+        );
+
+        // The default implementation, from the schema.
+        assert_eq!(TextMarker::key_at(0), Some("text"));
+        assert_eq!(TextMarker::key_at(1), None);
+        assert_eq!(EmptyMarker::key_at(0), None);
+    }
+
+    /// A marker with hand-written serde implementations, which serialize it as
+    /// a "major.minor" string.
+    #[derive(Debug, PartialEq, ProfilerMarker)]
+    #[marker_display(MarkerTable)]
+    pub struct VersionMarker {
+        #[format(Integer)]
+        major: u32,
+        #[format(Integer)]
+        minor: u32,
+    }
+
+    impl Serialize for VersionMarker {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(&format_args!("{}.{}", self.major, self.minor))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for VersionMarker {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let version = String::deserialize(deserializer)?;
+            let (major, minor) = version
+                .split_once('.')
+                .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+                .ok_or_else(|| serde::de::Error::custom("expected \"major.minor\""))?;
+            Ok(VersionMarker { major, minor })
+        }
+    }
+
+    #[test]
+    fn manual_serde_impls() {
+        fn assert_profiler_marker<T: ProfilerMarker>() {}
+        assert_profiler_marker::<VersionMarker>();
+
+        let marker = VersionMarker { major: 1, minor: 2 };
+        assert_eq!(serde_json::to_value(&marker).unwrap(), "1.2");
+        // The payload is streamed from the fields, not the serde representation.
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(json, serde_json::json!({ "major": 1, "minor": 2 }));
+        assert_eq!(
+            VersionMarker::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+    }
+
+    #[test]
+    fn try_from_string_map() {
+        let map = |entries: &[(&str, &str)]| -> HashMap<String, String> {
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            ExampleMarker::try_from(&map(&[("field1", "12"), ("field2", "Hello")])),
+            Ok(ExampleMarker {
+                field1: 12,
+                field2: "Hello".to_string(),
+                field3: None,
+            })
+        );
+        assert_eq!(
+            ExampleMarker::try_from(&map(&[
+                ("field1", "12"),
+                ("field2", "42"),
+                ("field3", "1.5")
+            ]))
+            .map(|marker| (marker.field2, marker.field3)),
+            Ok(("42".to_string(), Some(1.5)))
+        );
+        assert_eq!(
+            ExampleMarker::try_from(&map(&[("field2", "Hello")])),
+            Err("missing field \"field1\"".to_string())
+        );
+        assert_eq!(
+            ExampleMarker::try_from(&map(&[("field1", "-1"), ("field2", "Hello")])),
+            Err(
+                "invalid value \"-1\" for field \"field1\": invalid digit found in string"
+                    .to_string()
+            )
+        );
+
+        // Flattened fields are parsed from the same map, and other types from
+        // their JSON.
+        assert_eq!(
+            ConnectionMarker::try_from(&map(&[
+                ("protocol", "h2"),
+                ("host", "example.com"),
+                ("port", "443"),
+                ("latency", "12.5")
+            ])),
+            Ok(ConnectionMarker {
+                protocol: "h2".to_string(),
+                connection: ConnectionInfo {
+                    network: NetworkInfo {
+                        host: "example.com".to_string(),
+                        port: 443,
+                    },
+                    latency: 12.5,
+                },
+            })
+        );
+        let marker = EndpointMarker::try_from(&map(&[
+            ("endpoint", r#"{"host":"example.com","port":80}"#),
+            ("tags", r#"["a","b"]"#),
+        ]))
+        .unwrap();
+        assert_eq!(marker.endpoint.port, 80);
+        assert_eq!(marker.fallback, None);
+        assert_eq!(marker.tags, vec!["a", "b"]);
+
+        // Times are parsed from their `process_time()`, which must be finite
+        // and in the range of `Instant`.
+        assert_eq!(
+            PaintMarker::try_from(&map(&[("start", "2.5")])).map(|marker| marker.start),
+            Ok(*crate::PROCESS_START + std::time::Duration::from_micros(2500))
+        );
+        for value in ["NaN", "inf", "1e300"] {
+            assert_eq!(
+                PaintMarker::try_from(&map(&[("start", value)])).map(|marker| marker.start),
+                Err(format!(
+                    "invalid value {:?} for field \"start\": out of the range of `Instant`",
+                    value
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn ordered_key_values() {
+        let marker = ConnectionMarker {
+            protocol: "h2".to_string(),
+            connection: ConnectionInfo {
+                network: NetworkInfo {
+                    host: "example.com".to_string(),
+                    port: 443,
+                },
+                latency: 12.5,
+            },
+        };
+        // Flattened fields are in place of the field they're flattened from.
+        assert_eq!(
+            Vec::<(String, serde_json::Value)>::try_from(&marker).unwrap(),
+            vec![
+                ("protocol".to_string(), serde_json::json!("h2")),
+                ("host".to_string(), serde_json::json!("example.com")),
+                ("port".to_string(), serde_json::json!(443)),
+                ("latency".to_string(), serde_json::json!(12.5)),
+            ]
+        );
+
+        let marker = ExampleMarker {
+            field1: 12,
+            field2: "Hello, world!".to_string(),
+            field3: None,
+        };
+        let entries: Vec<(String, serde_json::Value)> = (&marker).try_into().unwrap();
+        let keys: Vec<_> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ExampleMarker::KEYS);
+        assert_eq!(entries[2].1, serde_json::Value::Null);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    pub struct Endpoint {
+        host: String,
+        port: u16,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    pub struct EndpointMarker {
+        #[format(String)]
+        endpoint: Endpoint,
+        #[format(String)]
+        fallback: Option<Endpoint>,
+        #[format(String)]
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn serialize_fallback_for_string_formats() {
+        let marker = EndpointMarker {
+            endpoint: Endpoint {
+                host: "example.com".to_string(),
+                port: 80,
+            },
+            fallback: None,
+            tags: vec!["a".to_string(), "b\"".to_string()],
+        };
+        let recorder = RecordingWriter::record(&marker);
+        assert_eq!(
+            recorder.properties(),
+            &[
+                (
+                    "endpoint".to_string(),
+                    PropertyValue::Object(vec![
+                        (
+                            "host".to_string(),
+                            PropertyValue::Str("example.com".to_string())
+                        ),
+                        ("port".to_string(), PropertyValue::Int(80)),
+                    ])
+                ),
+                ("fallback".to_string(), PropertyValue::Null),
+                (
+                    "tags".to_string(),
+                    PropertyValue::Array(vec![
+                        PropertyValue::Str("a".to_string()),
+                        PropertyValue::Str("b\"".to_string()),
+                    ])
+                ),
+            ]
+        );
+
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(
+            marker.field_value_as_string("endpoint").as_deref(),
+            Some(r#"{"host":"example.com","port":80}"#)
+        );
+        assert_eq!(
+            EndpointMarker::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+    }
+
+    #[test]
+    fn json_writer_measure() {
+        let mut json_writer = JSONWriter::new();
+        let ((), first) = json_writer.measure(|w| w.int_property("a", 1));
+        assert_eq!(first, r#""a":1"#.len());
+        let (result, rest) = json_writer.measure(|w| {
+            w.string_property("b", "text");
+            w.null_property("c");
+            "done"
+        });
+        assert_eq!(result, "done");
+        assert_eq!(rest, r#","b":"text","c":null"#.len());
+        let ((), nothing) = json_writer.measure(|_| {});
+        assert_eq!(nothing, 0);
+        assert_eq!(json_writer.take_string().len(), 1 + first + rest);
+    }
+
+    #[test]
+    fn recording_writer() {
+        let mut recorder = RecordingWriter::new();
+        recorder.unsigned_property("flow", u64::MAX);
+        recorder.start_object_property("outer");
+        recorder.bool_property("flag", true);
+        recorder.start_object_property("inner");
+        recorder.float_property("ratio", 0.5);
+        recorder.end_object();
+        recorder.end_object();
+        recorder.unique_string_property("name", "shared");
+
+        assert_eq!(recorder.get("flow"), Some(&PropertyValue::UInt(u64::MAX)));
+        let outer = recorder.get("outer").unwrap();
+        assert_eq!(outer.get("flag"), Some(&PropertyValue::Bool(true)));
+        assert_eq!(
+            outer.get("inner").and_then(|inner| inner.get("ratio")),
+            Some(&PropertyValue::Float(0.5))
+        );
+        recorder.assert_unique_str("name", "shared");
+        assert_eq!(recorder.get("flag"), None);
+
+        // Recording doesn't change what's written.
+        let json_writer = recorder.into_writer();
+        assert_eq!(json_writer.unique_strings().get(0), Some("shared"));
+        assert_eq!(
+            json_writer.take_string(),
+            r#"{"flow":18446744073709551615,"outer":{"flag":true,"inner":{"ratio":0.5}},"name":0"#
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected value for property \"field1\"")]
+    fn recording_writer_assertion_failure() {
+        let recorder = RecordingWriter::record(&ExampleMarker {
+            field1: 42,
+            field2: String::new(),
+            field3: None,
+        });
+        recorder.assert_str("field1", "42");
+    }
+
+    /// Stream `marker` through a `JSONWriter`, and parse the resulting payload.
+    fn stream_marker<T: ProfilerMarker>(marker: &T) -> (serde_json::Value, UniqueStrings) {
+        let mut json_writer = JSONWriter::new();
+        marker.stream_json_marker_data(&mut json_writer);
+        let unique_strings = json_writer.unique_strings().clone();
+        let mut payload = json_writer.take_string();
+        payload.push('}');
+        (serde_json::from_str(&payload).unwrap(), unique_strings)
+    }
+
+    #[test]
+    fn example_marker_json_round_trip() {
+        for field3 in [Some(7.0), None] {
+            let marker = ExampleMarker {
+                field1: 42,
+                field2: "Some \"text\"".to_string(),
+                field3,
+            };
+            let recorder = RecordingWriter::record(&marker);
+            recorder.assert_int("field1", 42);
+            recorder.assert_str("field2", "Some \"text\"");
+            match field3 {
+                Some(_) => recorder.assert_int("field3", 7),
+                None => recorder.assert_null("field3"),
+            }
+            assert_eq!(recorder.properties().len(), 3);
+
+            let (json, unique_strings) = stream_marker(&marker);
+            assert_eq!(
+                ExampleMarker::from_json_marker_data(&json, &unique_strings),
+                Ok(marker)
+            );
+        }
+    }
+
+    #[test]
+    fn renamed_marker_json_round_trip() {
+        for category in [Some("Layout".to_string()), None] {
+            let marker = RenamedMarker {
+                inner_window_id: 7,
+                category: category.clone(),
+                count: 3,
+            };
+            let recorder = RecordingWriter::record(&marker);
+            recorder.assert_int("innerWindowID", 7);
+            recorder.assert_str("count", "3");
+            match &category {
+                Some(category) => recorder.assert_unique_str("category", category),
+                None => recorder.assert_null("category"),
+            }
+
+            let (json, unique_strings) = stream_marker(&marker);
+            assert_eq!(
+                RenamedMarker::from_json_marker_data(&json, &unique_strings),
+                Ok(marker)
+            );
+        }
+    }
+
+    #[test]
+    fn fields_as_hashmap() {
+        let marker = RenamedMarker {
+            inner_window_id: 7,
+            category: Some("Layout".to_string()),
+            count: 3,
+        };
+        assert_eq!(
+            marker.fields_as_hashmap(),
+            HashMap::from([
+                ("innerWindowID", serde_json::json!(7)),
+                ("category", serde_json::json!("Layout")),
+                ("count", serde_json::json!("3")),
+            ])
+        );
+
+        let marker = ConnectionMarker {
+            protocol: "h2".to_string(),
+            connection: ConnectionInfo {
+                network: NetworkInfo {
+                    host: "example.com".to_string(),
+                    port: 443,
+                },
+                latency: 12.5,
+            },
+        };
+        let fields = marker.fields_as_hashmap();
+        let mut keys: Vec<&str> = fields.keys().copied().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["host", "latency", "port", "protocol"]);
+        assert_eq!(fields["protocol"], "h2");
+        assert_eq!(fields["port"], 443);
+        assert_eq!(fields["latency"], 12.5);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[serde(rename_all = "camelCase", deny_unknown_fields)]
+    pub struct SerdeRenamedMarker {
+        #[format(Integer)]
+        request_count: u32,
+        #[serde(rename = "innerWindowID")]
+        #[format(Integer)]
+        inner_window_id: u64,
+        #[serde(rename = "serdeName")]
+        #[key = "profilerName"]
+        #[format(UniqueString)]
+        display_name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[format(Milliseconds)]
+        total_time: Option<f64>,
+    }
+
+    #[test]
+    fn serde_rename_keys() {
+        assert_eq!(
+            SerdeRenamedMarker::KEYS,
+            &["requestCount", "innerWindowID", "profilerName", "totalTime"]
+        );
+        let schema = SerdeRenamedMarker::marker_type_display();
+        let keys: Vec<&str> = schema.rows().iter().filter_map(SchemaRow::key).collect();
+        assert_eq!(keys, SerdeRenamedMarker::KEYS);
+
+        let marker = SerdeRenamedMarker {
+            request_count: 2,
+            inner_window_id: 7,
+            display_name: "page".to_string(),
+            total_time: Some(1.5),
+        };
+        let recorder = RecordingWriter::record(&marker);
+        recorder.assert_int("requestCount", 2);
+        recorder.assert_int("innerWindowID", 7);
+        recorder.assert_unique_str("profilerName", "page");
+        recorder.assert_float("totalTime", 1.5);
+
+        let (json, unique_strings) = stream_marker(&marker);
+        assert_eq!(
+            SerdeRenamedMarker::from_json_marker_data(&json, &unique_strings),
+            Ok(marker)
+        );
+    }
+
+    #[test]
+    fn marker_into_btree_map() {
+        let map: std::collections::BTreeMap<String, serde_json::Value> = RenamedMarker {
+            inner_window_id: 7,
+            category: None,
+            count: 3,
+        }
+        .try_into()
+        .unwrap();
+        let keys: Vec<&str> = map.keys().map(String::as_str).collect();
+        assert_eq!(keys, vec!["category", "count", "innerWindowID"]);
+        assert_eq!(map["innerWindowID"], 7);
+        assert_eq!(map["category"], serde_json::Value::Null);
+        assert_eq!(map["count"], 3);
+
+        // Fields that can't be serialized are errors, rather than null.
+        #[derive(Serialize, Deserialize)]
+        struct Pairs(HashMap<(u8, u8), u32>);
+        #[derive(Serialize, Deserialize, ProfilerMarker)]
+        #[marker_display(MarkerTable)]
+        struct PairsMarker {
+            #[serde_property]
+            pairs: Pairs,
+        }
+        let marker = PairsMarker {
+            pairs: Pairs(HashMap::from([((1, 2), 3)])),
+        };
+        assert!(Vec::<(String, serde_json::Value)>::try_from(&marker).is_err());
+        assert!(std::collections::BTreeMap::<String, serde_json::Value>::try_from(marker).is_err());
+    }
+
+    #[test]
+    fn field_value_as_string() {
+        let marker = ExampleMarker {
+            field1: 42,
+            field2: "hello".to_string(),
+            field3: Some(2.5),
+        };
+        assert_eq!(
+            marker.field_value_as_string("field1").as_deref(),
+            Some("42")
+        );
+        assert_eq!(
+            marker.field_value_as_string("field2").as_deref(),
+            Some("hello")
+        );
+        assert_eq!(marker.field_value_as_string("field3").as_deref(), Some("2"));
+        assert_eq!(marker.field_value_as_string("field4"), None);
+
+        let marker = RenamedMarker {
+            inner_window_id: 7,
+            category: None,
+            count: 3,
+        };
+        assert_eq!(
+            marker.field_value_as_string("innerWindowID").as_deref(),
+            Some("7")
+        );
+        assert_eq!(marker.field_value_as_string("inner_window_id"), None);
+        assert_eq!(
+            marker.field_value_as_string("category").as_deref(),
+            Some("")
+        );
+        assert_eq!(marker.field_value_as_string("count").as_deref(), Some("3"));
+
+        let marker = MemoryMarker {
+            allocated: 1024,
+            usage: 0.25,
+            description: "heap".to_string(),
+        };
+        assert_eq!(
+            marker.field_value_as_string("usage").as_deref(),
+            Some("0.25")
+        );
+        let marker = SharedStringMarker {
+            arc_str: "a".into(),
+            rc_str: "b".into(),
+            arc_string: std::sync::Arc::new("c".to_string()),
+            rc_string: std::rc::Rc::new("d".to_string()),
+            url: Some("https://example.com".into()),
+        };
+        assert_eq!(
+            marker.field_value_as_string("arc_string").as_deref(),
+            Some("c")
+        );
+        assert_eq!(
+            marker.field_value_as_string("url").as_deref(),
+            Some("https://example.com")
+        );
+    }
+
+    #[test]
+    fn from_json_marker_data_errors() {
+        let unique_strings = UniqueStrings::new();
+        assert_eq!(
+            ExampleMarker::from_json_marker_data(&serde_json::json!([]), &unique_strings),
+            Err(MarkerError::NotAnObject)
+        );
+
+        let missing = serde_json::json!({ "field1": 1, "field3": null });
+        let error = ExampleMarker::from_json_marker_data(&missing, &unique_strings).unwrap_err();
+        assert_eq!(error, MarkerError::MissingProperty("field2".to_string()));
+        assert_eq!(
+            error.to_string(),
+            "marker payload has no property \"field2\""
+        );
+
+        let mismatch = serde_json::json!({ "field1": "one", "field2": "two", "field3": null });
+        let error = ExampleMarker::from_json_marker_data(&mismatch, &unique_strings).unwrap_err();
+        assert!(matches!(
+            &error,
+            MarkerError::TypeMismatch { key, .. } if key == "field1"
+        ));
+        assert!(error
+            .to_string()
+            .starts_with("marker payload property \"field1\" is invalid: "));
+
+        let unknown = serde_json::json!({ "innerWindowID": 1, "category": 3, "count": "1" });
+        assert_eq!(
+            RenamedMarker::from_json_marker_data(&unknown, &unique_strings),
+            Err(MarkerError::UnknownUniqueString {
+                key: "category".to_string(),
+                index: 3
+            })
+        );
+    }
+
+    #[test]
+    fn schema_to_rust_literal() {
+        let mut schema = MarkerSchema::new(&[Location::MarkerChart, Location::TimelineIPC]);
+        schema
+            .set_chart_label("{marker.data.name}")
+            .set_table_label("Says \"hi\"")
+            .set_location_note(Location::TimelineIPC, "IPC")
+            .add_key_format("name", Format::UniqueString)
+            .add_key_label_format_searchable("size", "Size", Format::Bytes, Searchable::Searchable)
+            .add_static_label_value("Note", "Static\nvalue")
+            .add_graph_line("size", GraphType::Bar)
+            .add_graph_line_with_color("size", GraphType::FilledLine, GraphColor::Teal);
+
+        let expected = r#"{
+    let mut schema = ::fx_markers::MarkerSchema::new(&[::fx_markers::Location::MarkerChart, ::fx_markers::Location::TimelineIPC]);
+    schema.set_chart_label("{marker.data.name}");
+    schema.set_table_label("Says \"hi\"");
+    schema.set_location_note(::fx_markers::Location::TimelineIPC, "IPC");
+    schema.add_key_format_searchable("name", ::fx_markers::Format::UniqueString, ::fx_markers::Searchable::NotSearchable);
+    schema.add_key_label_format_searchable("size", "Size", ::fx_markers::Format::Bytes, ::fx_markers::Searchable::Searchable);
+    schema.add_static_label_value("Note", "Static\nvalue");
+    schema.add_graph_line("size", ::fx_markers::GraphType::Bar);
+    schema.add_graph_line_with_color("size", ::fx_markers::GraphType::FilledLine, ::fx_markers::GraphColor::Teal);
+    schema
+}"#;
+        assert_eq!(schema.to_rust_literal(), expected);
+
+        // The expected source above, compiled.
+        let reconstructed = {
+            let mut schema = ::fx_markers::MarkerSchema::new(&[
+                ::fx_markers::Location::MarkerChart,
+                ::fx_markers::Location::TimelineIPC,
+            ]);
+            schema.set_chart_label("{marker.data.name}");
+            schema.set_table_label("Says \"hi\"");
+            schema.set_location_note(::fx_markers::Location::TimelineIPC, "IPC");
+            schema.add_key_format_searchable(
+                "name",
+                ::fx_markers::Format::UniqueString,
+                ::fx_markers::Searchable::NotSearchable,
+            );
+            schema.add_key_label_format_searchable(
+                "size",
+                "Size",
+                ::fx_markers::Format::Bytes,
+                ::fx_markers::Searchable::Searchable,
+            );
+            schema.add_static_label_value("Note", "Static\nvalue");
+            schema.add_graph_line("size", ::fx_markers::GraphType::Bar);
+            schema.add_graph_line_with_color(
+                "size",
+                ::fx_markers::GraphType::FilledLine,
+                ::fx_markers::GraphColor::Teal,
+            );
+            schema
+        };
+        assert_eq!(reconstructed, schema);
+
+        let special = MarkerSchema::new_with_special_frontend_location();
+        assert_eq!(
+            special.to_rust_literal(),
+            "{\n    let schema = ::fx_markers::MarkerSchema::new_with_special_frontend_location();\n    schema\n}"
+        );
+    }
+
+    fn payload_json(entry: &MarkerEntry) -> serde_json::Value {
+        serde_json::from_str(&entry.payload).unwrap()
+    }
+
+    #[test]
+    fn marker_buffer_records_markers_in_order() {
+        let buffer = MarkerBuffer::new(16);
+        buffer.add_marker(
+            "Write",
+            &DiskIOMarker {
+                path: "/tmp/\"quoted\"".to_string(),
+                size: 4096,
+                operation: "write".to_string(),
+            },
+        );
+        buffer.add_marker(
+            "Example",
+            &ExampleMarker {
+                field1: 42,
+                field2: "hello".to_string(),
+                field3: None,
+            },
+        );
+
+        let entries = buffer.drain();
+        assert!(buffer.is_empty());
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].name, "Write");
+        assert_eq!(entries[0].marker_type, "DiskIOMarker");
+        let payload = payload_json(&entries[0]);
+        assert_eq!(payload["path"], "/tmp/\"quoted\"");
+        assert_eq!(payload["size"], 4096);
+        let index = payload["operation"].as_u64().unwrap() as u32;
+        assert_eq!(buffer.unique_string(index).as_deref(), Some("write"));
+
+        assert_eq!(entries[1].name, "Example");
+        assert_eq!(entries[1].marker_type, "ExampleMarker");
+        assert!(entries[1].timestamp >= entries[0].timestamp);
+        assert_eq!(
+            payload_json(&entries[1]),
+            serde_json::json!({ "field1": 42, "field2": "hello", "field3": null })
+        );
+    }
+
+    #[test]
+    fn marker_buffer_is_bounded_and_clearable() {
+        let buffer = MarkerBuffer::new(2);
+        for size in 0..3 {
+            buffer.add_marker(
+                "Read",
+                &DiskIOMarker {
+                    path: "/tmp/file".to_string(),
+                    size,
+                    operation: "read".to_string(),
+                },
+            );
+        }
+        assert_eq!(buffer.len(), 2);
+
+        let sizes: Vec<_> = buffer
+            .drain()
+            .iter()
+            .map(|entry| payload_json(entry)["size"].as_u64().unwrap())
+            .collect();
+        assert_eq!(sizes, vec![1, 2]);
+
+        buffer.add_marker(
+            "Read",
+            &DiskIOMarker {
+                path: "/tmp/file".to_string(),
+                size: 0,
+                operation: "read".to_string(),
+            },
+        );
+        buffer.clear();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.unique_string(0), None);
+    }
+
+    static NESTED_BUFFER: MarkerBuffer = MarkerBuffer::new(16);
+
+    /// What streaming a `NestingMarker` does.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    pub enum NestedAction {
+        AddMarker,
+        Panic,
+    }
+
+    impl MarkerFieldValue for NestedAction {
+        const FORMAT: Format = Format::String;
+        fn write(&self, key: &str, json_writer: &mut JSONWriter) {
+            match self {
+                NestedAction::AddMarker => NESTED_BUFFER.add_marker(
+                    "Nested",
+                    &DiskIOMarker {
+                        path: "/tmp/file".to_string(),
+                        size: 1,
+                        operation: "read".to_string(),
+                    },
+                ),
+                NestedAction::Panic => panic!("streaming failed"),
+            }
+            json_writer.string_property(key, "done");
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    pub struct NestingMarker {
+        #[format(UniqueString)]
+        name: String,
+        action: NestedAction,
+    }
+
+    #[test]
+    fn marker_buffer_nested_markers() {
+        // Only this test uses `NESTED_BUFFER`.
+        let disk_io = DiskIOMarker {
+            path: "/tmp/file".to_string(),
+            size: 0,
+            operation: "read".to_string(),
+        };
+        NESTED_BUFFER.add_marker("Read", &disk_io);
+        NESTED_BUFFER.add_marker(
+            "Outer",
+            &NestingMarker {
+                name: "outer".to_string(),
+                action: NestedAction::AddMarker,
+            },
+        );
+        let entries = NESTED_BUFFER.drain();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["Read", "Nested", "Outer"]);
+        let index = payload_json(&entries[2])["name"].as_u64().unwrap() as u32;
+        assert_eq!(NESTED_BUFFER.unique_string(index).as_deref(), Some("outer"));
+    }
+
+    #[test]
+    fn marker_buffer_panicking_payload() {
+        let buffer = MarkerBuffer::new(16);
+        buffer.add_marker(
+            "Read",
+            &DiskIOMarker {
+                path: "/tmp/file".to_string(),
+                size: 0,
+                operation: "read".to_string(),
+            },
+        );
+        let result = std::panic::catch_unwind(|| {
+            buffer.add_marker(
+                "Panic",
+                &NestingMarker {
+                    name: "lost".to_string(),
+                    action: NestedAction::Panic,
+                },
+            )
+        });
+        assert!(result.is_err());
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.unique_string(0).as_deref(), Some("read"));
+        assert_eq!(buffer.unique_string(1), None);
+
+        // Payloads aren't streamed at all when there's no room for them.
+        MarkerBuffer::new(0).add_marker(
+            "Panic",
+            &NestingMarker {
+                name: "unused".to_string(),
+                action: NestedAction::Panic,
+            },
+        );
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, ProfilerMarker)]
+    #[marker_display(MarkerChart)]
+    #[marker_options(interval_start, stack)]
+    pub struct CompileMarker {
+        #[format(String)]
+        unit: String,
+    }
+
+    #[test]
+    fn marker_buffer_records_timing() {
+        let buffer = MarkerBuffer::new(16);
+        let disk_io = DiskIOMarker {
+            path: "/tmp/file".to_string(),
+            size: 1,
+            operation: "read".to_string(),
+        };
+        let start = std::time::Instant::now();
+        let end = start + std::time::Duration::from_millis(5);
+
+        buffer.add_marker("Instant", &disk_io);
+        buffer.add_marker_with_options(
+            "Interval",
+            MarkerOptions::new().timing(MarkerTiming::Interval { start, end }),
+            &disk_io,
+        );
+        // Reversed intervals are recorded with their ends swapped.
+        buffer.add_marker_with_options(
+            "Reversed",
+            MarkerOptions::new().timing(MarkerTiming::Interval {
+                start: end,
+                end: start,
+            }),
+            &disk_io,
+        );
+        buffer.add_marker_with_options(
+            "Start",
+            MarkerOptions::new().timing(MarkerTiming::IntervalStart(start)),
+            &disk_io,
+        );
+        buffer.add_marker_with_options(
+            "Since",
+            MarkerOptions::new().interval_from(start),
+            &disk_io,
+        );
+
+        let entries = buffer.drain();
+        assert_eq!(
+            entries[0].timing,
+            MarkerTiming::Instant(entries[0].timestamp)
+        );
+        assert_eq!(entries[1].timing, MarkerTiming::Interval { start, end });
+        assert_eq!(entries[2].timing, MarkerTiming::Interval { start, end });
+        assert_eq!(entries[3].timing, MarkerTiming::IntervalStart(start));
+        assert_eq!(
+            entries[4].timing,
+            MarkerTiming::Interval {
+                start,
+                end: entries[4].timestamp
+            }
+        );
+        assert!(entries.iter().all(|entry| entry.stack.is_none()));
+        assert_eq!(
+            MarkerTiming::interval(end, start),
+            MarkerTiming::Interval { start, end }
+        );
+    }
+
+    #[test]
+    fn derived_default_marker_options() {
+        assert_eq!(
+            DiskIOMarker::default_marker_options(),
+            MarkerOptions::default()
+        );
+        let options = CompileMarker::default_marker_options();
+        assert_eq!(options.stack, MarkerStack::Capture);
+
+        let marker = CompileMarker {
+            unit: "lib.rs".to_string(),
+        };
+        let buffer = MarkerBuffer::new(16);
+        buffer.add_marker("Compile", &marker);
+        // A whole interval starts at a time that only the caller knows.
+        let start = std::time::Instant::now() - std::time::Duration::from_millis(5);
+        buffer.add_marker_with_options(
+            "Compile",
+            CompileMarker::default_marker_options().interval_from(start),
+            &marker,
+        );
+        let entries = buffer.drain();
+        assert!(entries.iter().all(|entry| entry.stack.is_some()));
+        assert!(matches!(
+            entries[0].timing,
+            MarkerTiming::IntervalStart(start) if start <= entries[0].timestamp
+        ));
+        match entries[1].timing {
+            MarkerTiming::Interval { start, end } => {
+                assert!(end - start >= std::time::Duration::from_millis(5));
+                assert_eq!(end, entries[1].timestamp);
+            }
+            timing => panic!("expected an interval, got {:?}", timing),
+        }
+    }
+
+    #[test]
+    fn lazy_marker_only_built_while_active() {
+        // This is the only test that uses the global buffer, or changes
+        // whether the profiler is active.
+        let built = std::cell::Cell::new(0);
+        let make_marker = || {
+            built.set(built.get() + 1);
+            DiskIOMarker {
+                path: "/tmp/lazy".to_string(),
+                size: 1,
+                operation: "write".to_string(),
+            }
+        };
+
+        set_active(false);
+        assert!(!is_active());
+        add_lazy_marker("Lazy", make_marker);
+        add_marker("Eager", &make_marker());
+        assert_eq!(built.get(), 1);
+        assert!(MarkerBuffer::global().is_empty());
+
+        set_active(true);
+        add_lazy_marker("Lazy", make_marker);
+        assert_eq!(built.get(), 2);
+        let entries = MarkerBuffer::global().drain();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "Lazy");
+    }
+
+    #[gecko_profiler_fn_label(Layout)]
+    fn labeled_layout(x: u32) -> (u32, Option<ProfilerLabel>) {
+        (x + 1, current_label())
+    }
+
+    #[test]
+    fn fn_label() {
+        assert_eq!(current_label(), None);
+        assert_eq!(
+            labeled_layout(1),
+            (2, Some(ProfilerLabel::new("Layout", None)))
+        );
+        assert_eq!(current_label(), None);
+
+        {
+            gecko_profiler_label!(DOM);
+            assert_eq!(label_stack(), vec![ProfilerLabel::new("DOM", None)]);
+            assert_eq!(
+                labeled_layout(1).1,
+                Some(ProfilerLabel::new("Layout", None))
+            );
+            assert_eq!(label_stack(), vec![ProfilerLabel::new("DOM", None)]);
+        }
+        assert_eq!(label_stack(), vec![]);
+    }
+
+    /// A future that is pending the first time it's polled.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[gecko_profiler_fn_label(Javascript, IonMonkey)]
+    async fn labeled_async(
+        labels: &RefCell<Vec<Option<ProfilerLabel>>>,
+        count: &str,
+    ) -> Result<usize, std::num::ParseIntError> {
+        labels.borrow_mut().push(current_label());
+        YieldOnce(false).await;
+        labels.borrow_mut().push(current_label());
+        Ok(count.parse::<usize>()? + labels.borrow().len())
+    }
+
+    #[test]
+    fn async_fn_label() {
+        let labels = RefCell::new(vec![]);
+        let mut future = std::pin::pin!(labeled_async(&labels, "1"));
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+
+        assert_eq!(future.as_mut().poll(&mut cx), Poll::Pending);
+        // The label is exited while the future is suspended.
+        assert_eq!(current_label(), None);
+        assert_eq!(future.as_mut().poll(&mut cx), Poll::Ready(Ok(3)));
+        assert_eq!(current_label(), None);
+
+        let label = Some(ProfilerLabel::new("Javascript", Some("IonMonkey")));
+        assert_eq!(*labels.borrow(), vec![label, label]);
+
+        let labels = RefCell::new(vec![]);
+        let mut future = std::pin::pin!(labeled_async(&labels, "x"));
+        while future.as_mut().poll(&mut cx).is_pending() {}
+        assert_eq!(current_label(), None);
     }
 }
@@ -0,0 +1,15 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+#[profiler_marker(crate = "not a path")]
+struct InvalidPathMarker {
+    field: u32,
+}
+
+#[derive(ProfilerMarker)]
+#[profiler_marker(krate = "::fx_markers")]
+struct UnknownArgumentMarker {
+    field: u32,
+}
+
+fn main() {}
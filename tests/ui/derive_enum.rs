@@ -0,0 +1,9 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+enum EnumMarker {
+    A,
+    B(u32),
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+struct TupleMarker(u32, String);
+
+fn main() {}
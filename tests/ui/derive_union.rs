@@ -0,0 +1,9 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+union UnionMarker {
+    a: u32,
+    b: f32,
+}
+
+fn main() {}
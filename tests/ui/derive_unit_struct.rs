@@ -0,0 +1,6 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+struct UnitMarker;
+
+fn main() {}
@@ -0,0 +1,18 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+struct DuplicateMarker {
+    #[key = "first"]
+    #[key = "second"]
+    field: u32,
+    #[format(Integer)]
+    #[format(Bytes)]
+    #[graph(Bar)]
+    #[graph(Line)]
+    size: u64,
+    #[format(String)]
+    #[graph(Line)]
+    name: String,
+}
+
+fn main() {}
@@ -0,0 +1,25 @@
+use fx_markers::{Format, JSONWriter, MarkerFieldValue};
+use profiler_macros::ProfilerMarker;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct Pid(u32);
+
+#[derive(Serialize, Deserialize)]
+struct Name(String);
+
+impl MarkerFieldValue for Name {
+    const FORMAT: Format = Format::String;
+    fn write(&self, key: &str, json_writer: &mut JSONWriter) {
+        json_writer.string_property(key, &self.0);
+    }
+}
+
+#[derive(Serialize, Deserialize, ProfilerMarker)]
+struct ProcessMarker {
+    pid: Pid,
+    #[graph(Line)]
+    name: Name,
+}
+
+fn main() {}
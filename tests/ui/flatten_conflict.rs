@@ -0,0 +1,11 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+struct ConflictMarker {
+    #[flatten]
+    #[searchable]
+    #[key = "inner"]
+    inner: u32,
+}
+
+fn main() {}
@@ -0,0 +1,14 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+struct FlowMarker {
+    #[format(Flow)]
+    flow: String,
+    #[format(TerminatingFlow)]
+    end: Option<f64>,
+    #[format(List)]
+    #[graph(Bar)]
+    samples: Vec<u32>,
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use profiler_macros::ProfilerMarker;
+use serde::{Deserialize, Serialize};
+
+type Ratio = f64;
+
+#[derive(Serialize, Deserialize, ProfilerMarker)]
+struct AliasedFlowMarker {
+    #[format(Flow)]
+    flow: Ratio,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use profiler_macros::gecko_profiler_fn_label;
+
+#[gecko_profiler_fn_label(Layout)]
+struct NotAFunction;
+
+#[gecko_profiler_fn_label(Layout, StyleComputation, Extra)]
+fn too_many_arguments() {}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use profiler_macros::ProfilerMarker;
+use std::collections::BTreeMap;
+
+#[derive(ProfilerMarker)]
+struct HistogramMarker {
+    buckets: BTreeMap<u32, u64>,
+    #[graph(Line)]
+    counts: BTreeMap<String, u64>,
+}
+
+fn main() {}
@@ -0,0 +1,21 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+#[marker_diffable]
+struct NonNumericMarker {
+    #[format(Integer)]
+    count: u32,
+    #[format(Url)]
+    url: String,
+    total: u64,
+}
+
+#[derive(ProfilerMarker)]
+#[profiler_impl_for = "other::Type"]
+#[marker_diffable]
+struct ForeignConfig {
+    #[format(Integer)]
+    count: u32,
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+#[profiler_macros::marker_display(MarkerChart)]
+struct NotAnImpl {
+    count: u32,
+}
+
+struct Marker;
+
+#[profiler_macros::marker_display(MarkerChart)]
+impl Clone for Marker {
+    fn clone(&self) -> Self {
+        Marker
+    }
+}
+
+#[profiler_macros::marker_display(Nowhere)]
+impl Marker {}
+
+fn main() {}
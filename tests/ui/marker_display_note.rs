@@ -0,0 +1,15 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+#[marker_display(note = "Before any location", MarkerChart)]
+struct LeadingNoteMarker {
+    field: u32,
+}
+
+#[derive(ProfilerMarker)]
+#[marker_display(MarkerChart, note = "First", note = "Second")]
+struct DoubleNoteMarker {
+    field: u32,
+}
+
+fn main() {}
@@ -0,0 +1,31 @@
+use profiler_macros::ProfilerMarker;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, ProfilerMarker)]
+#[marker_metrics]
+struct NoSearchableStringMarker {
+    #[searchable]
+    #[format(Integer)]
+    count: u32,
+    #[format(Url)]
+    url: String,
+}
+
+#[derive(Serialize, Deserialize, ProfilerMarker)]
+#[profiler_impl_for = "other::Type"]
+#[marker_metrics]
+struct ForeignConfig {
+    #[searchable]
+    #[format(String)]
+    name: String,
+}
+
+#[derive(Serialize, Deserialize, ProfilerMarker)]
+#[marker_metrics(path = "::metrics")]
+struct UnknownArgumentMarker {
+    #[searchable]
+    #[format(String)]
+    name: String,
+}
+
+fn main() {}
@@ -0,0 +1,32 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+#[marker_options(backtrace)]
+struct UnknownOptionMarker {
+    #[format(Integer)]
+    count: u32,
+}
+
+#[derive(ProfilerMarker)]
+#[marker_options(interval_start, interval_end)]
+struct TwoTimingsMarker {
+    #[format(Integer)]
+    count: u32,
+}
+
+#[derive(ProfilerMarker)]
+#[marker_options(stack)]
+#[marker_options(interval_start)]
+struct DuplicateOptionsMarker {
+    #[format(Integer)]
+    count: u32,
+}
+
+#[derive(ProfilerMarker)]
+#[marker_options(interval)]
+struct IntervalMarker {
+    #[format(Integer)]
+    count: u32,
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+#[marker_scalable(fields)]
+struct ArgumentsMarker {
+    #[format(Integer)]
+    count: u32,
+}
+
+#[derive(ProfilerMarker)]
+#[profiler_impl_for = "other::Type"]
+#[marker_scalable]
+struct ForeignConfig {
+    #[format(Integer)]
+    count: u32,
+}
+
+fn main() {}
@@ -0,0 +1,24 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+#[marker_v2(revision = 2)]
+struct UnknownArgumentMarker {
+    #[format(Integer)]
+    count: u32,
+}
+
+#[derive(ProfilerMarker)]
+#[marker_v2(version = -1)]
+struct NegativeVersionMarker {
+    #[format(Integer)]
+    count: u32,
+}
+
+#[derive(ProfilerMarker)]
+#[marker_v2(category = "Layout", category = "Graphics")]
+struct DuplicateCategoryMarker {
+    #[format(Integer)]
+    count: u32,
+}
+
+fn main() {}
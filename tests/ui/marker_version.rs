@@ -0,0 +1,18 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+#[marker_version(-1)]
+struct NegativeVersionMarker {
+    #[format(Integer)]
+    count: u32,
+}
+
+#[derive(ProfilerMarker)]
+#[marker_version(2)]
+#[marker_v2(version = 3)]
+struct ConflictingVersionMarker {
+    #[format(Integer)]
+    count: u32,
+}
+
+fn main() {}
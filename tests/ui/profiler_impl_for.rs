@@ -0,0 +1,18 @@
+use profiler_macros::ProfilerMarker;
+
+#[derive(ProfilerMarker)]
+#[profiler_impl_for = "not a path"]
+struct BadPathConfig {
+    #[format(Integer)]
+    count: u32,
+}
+
+#[derive(ProfilerMarker)]
+#[profiler_impl_for = "first::Type"]
+#[profiler_impl_for = "second::Type"]
+struct DuplicateConfig {
+    #[format(Integer)]
+    count: u32,
+}
+
+fn main() {}